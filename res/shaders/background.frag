@@ -0,0 +1,138 @@
+#version 450
+
+layout(location=0) in vec3 v_direction; // world space
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BackgroundUniforms {
+  mat4 u_inv_view_proj; // without the camera translation
+  vec4 u_color_a; // top of the gradient or zenith of the sky, w is the intensity of the maps
+                  // (sun color and intensity of the physical sky)
+  vec4 u_color_b; // bottom of the gradient or horizon of the sky
+  vec4 u_color_c; // ground of the sky
+  vec4 u_sun_direction; // towards the sun
+  uint u_mode;
+  vec4 u_perez[5]; // Perez coefficients A to E of the physical sky, for Y, x and y
+  vec4 u_zenith; // Y, x and y at the zenith divided by the Perez distribution there, radiance per Y
+};
+
+layout(set=0, binding=1) uniform textureCube t_cubemap;
+layout(set=0, binding=2) uniform texture2D t_panorama; // can't be filtered
+layout(set=0, binding=3) uniform sampler s_background;
+
+// Matches background::BACKGROUND_*
+const uint BACKGROUND_GRADIENT = 0;
+const uint BACKGROUND_SKY = 1;
+const uint BACKGROUND_CUBEMAP = 2;
+const uint BACKGROUND_PANORAMA = 3;
+const uint BACKGROUND_PHYSICAL_SKY = 4;
+
+const float PI = 3.14159265359;
+
+const vec3 SUN_COLOR = vec3(1.0, 0.95, 0.85);
+
+// Cosine of the angular radius of the sun
+const float SUN_COS_RADIUS = 0.9998;
+
+// Radiance of the sun's disc in the physical sky, relative to the sun light's intensity
+const float SUN_DISC_RADIANCE = 20.0;
+
+// Fraction of the light at the horizon reflected by the ground below it
+const float GROUND_ALBEDO = 0.3;
+
+vec3 sky(vec3 direction) {
+  vec3 color;
+  if (direction.y >= 0.0) {
+    color = mix(u_color_b.rgb, u_color_a.rgb, pow(direction.y, 0.5));
+  } else {
+    // Fade into the ground quickly, so the horizon stays sharp
+    color = mix(u_color_b.rgb, u_color_c.rgb, clamp(-direction.y * 10.0, 0.0, 1.0));
+  }
+
+  // A glow around the sun, and the sun itself above the horizon
+  float cos_sun = dot(direction, normalize(u_sun_direction.xyz));
+  color += SUN_COLOR * 0.5 * pow(max(cos_sun, 0.0), 64.0);
+  if (direction.y >= 0.0) {
+    color += SUN_COLOR * smoothstep(SUN_COS_RADIUS - 0.0001, SUN_COS_RADIUS, cos_sun);
+  }
+  return color;
+}
+
+vec3 xyy_to_rgb(float x, float y, float luminance) {
+  if (y <= 0.0) {
+    return vec3(0.0);
+  }
+  vec3 xyz = vec3(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+  mat3 xyz_to_rgb = mat3(
+    3.2406, -0.9689, 0.0557,
+    -1.5372, 1.8758, -0.2040,
+    -0.4986, 0.0415, 1.0570
+  );
+  return max(xyz_to_rgb * xyz, vec3(0.0));
+}
+
+// The Preetham sky, see sky.rs
+vec3 physical_sky(vec3 direction) {
+  vec3 sun = u_sun_direction.xyz;
+  float cos_theta = max(direction.y, 0.001); // the ground reflects the horizon
+  float cos_gamma = clamp(dot(direction, sun), -1.0, 1.0);
+  float gamma = acos(cos_gamma);
+
+  vec3 a = u_perez[0].xyz;
+  vec3 b = u_perez[1].xyz;
+  vec3 c = u_perez[2].xyz;
+  vec3 d = u_perez[3].xyz;
+  vec3 e = u_perez[4].xyz;
+  vec3 perez = (1.0 + a * exp(b / cos_theta)) * (1.0 + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+  vec3 yxy = u_zenith.xyz * perez;
+  vec3 color = xyy_to_rgb(yxy.y, yxy.z, yxy.x) * u_zenith.w;
+
+  if (direction.y < 0.0) {
+    color *= mix(1.0, GROUND_ALBEDO, clamp(-direction.y * 10.0, 0.0, 1.0));
+  } else {
+    float disc = smoothstep(SUN_COS_RADIUS - 0.0001, SUN_COS_RADIUS, cos_gamma);
+    color += u_color_a.rgb * u_color_a.w * SUN_DISC_RADIANCE * disc;
+  }
+  return color;
+}
+
+// Bilinearly filtered by hand, see environment::load_equirect
+vec3 panorama(vec3 direction) {
+  ivec2 size = textureSize(sampler2D(t_panorama, s_background), 0);
+  vec2 uv = vec2(atan(direction.z, direction.x) / (2.0 * PI) + 0.5, acos(direction.y) / PI);
+  vec2 texel = uv * vec2(size) - 0.5;
+  ivec2 base = ivec2(floor(texel));
+  vec2 t = texel - vec2(base);
+
+  vec3 samples[4];
+  for (int i = 0; i < 4; i++) {
+    ivec2 offset = ivec2(i & 1, i >> 1);
+    // Wraps around horizontally, and stops at the poles
+    ivec2 coords = ivec2((base.x + offset.x + size.x) % size.x, clamp(base.y + offset.y, 0, size.y - 1));
+    samples[i] = texelFetch(sampler2D(t_panorama, s_background), coords, 0).rgb;
+  }
+  return mix(mix(samples[0], samples[1], t.x), mix(samples[2], samples[3], t.x), t.y);
+}
+
+void main() {
+  vec3 direction = normalize(v_direction);
+  vec3 color;
+  switch (u_mode) {
+  case BACKGROUND_SKY:
+    color = sky(direction);
+    break;
+  case BACKGROUND_CUBEMAP:
+    color = textureLod(samplerCube(t_cubemap, s_background), direction, 0.0).rgb * u_color_a.w;
+    break;
+  case BACKGROUND_PANORAMA:
+    color = panorama(direction) * u_color_a.w;
+    break;
+  case BACKGROUND_PHYSICAL_SKY:
+    color = physical_sky(direction);
+    break;
+  default:
+    color = mix(u_color_b.rgb, u_color_a.rgb, direction.y * 0.5 + 0.5);
+    break;
+  }
+  f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,24 @@
+#version 450
+
+// A single triangle covering the screen at the far plane, with the direction from the camera
+// through each corner
+
+layout(location=0) out vec3 v_direction; // world space
+
+layout(set=0, binding=0) uniform BackgroundUniforms {
+  mat4 u_inv_view_proj; // without the camera translation
+  vec4 u_color_a;
+  vec4 u_color_b;
+  vec4 u_color_c;
+  vec4 u_sun_direction;
+  uint u_mode;
+  vec4 u_perez[5]; // Perez coefficients A to E of the physical sky, for Y, x and y
+  vec4 u_zenith; // Y, x and y at the zenith divided by the Perez distribution there, radiance per Y
+};
+
+void main() {
+  vec2 ndc = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+  vec4 world = u_inv_view_proj * vec4(ndc, 1.0, 1.0);
+  v_direction = world.xyz / world.w;
+  gl_Position = vec4(ndc, 1.0, 1.0);
+}
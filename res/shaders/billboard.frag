@@ -1,6 +1,7 @@
 #version 450
 
 layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in float v_emission;
 
 layout(location=0) out vec4 f_color;
 
@@ -15,5 +16,5 @@ void main() {
   // See https://www.khronos.org/opengl/wiki/Transparency_Sorting
   if (object_color.a < 0.5)
     discard;
-  f_color = object_color;
+  f_color = vec4(object_color.rgb * v_emission, object_color.a);
 }
@@ -0,0 +1,46 @@
+#version 450
+
+// Adds the top of the bloom chain, upsampled, to the scene
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BloomUniforms {
+  float u_threshold;
+  float u_knee;
+  float u_intensity;
+  float u_radius; // texels of the bloom
+  bool u_prefilter;
+  uint u_mip_count;
+};
+
+layout(set=1, binding=0) uniform texture2D t_bloom;
+layout(set=1, binding=1) uniform sampler s_bloom;
+
+layout(set=2, binding=0) uniform texture2D t_scene;
+layout(set=2, binding=1) uniform sampler s_scene;
+
+vec3 sample_bloom(vec2 uv) {
+  return texture(sampler2D(t_bloom, s_bloom), uv).rgb;
+}
+
+void main() {
+  vec2 offset = u_radius / vec2(textureSize(sampler2D(t_bloom, s_bloom), 0));
+  vec2 uv = v_tex_coords;
+
+  vec3 bloom = sample_bloom(uv) * 4.0;
+  bloom += (sample_bloom(uv + offset * vec2(-1.0, 0.0))
+    + sample_bloom(uv + offset * vec2(1.0, 0.0))
+    + sample_bloom(uv + offset * vec2(0.0, -1.0))
+    + sample_bloom(uv + offset * vec2(0.0, 1.0))) * 2.0;
+  bloom += sample_bloom(uv + offset * vec2(-1.0, -1.0))
+    + sample_bloom(uv + offset * vec2(1.0, -1.0))
+    + sample_bloom(uv + offset * vec2(-1.0, 1.0))
+    + sample_bloom(uv + offset * vec2(1.0, 1.0));
+  // Each mip of the chain was added up, so it's averaged over them
+  bloom /= 16.0 * float(u_mip_count);
+
+  vec3 scene = texelFetch(sampler2D(t_scene, s_scene), ivec2(gl_FragCoord.xy), 0).rgb;
+  f_color = vec4(scene + bloom * u_intensity, 1.0);
+}
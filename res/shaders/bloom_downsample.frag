@@ -0,0 +1,81 @@
+#version 450
+
+// Renders a mip of the bloom chain from the scene or the mip above it, with the 13 tap filter of
+// Jimenez's "Next Generation Post Processing in Call of Duty: Advanced Warfare"
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BloomUniforms {
+  float u_threshold;
+  float u_knee; // fraction of the threshold
+  float u_intensity;
+  float u_radius; // texels
+  bool u_prefilter; // the source is the scene
+  uint u_mip_count;
+};
+
+layout(set=1, binding=0) uniform texture2D t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+float luminance(vec3 color) {
+  return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+vec3 sample_source(vec2 uv) {
+  return texture(sampler2D(t_source, s_source), uv).rgb;
+}
+
+// Average of four samples, weighted down by their brightness on the first downsample, so that
+// single very bright pixels don't flicker as they move (Karis average)
+vec3 box(vec3 a, vec3 b, vec3 c, vec3 d) {
+  if (!u_prefilter) {
+    return (a + b + c + d) * 0.25;
+  }
+  float wa = 1.0 / (1.0 + luminance(a));
+  float wb = 1.0 / (1.0 + luminance(b));
+  float wc = 1.0 / (1.0 + luminance(c));
+  float wd = 1.0 / (1.0 + luminance(d));
+  return (a * wa + b * wb + c * wc + d * wd) / (wa + wb + wc + wd);
+}
+
+// Keeps the light above the threshold, with a quadratic transition of the knee's width
+vec3 threshold(vec3 color) {
+  float brightness = max(color.r, max(color.g, color.b));
+  float knee = u_threshold * u_knee + 1e-4;
+  float soft = clamp(brightness - u_threshold + knee, 0.0, 2.0 * knee);
+  soft = soft * soft / (4.0 * knee);
+  return color * max(soft, brightness - u_threshold) / max(brightness, 1e-4);
+}
+
+void main() {
+  vec2 texel = 1.0 / vec2(textureSize(sampler2D(t_source, s_source), 0));
+  vec2 uv = v_tex_coords;
+
+  vec3 a = sample_source(uv + texel * vec2(-2.0, -2.0));
+  vec3 b = sample_source(uv + texel * vec2(0.0, -2.0));
+  vec3 c = sample_source(uv + texel * vec2(2.0, -2.0));
+  vec3 d = sample_source(uv + texel * vec2(-2.0, 0.0));
+  vec3 e = sample_source(uv);
+  vec3 f = sample_source(uv + texel * vec2(2.0, 0.0));
+  vec3 g = sample_source(uv + texel * vec2(-2.0, 2.0));
+  vec3 h = sample_source(uv + texel * vec2(0.0, 2.0));
+  vec3 i = sample_source(uv + texel * vec2(2.0, 2.0));
+  vec3 j = sample_source(uv + texel * vec2(-1.0, -1.0));
+  vec3 k = sample_source(uv + texel * vec2(1.0, -1.0));
+  vec3 l = sample_source(uv + texel * vec2(-1.0, 1.0));
+  vec3 m = sample_source(uv + texel * vec2(1.0, 1.0));
+
+  // The inner box, and four overlapping boxes around it
+  vec3 color = box(j, k, l, m) * 0.5
+    + box(a, b, d, e) * 0.125
+    + box(b, c, e, f) * 0.125
+    + box(d, e, g, h) * 0.125
+    + box(e, f, h, i) * 0.125;
+
+  if (u_prefilter) {
+    color = threshold(color);
+  }
+  f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,40 @@
+#version 450
+
+// Upsamples a mip of the bloom chain with a 3x3 tent filter, to be added to the mip above it
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BloomUniforms {
+  float u_threshold;
+  float u_knee;
+  float u_intensity;
+  float u_radius; // texels of the source
+  bool u_prefilter;
+  uint u_mip_count;
+};
+
+layout(set=1, binding=0) uniform texture2D t_source; // the mip below
+layout(set=1, binding=1) uniform sampler s_source;
+
+vec3 sample_source(vec2 uv) {
+  return texture(sampler2D(t_source, s_source), uv).rgb;
+}
+
+void main() {
+  vec2 offset = u_radius / vec2(textureSize(sampler2D(t_source, s_source), 0));
+  vec2 uv = v_tex_coords;
+
+  vec3 color = sample_source(uv) * 4.0;
+  color += (sample_source(uv + offset * vec2(-1.0, 0.0))
+    + sample_source(uv + offset * vec2(1.0, 0.0))
+    + sample_source(uv + offset * vec2(0.0, -1.0))
+    + sample_source(uv + offset * vec2(0.0, 1.0))) * 2.0;
+  color += sample_source(uv + offset * vec2(-1.0, -1.0))
+    + sample_source(uv + offset * vec2(1.0, -1.0))
+    + sample_source(uv + offset * vec2(-1.0, 1.0))
+    + sample_source(uv + offset * vec2(1.0, 1.0));
+
+  f_color = vec4(color / 16.0, 1.0);
+}
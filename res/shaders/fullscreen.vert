@@ -0,0 +1,11 @@
+#version 450
+
+// A single triangle covering the whole target, drawn with three vertices and no vertex buffer
+
+layout(location=0) out vec2 v_tex_coords;
+
+void main() {
+  vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+  v_tex_coords = uv;
+  gl_Position = vec4(uv * vec2(2.0, -2.0) + vec2(-1.0, 1.0), 0.0, 1.0);
+}
@@ -0,0 +1,36 @@
+#version 450
+
+// Renders a mip of the environment cubemap from the one above it
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BakeUniforms {
+  uint u_face;
+  float u_roughness;
+  float u_source_size;
+};
+
+layout(set=1, binding=0) uniform textureCube t_source; // the previous mip only
+layout(set=1, binding=1) uniform sampler s_source;
+
+// Direction through a point on a cube face, in the layout cube samplers use
+vec3 cube_direction(uint face, vec2 uv) {
+  vec2 st = uv * 2.0 - 1.0;
+  switch (face) {
+  case 0: return normalize(vec3(1.0, -st.y, -st.x));
+  case 1: return normalize(vec3(-1.0, -st.y, st.x));
+  case 2: return normalize(vec3(st.x, 1.0, st.y));
+  case 3: return normalize(vec3(st.x, -1.0, -st.y));
+  case 4: return normalize(vec3(st.x, -st.y, 1.0));
+  default: return normalize(vec3(-st.x, -st.y, -1.0));
+  }
+}
+
+void main() {
+  // The center of a texel lies between four texels of the previous mip, which bilinear filtering
+  // averages
+  vec3 direction = cube_direction(u_face, v_tex_coords);
+  f_color = vec4(textureLod(samplerCube(t_source, s_source), direction, 0.0).rgb, 1.0);
+}
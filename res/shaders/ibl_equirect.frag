@@ -0,0 +1,50 @@
+#version 450
+
+// Projects an equirectangular panorama onto a face of the environment cubemap
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BakeUniforms {
+  uint u_face;
+  float u_roughness;
+  float u_source_size; // face size of the environment cubemap
+};
+
+layout(set=1, binding=0) uniform texture2D t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+const float PI = 3.14159265359;
+
+// Direction through a point on a cube face, in the layout cube samplers use
+vec3 cube_direction(uint face, vec2 uv) {
+  vec2 st = uv * 2.0 - 1.0;
+  switch (face) {
+  case 0: return normalize(vec3(1.0, -st.y, -st.x));
+  case 1: return normalize(vec3(-1.0, -st.y, st.x));
+  case 2: return normalize(vec3(st.x, 1.0, st.y));
+  case 3: return normalize(vec3(st.x, -1.0, -st.y));
+  case 4: return normalize(vec3(st.x, -st.y, 1.0));
+  default: return normalize(vec3(-st.x, -st.y, -1.0));
+  }
+}
+
+vec3 sample_equirect(vec3 direction) {
+  vec2 uv = vec2(atan(direction.z, direction.x) / (2.0 * PI) + 0.5, acos(direction.y) / PI);
+  return textureLod(sampler2D(t_source, s_source), uv, 0.0).rgb;
+}
+
+void main() {
+  // The panorama is usually much larger than a face and can't be filtered, so average a few
+  // samples across the texel
+  float texel = 1.0 / u_source_size;
+  vec3 color = vec3(0.0);
+  for (int y = 0; y < 2; y++) {
+    for (int x = 0; x < 2; x++) {
+      vec2 offset = (vec2(x, y) - 0.5) * 0.5 * texel;
+      color += sample_equirect(cube_direction(u_face, v_tex_coords + offset));
+    }
+  }
+  f_color = vec4(color / 4.0, 1.0);
+}
@@ -0,0 +1,60 @@
+#version 450
+
+// Convolves the environment with a cosine lobe, giving the diffuse light reaching a surface
+// facing each direction
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BakeUniforms {
+  uint u_face;
+  float u_roughness;
+  float u_source_size; // face size of the environment cubemap
+};
+
+layout(set=1, binding=0) uniform textureCube t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+const float PI = 3.14159265359;
+
+// Angle between samples, in both directions
+const float SAMPLE_DELTA = 0.025;
+
+// Face size of the mip to sample from. The samples are far apart, so a blurrier mip keeps small
+// bright spots from turning into noise.
+const float SAMPLE_SIZE = 64.0;
+
+// Direction through a point on a cube face, in the layout cube samplers use
+vec3 cube_direction(uint face, vec2 uv) {
+  vec2 st = uv * 2.0 - 1.0;
+  switch (face) {
+  case 0: return normalize(vec3(1.0, -st.y, -st.x));
+  case 1: return normalize(vec3(-1.0, -st.y, st.x));
+  case 2: return normalize(vec3(st.x, 1.0, st.y));
+  case 3: return normalize(vec3(st.x, -1.0, -st.y));
+  case 4: return normalize(vec3(st.x, -st.y, 1.0));
+  default: return normalize(vec3(-st.x, -st.y, -1.0));
+  }
+}
+
+void main() {
+  vec3 normal = cube_direction(u_face, v_tex_coords);
+  vec3 up = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+  vec3 right = normalize(cross(up, normal));
+  up = cross(normal, right);
+
+  float lod = max(log2(u_source_size / SAMPLE_SIZE), 0.0);
+  vec3 irradiance = vec3(0.0);
+  float samples = 0.0;
+  for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_DELTA) {
+    for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_DELTA) {
+      vec3 tangent = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+      vec3 direction = tangent.x * right + tangent.y * up + tangent.z * normal;
+      vec3 radiance = textureLod(samplerCube(t_source, s_source), direction, lod).rgb;
+      irradiance += radiance * cos(theta) * sin(theta);
+      samples += 1.0;
+    }
+  }
+  f_color = vec4(PI * irradiance / samples, 1.0);
+}
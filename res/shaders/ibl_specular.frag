@@ -0,0 +1,98 @@
+#version 450
+
+// Convolves the environment with the GGX distribution of a roughness, giving the specular light
+// reflected towards the view along each direction (assuming the view is along the normal)
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform BakeUniforms {
+  uint u_face;
+  float u_roughness;
+  float u_source_size; // face size of the environment cubemap
+};
+
+layout(set=1, binding=0) uniform textureCube t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+const float PI = 3.14159265359;
+
+const uint SAMPLE_COUNT = 512u;
+
+// Direction through a point on a cube face, in the layout cube samplers use
+vec3 cube_direction(uint face, vec2 uv) {
+  vec2 st = uv * 2.0 - 1.0;
+  switch (face) {
+  case 0: return normalize(vec3(1.0, -st.y, -st.x));
+  case 1: return normalize(vec3(-1.0, -st.y, st.x));
+  case 2: return normalize(vec3(st.x, 1.0, st.y));
+  case 3: return normalize(vec3(st.x, -1.0, -st.y));
+  case 4: return normalize(vec3(st.x, -st.y, 1.0));
+  default: return normalize(vec3(-st.x, -st.y, -1.0));
+  }
+}
+
+float radical_inverse(uint bits) {
+  bits = (bits << 16u) | (bits >> 16u);
+  bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+  bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+  bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+  bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+  return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint count) {
+  return vec2(float(i) / float(count), radical_inverse(i));
+}
+
+// A half vector around the normal, distributed like the GGX microfacets
+vec3 importance_sample_ggx(vec2 xi, vec3 normal, float roughness) {
+  float a = roughness * roughness;
+  float phi = 2.0 * PI * xi.x;
+  float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+  float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+  vec3 h = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+  vec3 up = abs(normal.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+  vec3 tangent = normalize(cross(up, normal));
+  vec3 bitangent = cross(normal, tangent);
+  return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+float distribution_ggx(float n_dot_h, float roughness) {
+  float a2 = roughness * roughness * roughness * roughness;
+  float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+  return a2 / (PI * d * d);
+}
+
+void main() {
+  vec3 normal = cube_direction(u_face, v_tex_coords);
+  if (u_roughness == 0.0) {
+    f_color = vec4(textureLod(samplerCube(t_source, s_source), normal, 0.0).rgb, 1.0);
+    return;
+  }
+
+  // Solid angle of a texel of the environment cubemap
+  float texel_solid_angle = 4.0 * PI / (6.0 * u_source_size * u_source_size);
+
+  vec3 color = vec3(0.0);
+  float total_weight = 0.0;
+  for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+    vec3 h = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), normal, u_roughness);
+    vec3 l = normalize(2.0 * dot(normal, h) * h - normal);
+    float n_dot_l = dot(normal, l);
+    if (n_dot_l > 0.0) {
+      // Sample from a mip where a texel covers about as much as the sample does, which keeps
+      // the few samples from aliasing
+      float n_dot_h = max(dot(normal, h), 0.0);
+      float pdf = distribution_ggx(n_dot_h, u_roughness) / 4.0 + 0.0001;
+      float sample_solid_angle = 1.0 / (float(SAMPLE_COUNT) * pdf + 0.0001);
+      float lod = max(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0, 0.0);
+
+      color += textureLod(samplerCube(t_source, s_source), l, lod).rgb * n_dot_l;
+      total_weight += n_dot_l;
+    }
+  }
+  f_color = vec4(color / total_weight, 1.0);
+}
@@ -0,0 +1,14 @@
+#version 450
+
+layout(location=0) flat in uint v_instance;
+
+layout(location=0) out uvec4 f_id;
+
+layout(set=3, binding=0) uniform PickUniforms {
+  uint u_draw_id; // 0 is reserved for the background
+};
+
+void main() {
+  // Store the depth as well, so that the world position can be reconstructed on the CPU
+  f_id = uvec4(u_draw_id, v_instance, floatBitsToUint(gl_FragCoord.z), 0);
+}
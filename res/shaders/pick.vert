@@ -0,0 +1,51 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+
+layout(location=0) flat out uint v_instance;
+
+layout(set=0, binding=0) uniform Globals {
+  vec3 u_view_position; // world space
+  mat4 u_view_proj;
+};
+
+struct Instance {
+  mat4 model;
+  uint flags;
+};
+
+layout(set=1, binding=0) buffer Instances {
+  Instance s_instances[];
+};
+
+struct MorphTargetVertex {
+  vec4 position;
+  vec4 normal;
+  vec4 tangent;
+};
+
+layout(set=2, binding=0) readonly buffer MorphTargets {
+  MorphTargetVertex s_morph_targets[]; // target major
+};
+
+layout(set=2, binding=1) readonly buffer MorphWeights {
+  float s_morph_weights[]; // instance major
+};
+
+layout(set=2, binding=2) uniform MorphInfo {
+  uint u_morph_vertex_count;
+  uint u_morph_target_count;
+};
+
+void main() {
+  // Ids need to cover exactly the pixels the forward pass draws, so morph targets are applied
+  vec3 position = a_position;
+  for (uint i = 0; i < u_morph_target_count; i++) {
+    float weight = s_morph_weights[uint(gl_InstanceIndex) * u_morph_target_count + i];
+    position += weight * s_morph_targets[i * u_morph_vertex_count + uint(gl_VertexIndex)].position.xyz;
+  }
+
+  mat4 model_matrix = s_instances[gl_InstanceIndex].model;
+  v_instance = uint(gl_InstanceIndex);
+  gl_Position = u_view_proj * model_matrix * vec4(position, 1.0);
+}
@@ -0,0 +1,6 @@
+#version 450
+
+// Depth only prepass of shader.vert, see forward.rs
+
+void main() {
+}
@@ -0,0 +1,17 @@
+#version 450
+
+// Prepass of shader.vert writing the world space normals of the surfaces, normal maps included,
+// for ssao.frag
+
+layout(location=2) in vec2 v_tex_coords;
+layout(location=4) in mat3 v_tangent_matrix; // world space -> tangent space
+
+layout(location=0) out vec4 f_normal;
+
+layout(set = 0, binding = 2) uniform texture2D t_normal;
+layout(set = 0, binding = 3) uniform sampler s_normal;
+
+void main() {
+  vec3 normal = texture(sampler2D(t_normal, s_normal), v_tex_coords).xyz * 2.0 - 1.0;
+  f_normal = vec4(normalize(transpose(v_tangent_matrix) * normal), 1.0);
+}
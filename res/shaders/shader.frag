@@ -2,15 +2,15 @@
 
 // We do all light calculations in tangent space to avoid having to do matrix multiplications
 // for every fragment (in order to convert normal sampled from normal map into world space).
-
-// If you change this, make sure the location qualifiers are correct
-const int MAX_LIGHTS = 2;
+// Light positions are moved into tangent space here rather than in the vertex shader, since the
+// number of lights is only known at runtime.
 
 layout(location=0) in vec3 v_position;       // tangent space
-layout(location=1) in vec3 v_light_positions[MAX_LIGHTS]; // tangent space
-layout(location=3) in vec3 v_view_position;  // tangent space
-layout(location=4) in vec2 v_tex_coords;
-layout(location=5) in vec3 v_position_world_space;
+layout(location=1) in vec3 v_view_position;  // tangent space
+layout(location=2) in vec2 v_tex_coords;
+layout(location=3) in vec3 v_position_world_space;
+layout(location=4) in mat3 v_tangent_matrix; // world space -> tangent space
+layout(location=7) flat in uint v_instance_flags;
 
 layout(location=0) out vec4 f_color;
 
@@ -18,70 +18,432 @@ layout(set = 0, binding = 0) uniform texture2D t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
 layout(set = 0, binding = 2) uniform texture2D t_normal;
 layout(set = 0, binding = 3) uniform sampler s_normal;
+layout(set = 0, binding = 4) uniform texture2D t_emissive;
+layout(set = 0, binding = 5) uniform sampler s_emissive;
+layout(set = 0, binding = 6) uniform Material {
+  vec4 u_emissive; // w is 1 if t_emissive is bound
+};
+
+layout(set = 1, binding = 1) uniform texture2D t_occlusion; // of the ambient light, see ssao.rs
+layout(set = 1, binding = 2) uniform sampler s_occlusion;
+
+struct Light {
+  vec4 position; // world space, w is the influence radius
+  vec4 color; // w is the intensity
+  vec4 direction; // world space
+  vec4 cascade_splits; // view depth where each cascade ends (directional lights)
+  vec4 shadow_params; // far plane, bias
+  uint shadow_index; // first shadow layer (one per cube face or cascade), or NO_SHADOW
+  uint light_type;
+  float cos_inner; // cone of spot lights
+  float cos_outer;
+};
+
+// Matches light::LIGHT_TYPE_*
+const uint LIGHT_TYPE_DIRECTIONAL = 0;
+const uint LIGHT_TYPE_POINT = 1;
+const uint LIGHT_TYPE_SPOT = 2;
+
+// Matches light::NO_SHADOW
+const uint NO_SHADOW = 0xffffffffu;
+
+// Matches model::INSTANCE_RECEIVES_SHADOWS
+const uint INSTANCE_RECEIVES_SHADOWS = 2;
 
-layout(set = 3, binding = 0) uniform Light {
-  vec3 light_positions[MAX_LIGHTS];
-  vec3 light_colors[MAX_LIGHTS];
+// Matches shadow::CASCADE_COUNT
+const uint CASCADE_COUNT = 4;
+
+layout(set = 3, binding = 0) readonly buffer Lights {
+  vec4 u_ambient; // summed over all lights
+  uint u_light_count;
+  uint u_directional_light_count; // directional lights come first
+  Light s_lights[];
 };
-layout(set = 3, binding = 1) uniform textureCube shadow_texs[MAX_LIGHTS];
+layout(set = 3, binding = 1) uniform texture2D shadow_atlas; // shadow maps of all lights
 layout(set = 3, binding = 2) uniform sampler shadow_sampler;
 layout(set = 3, binding = 3) uniform LightConfig {
   bool shadows_enabled;
+  uint u_shadow_filter;
+  uint u_filter_taps;
+  float u_filter_radius; // shadow map texels
+  float u_light_size; // world units
+  float u_sun_size; // tangent of the angular radius of directional lights
+  float u_light_bleed_reduction;
+  float u_environment_intensity;
+};
+layout(set = 3, binding = 4) uniform Clusters {
+  uvec4 u_cluster_grid; // w is set when the heatmap is enabled
+  vec2 u_screen_size;
+  float u_cluster_z_near;
+  float u_cluster_z_far;
+};
+layout(set = 3, binding = 5) readonly buffer ClusterRanges {
+  uvec2 s_cluster_ranges[]; // offset into the light indices and light count
+};
+layout(set = 3, binding = 6) readonly buffer ClusterLightIndices {
+  uint s_cluster_light_indices[];
 };
+struct ShadowLayer {
+  mat4 view_proj;
+  vec4 rect; // offset and size of the shadow map in the atlas, in texture coordinates
+  float depth_scale; // projected depth per world unit along the light direction
+};
+
+layout(set = 3, binding = 7) readonly buffer ShadowLayers {
+  ShadowLayer s_shadow_layers[];
+};
+// Blurred depth moments of the shadow maps, laid out like shadow_atlas, for the variance filters
+layout(set = 3, binding = 8) uniform texture2D shadow_moments;
+layout(set = 3, binding = 9) uniform sampler shadow_compare_sampler;
+// Image based lighting, see environment.rs
+layout(set = 3, binding = 10) uniform textureCube environment_irradiance;
+layout(set = 3, binding = 11) uniform textureCube environment_specular; // roughness by mip
+layout(set = 3, binding = 12) uniform texture2D environment_brdf_lut;
+layout(set = 3, binding = 13) uniform sampler environment_sampler;
+
+// Matches light::SHADOW_FILTER_*
+const uint SHADOW_FILTER_HARDWARE = 0;
+const uint SHADOW_FILTER_PCF = 1;
+const uint SHADOW_FILTER_PCSS = 2;
+const uint SHADOW_FILTER_VSM = 3;
+const uint SHADOW_FILTER_EVSM = 4;
 
-//float z_near = 0.1;
-float z_near = 0.1;
-float z_far = 100;
+// Matches light::MAX_FILTER_TAPS
+const uint MAX_FILTER_TAPS = 32;
 
-vec3 sample_offset_directions[20] = vec3[](
-   vec3( 1,  1,  1), vec3( 1, -1,  1), vec3(-1, -1,  1), vec3(-1,  1,  1),
-   vec3( 1,  1, -1), vec3( 1, -1, -1), vec3(-1, -1, -1), vec3(-1,  1, -1),
-   vec3( 1,  1,  0), vec3( 1, -1,  0), vec3(-1, -1,  0), vec3(-1,  1,  0),
-   vec3( 1,  0,  1), vec3(-1,  0,  1), vec3( 1,  0, -1), vec3(-1,  0, -1),
-   vec3( 0,  1,  1), vec3( 0, -1,  1), vec3( 0, -1, -1), vec3( 0,  1, -1)
+// Largest kernel of the soft shadow filters, in texels
+const float MAX_FILTER_TEXELS = 32.0;
+
+// Matches vsm_moments.frag
+const vec2 EVSM_EXPONENTS = vec2(5.0, 5.0);
+
+// Fraction of each cascade over which it fades into the next one
+const float CASCADE_BLEND = 0.1;
+
+// Poisson disk over the unit circle. Any prefix is still well spread out, so filters with fewer
+// taps just use the first few.
+const vec2 POISSON_DISK[MAX_FILTER_TAPS] = vec2[](
+  vec2(-0.3523, -0.6983), vec2( 0.3306,  0.8975), vec2( 0.7603, -0.3429), vec2(-0.7585,  0.4292),
+  vec2( 0.0261,  0.0852), vec2( 0.8773,  0.3627), vec2( 0.3131, -0.8999), vec2(-0.2927,  0.8798),
+  vec2(-0.9541, -0.2521), vec2( 0.2364, -0.3936), vec2(-0.4192, -0.1959), vec2( 0.3945,  0.3464),
+  vec2( 0.0040,  0.5369), vec2(-0.4019,  0.2191), vec2( 0.9949, -0.0077), vec2( 0.4196, -0.0798),
+  vec2(-0.9214,  0.1028), vec2(-0.7197, -0.6678), vec2(-0.0709, -0.9755), vec2( 0.6277,  0.5925),
+  vec2( 0.6138, -0.7227), vec2( 0.0629, -0.6771), vec2(-0.4876,  0.6064), vec2(-0.0806, -0.2838),
+  vec2(-0.0016,  0.8292), vec2(-0.6773, -0.3898), vec2( 0.6402,  0.1443), vec2( 0.2724,  0.6378),
+  vec2(-0.6328, -0.0236), vec2(-0.3230, -0.4496), vec2(-0.1813,  0.3411), vec2(-0.2279,  0.0040)
 );
 
-float calculate_shadow(vec3 light_position, int shadow_tex_index) {
-  if (!shadows_enabled) {
-    return 0.0;
+// Cube faces in the order they are rendered (see shadow::create_light_proj_cube), with the up
+// vector of each
+const vec3 CUBE_FACE_DIRECTIONS[6] = vec3[](
+  vec3(1, 0, 0), vec3(-1, 0, 0), vec3(0, 1, 0), vec3(0, -1, 0), vec3(0, 0, -1), vec3(0, 0, 1)
+);
+const vec3 CUBE_FACE_UPS[6] = vec3[](
+  vec3(0, 1, 0), vec3(0, 1, 0), vec3(0, 0, 1), vec3(0, 0, -1), vec3(0, 1, 0), vec3(0, 1, 0)
+);
+
+// Finds the cube face a direction points at, and where on that face it ends up. The faces are
+// scattered over the atlas, so this can't be left to a cube sampler.
+vec3 cube_face_coords(vec3 direction) {
+  vec3 a = abs(direction);
+  uint face;
+  if (a.x >= a.y && a.x >= a.z) {
+    face = direction.x > 0.0 ? 0u : 1u;
+  } else if (a.y >= a.z) {
+    face = direction.y > 0.0 ? 2u : 3u;
+  } else {
+    face = direction.z < 0.0 ? 4u : 5u;
   }
 
-  vec3 frag_to_light = v_position_world_space - light_position;
-  frag_to_light *= vec3(1, 1, -1); // TODO Not sure why this is needed
+  // Project onto the face the same way its 90 degree perspective projection does
+  vec3 forward = CUBE_FACE_DIRECTIONS[face];
+  vec3 right = normalize(cross(forward, CUBE_FACE_UPS[face]));
+  vec3 up = cross(right, forward);
+  vec2 ndc = vec2(dot(direction, right), dot(direction, up)) / dot(direction, forward);
+  vec2 uv = ndc * vec2(0.5, -0.5) + 0.5;
+
+  return vec3(uv, float(face));
+}
+
+bool receives_shadows() {
+  return shadows_enabled && (v_instance_flags & INSTANCE_RECEIVES_SHADOWS) != 0u;
+}
+
+// Where the fragment ends up in a shadow map. Offsets around it are given in normalized device
+// coordinates of the light.
+struct ShadowCoords {
+  bool cube; // a point light, with one shadow layer per cube face
+  uint layer; // the first face for point lights
+  vec3 direction; // from the light to the fragment (cubes only)
+  vec3 right; // directions to offset along (cubes only)
+  vec3 up;
+  vec2 uv; // within the shadow map, from 0 to 1 (non-cubes only)
+  float depth; // of the fragment, as stored in the shadow map
+  float bias; // in stored depth
+  float depth_scale; // stored depth per world unit
+  float ndc_per_world; // size of a world unit at the fragment
+  float texel; // size of a shadow map texel
+  bool perspective; // depth is the distance to the light rather than along its direction
+};
+
+// Size of a texel of a shadow layer, in normalized device coordinates of the light
+float shadow_texel(uint layer) {
+  float atlas_size = float(textureSize(sampler2D(shadow_atlas, shadow_sampler), 0).x);
+  return 2.0 / (s_shadow_layers[layer].rect.z * atlas_size);
+}
 
-  float current_depth = length(frag_to_light);
+ShadowCoords point_shadow_coords(Light light) {
+  vec3 light_to_frag = v_position_world_space - light.position.xyz;
+  float distance = length(light_to_frag);
+  float z_far = light.shadow_params.x;
 
-  // Do PCF for smoother shadows
+  ShadowCoords s;
+  s.cube = true;
+  s.layer = light.shadow_index;
+  s.direction = light_to_frag / distance;
+  vec3 reference = abs(s.direction.y) < 0.99 ? vec3(0, 1, 0) : vec3(1, 0, 0);
+  s.right = normalize(cross(s.direction, reference));
+  s.up = cross(s.right, s.direction);
+  s.uv = vec2(0.0);
+  s.depth = distance / z_far; // the shadow pass stores the distance over the far plane
+  s.bias = light.shadow_params.y / z_far;
+  s.depth_scale = 1.0 / z_far;
+  s.ndc_per_world = 1.0 / distance; // the faces have a 90 degree field of view
+  s.texel = shadow_texel(s.layer); // all faces share a resolution
+  s.perspective = true;
+  return s;
+}
 
+// Spot lights store the linear distance to the light like point lights, just in a single
+// perspective shadow map
+ShadowCoords layer_shadow_coords(Light light, uint layer, bool perspective) {
+  mat4 view_proj = s_shadow_layers[layer].view_proj;
+  vec4 light_space = view_proj * vec4(v_position_world_space, 1.0);
+  vec3 ndc = light_space.xyz / light_space.w;
+  float z_far = light.shadow_params.x;
+
+  ShadowCoords s;
+  s.cube = false;
+  s.layer = layer;
+  s.uv = ndc.xy * vec2(0.5, -0.5) + 0.5;
+  if (perspective) {
+    s.depth = length(v_position_world_space - light.position.xyz) / z_far;
+    s.bias = light.shadow_params.y / z_far;
+    s.depth_scale = 1.0 / z_far;
+  } else {
+    s.depth = ndc.z;
+    s.bias = light.shadow_params.y * s_shadow_layers[layer].depth_scale;
+    s.depth_scale = s_shadow_layers[layer].depth_scale;
+  }
+  s.ndc_per_world = length(vec3(view_proj[0][0], view_proj[1][0], view_proj[2][0])) / light_space.w;
+  s.texel = shadow_texel(layer);
+  s.perspective = perspective;
+  return s;
+}
+
+// Atlas coordinates of a point offset from the fragment
+vec2 shadow_atlas_coords(ShadowCoords s, vec2 offset) {
+  uint layer = s.layer;
+  vec2 uv;
+  if (s.cube) {
+    vec3 coords = cube_face_coords(s.direction + s.right * offset.x + s.up * offset.y);
+    layer += uint(coords.z);
+    uv = coords.xy;
+  } else {
+    uv = s.uv + offset * 0.5;
+  }
+
+  // Keep the filters from reaching into the neighbouring shadow maps, which belong to other
+  // faces or lights
+  float margin = 0.25 * s.texel; // half a texel
+  vec4 rect = s_shadow_layers[layer].rect;
+  return rect.xy + clamp(uv, margin, 1.0 - margin) * rect.zw;
+}
+
+// Depth stored in the shadow map
+float shadow_depth(ShadowCoords s, vec2 offset) {
+  vec2 coords = shadow_atlas_coords(s, offset);
+  return textureLod(sampler2D(shadow_atlas, shadow_sampler), coords, 0.0).r;
+}
+
+// Whether the fragment is occluded, bilinearly filtered by the comparison sampler
+float shadow_compare(ShadowCoords s, vec2 offset) {
+  vec3 coords = vec3(shadow_atlas_coords(s, offset), s.depth - s.bias);
+  return 1.0 - texture(sampler2DShadow(shadow_atlas, shadow_compare_sampler), coords);
+}
+
+// Rotates the Poisson disk per pixel, trading banding for noise
+mat2 poisson_rotation() {
+  float noise = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+  float angle = 6.2831853 * noise;
+  return mat2(cos(angle), sin(angle), -sin(angle), cos(angle));
+}
+
+uint filter_taps() {
+  return clamp(u_filter_taps, 1u, MAX_FILTER_TAPS);
+}
+
+float filter_pcf(ShadowCoords s, float radius) {
+  mat2 rotation = poisson_rotation();
+  uint taps = filter_taps();
   float shadow = 0.0;
-  float bias = 0.15;
-  int samples = 20;
-  float view_distance = length(v_view_position - v_position_world_space);
-
-  // By letting the disk radius depend on view distance, we get softer shadows when far away
-  // and sharper shadows when close by
-  float disk_radius = (1.0 + (view_distance / z_far)) / 25.0;
-
-  for (int i = 0; i < samples; ++i) {
-    float closest_depth = texture(samplerCube(shadow_texs[shadow_tex_index], shadow_sampler), frag_to_light + sample_offset_directions[i] * disk_radius).r;
-    closest_depth *= z_far; // undo linear [0,1] mapping done in shadow pass fragment stage
-    if (current_depth - bias > closest_depth) {
-      shadow += 1.0;
+  for (uint i = 0u; i < taps; i++) {
+    shadow += shadow_compare(s, rotation * POISSON_DISK[i] * radius);
+  }
+  return shadow / float(taps);
+}
+
+// Percentage closer soft shadows: the penumbra widens with the distance between the blockers and
+// the fragment, relative to the size of the light
+float filter_pcss(ShadowCoords s) {
+  float max_radius = MAX_FILTER_TEXELS * s.texel;
+  float light_size = s.perspective ? u_light_size : u_sun_size / s.depth_scale;
+  float search_radius = clamp(light_size * s.ndc_per_world, s.texel, max_radius);
+
+  // Find the average depth of the blockers in the area of the light
+  mat2 rotation = poisson_rotation();
+  uint taps = filter_taps();
+  float blocker_depth = 0.0;
+  uint blockers = 0u;
+  for (uint i = 0u; i < taps; i++) {
+    float depth = shadow_depth(s, rotation * POISSON_DISK[i] * search_radius);
+    if (depth < s.depth - s.bias) {
+      blocker_depth += depth;
+      blockers++;
     }
   }
-  shadow /= float(samples);
+  if (blockers == 0u) {
+    return 0.0;
+  }
+  blocker_depth /= float(blockers);
+
+  float penumbra; // world units
+  if (s.perspective) {
+    penumbra = u_light_size * (s.depth - blocker_depth) / max(blocker_depth, 0.0001);
+  } else {
+    penumbra = u_sun_size * (s.depth - blocker_depth) / s.depth_scale;
+  }
+  return filter_pcf(s, clamp(penumbra * s.ndc_per_world, s.texel, max_radius));
+}
+
+// Upper bound on the fraction of the distribution further away than the depth
+float chebyshev(vec2 moments, float depth, float min_variance) {
+  if (depth <= moments.x) {
+    return 1.0;
+  }
+  float variance = max(moments.y - moments.x * moments.x, min_variance);
+  float d = depth - moments.x;
+  float p = variance / (variance + d * d);
+
+  // Cut off the tail of the distribution, where light bleeds through overlapping occluders
+  return clamp((p - u_light_bleed_reduction) / (1.0 - u_light_bleed_reduction), 0.0, 1.0);
+}
+
+float filter_variance(ShadowCoords s) {
+  vec2 coords = shadow_atlas_coords(s, vec2(0.0));
+  vec4 moments = textureLod(sampler2D(shadow_moments, shadow_sampler), coords, 0.0);
+
+  if (u_shadow_filter == SHADOW_FILTER_VSM) {
+    return 1.0 - chebyshev(moments.xy, s.depth, 0.00002);
+  }
+
+  // Exponentially warped depth, see vsm_moments.frag
+  float depth = 2.0 * s.depth - 1.0;
+  vec2 warped = vec2(exp(EVSM_EXPONENTS.x * depth), -exp(-EVSM_EXPONENTS.y * depth));
+  vec2 min_variance = 0.00002 * EVSM_EXPONENTS * EVSM_EXPONENTS * warped * warped;
+  float positive = chebyshev(moments.xy, warped.x, min_variance.x);
+  float negative = chebyshev(moments.zw, warped.y, min_variance.y);
+  return 1.0 - min(positive, negative);
+}
+
+float filter_shadow(ShadowCoords s) {
+  switch (u_shadow_filter) {
+  case SHADOW_FILTER_PCF:
+    return filter_pcf(s, u_filter_radius * s.texel);
+  case SHADOW_FILTER_PCSS:
+    return filter_pcss(s);
+  case SHADOW_FILTER_VSM:
+  case SHADOW_FILTER_EVSM:
+    return filter_variance(s);
+  default:
+    return shadow_compare(s, vec2(0.0));
+  }
+}
+
+float calculate_shadow(Light light) {
+  if (!receives_shadows() || light.shadow_index == NO_SHADOW) {
+    return 0.0;
+  }
+  return filter_shadow(point_shadow_coords(light));
+}
+
+// Distance from the camera along the view direction
+float view_depth() {
+  // Linearize the depth buffer value
+  float z_ndc = gl_FragCoord.z * 2.0 - 1.0;
+  return 2.0 * u_cluster_z_near * u_cluster_z_far
+    / (u_cluster_z_far + u_cluster_z_near - z_ndc * (u_cluster_z_far - u_cluster_z_near));
+}
+
+float sample_cascade(Light light, uint layer) {
+  ShadowCoords s = layer_shadow_coords(light, layer, false);
+  if (s.depth > 1.0) {
+    return 0.0;
+  }
+  return filter_shadow(s);
+}
+
+float calculate_directional_shadow(Light light) {
+  if (!receives_shadows() || light.shadow_index == NO_SHADOW) {
+    return 0.0;
+  }
+
+  float depth = view_depth();
+  vec4 splits = light.cascade_splits;
+  if (depth > splits[CASCADE_COUNT - 1]) {
+    return 0.0;
+  }
+
+  uint cascade = 0;
+  while (cascade < CASCADE_COUNT - 1 && depth > splits[cascade]) {
+    cascade++;
+  }
+  float shadow = sample_cascade(light, light.shadow_index + cascade);
+
+  // Fade into the next cascade towards the end of this one to hide the seam between them
+  float start = cascade == 0 ? u_cluster_z_near : splits[cascade - 1];
+  float blend_start = splits[cascade] - CASCADE_BLEND * (splits[cascade] - start);
+  if (cascade < CASCADE_COUNT - 1 && depth > blend_start) {
+    float t = (depth - blend_start) / (splits[cascade] - blend_start);
+    shadow = mix(shadow, sample_cascade(light, light.shadow_index + cascade + 1), t);
+  }
 
   return shadow;
 }
 
-vec3 calculate_light(vec3 light_position, vec3 light_position_tangent_space, vec3 light_color, int shadow_tex_index) {
-  // Obtain normal from the normal map
+float calculate_spot_shadow(Light light) {
+  if (!receives_shadows() || light.shadow_index == NO_SHADOW) {
+    return 0.0;
+  }
+  return filter_shadow(layer_shadow_coords(light, light.shadow_index, true));
+}
+
+const float SHININESS = 32.0;
+
+// Reflectance at normal incidence of the environment's specular light, as for most dielectrics
+const vec3 ENVIRONMENT_F0 = vec3(0.04);
+
+// Tangent space normal from the normal map
+vec3 surface_normal() {
   vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
 
   // Normals are stored in ranges [0..1], but we need them in [-1, 1]
-  vec3 normal = normalize(object_normal.rgb * 2.0 - 1.0);
-  
-  vec3 light_dir = normalize(light_position_tangent_space - v_position);
+  return normalize(object_normal.rgb * 2.0 - 1.0);
+}
+
+// Diffuse and specular lighting from a light shining from the given tangent space direction
+vec3 shade(vec3 light_dir, vec3 light_color) {
+  vec3 normal = surface_normal();
 
   float diffuse_strength = max(dot(normal, light_dir), 0.0);
   vec3 diffuse_color = light_color * diffuse_strength;
@@ -89,35 +451,117 @@ vec3 calculate_light(vec3 light_position, vec3 light_position_tangent_space, vec
   vec3 view_dir = normalize(v_view_position - v_position);
   vec3 half_dir = normalize(view_dir + light_dir);
 
-  float shininess = 32;
-  float specular = pow(max(dot(normal, half_dir), 0.0), shininess);
+  float specular = pow(max(dot(normal, half_dir), 0.0), SHININESS);
   float specular_strength = 0.5; // TODO sample from specular map
   vec3 specular_color = specular_strength * specular * light_color;
 
-  vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
-  float ambient_strength = 0.1;
-  vec3 ambient_color = light_color * ambient_strength;
+  return diffuse_color + specular_color;
+}
+
+// Diffuse and specular light from the environment, with the split sum approximation: the
+// irradiance map holds the diffuse part, and the specular part is the prefiltered environment
+// scaled by the integrated BRDF
+vec3 environment_light() {
+  // The tangent matrix is orthonormal, so its transpose goes back to world space
+  mat3 tangent_to_world = transpose(v_tangent_matrix);
+  vec3 normal = normalize(tangent_to_world * surface_normal());
+  vec3 view_dir = normalize(tangent_to_world * (v_view_position - v_position));
+  float n_dot_v = max(dot(normal, view_dir), 0.0);
 
-  // calculate shadow
-  float shadow = calculate_shadow(light_position, shadow_tex_index);
+  // The GGX roughness with about the same highlight as the Blinn-Phong exponent of shade()
+  float roughness = pow(2.0 / (SHININESS + 2.0), 0.25);
 
-  // distance falloff
-  float strength = 100;
+  vec3 fresnel = ENVIRONMENT_F0 + (max(vec3(1.0 - roughness), ENVIRONMENT_F0) - ENVIRONMENT_F0)
+    * pow(1.0 - n_dot_v, 5.0);
+  vec3 irradiance = texture(samplerCube(environment_irradiance, environment_sampler), normal).rgb;
+  vec3 diffuse = (1.0 - fresnel) * irradiance;
+
+  vec3 reflected = reflect(-view_dir, normal);
+  float max_lod = float(textureQueryLevels(samplerCube(environment_specular, environment_sampler)) - 1);
+  vec3 prefiltered = textureLod(
+    samplerCube(environment_specular, environment_sampler), reflected, roughness * max_lod
+  ).rgb;
+  vec2 brdf = texture(sampler2D(environment_brdf_lut, environment_sampler), vec2(n_dot_v, roughness)).rg;
+  vec3 specular = prefiltered * (fresnel * brdf.x + brdf.y);
+
+  return (diffuse + specular) * u_environment_intensity;
+}
+
+vec3 calculate_directional_light(Light light) {
+  vec3 light_dir = normalize(v_tangent_matrix * -light.direction.xyz);
+  float shadow = calculate_directional_shadow(light);
+  return (1.0 - shadow) * shade(light_dir, light.color.rgb * light.color.w);
+}
+
+// Point and spot lights
+vec3 calculate_point_light(Light light) {
+  vec3 light_position = light.position.xyz;
+  vec3 light_position_tangent_space = v_tangent_matrix * light_position;
+  float light_radius = light.position.w;
+
+  vec3 light_dir = normalize(light_position_tangent_space - v_position);
+
+  // calculate shadow, and fade spot lights out towards the edge of their cone
+  float shadow;
+  float cone = 1.0;
+  if (light.light_type == LIGHT_TYPE_SPOT) {
+    float cos_angle = dot(normalize(v_position_world_space - light_position), light.direction.xyz);
+    cone = smoothstep(light.cos_outer, light.cos_inner, cos_angle);
+    if (cone <= 0.0) {
+      return vec3(0.0);
+    }
+    shadow = calculate_spot_shadow(light);
+  } else {
+    shadow = calculate_shadow(light);
+  }
+
+  // inverse square distance falloff, windowed so that it smoothly reaches zero at the influence
+  // radius (otherwise there would be visible seams between clusters with and without the light)
   float distance_to_light = length(v_position_world_space - light_position);
-  float intensity = strength * (1 / (distance_to_light * distance_to_light));
+  float window = clamp(1.0 - pow(distance_to_light / light_radius, 4.0), 0.0, 1.0);
+  float intensity = light.color.w / max(distance_to_light * distance_to_light, 0.01) * window * window;
 
-  return (ambient_color + intensity * (1.0 - shadow) * (diffuse_color + specular_color)) * object_color.xyz;
+  return cone * intensity * (1.0 - shadow) * shade(light_dir, light.color.rgb);
+}
+
+// Finds the cluster this fragment belongs to, using the same slicing as cluster.rs
+uint cluster_index() {
+  float slice = log(view_depth() / u_cluster_z_near) / log(u_cluster_z_far / u_cluster_z_near) * float(u_cluster_grid.z);
+  uint z = min(uint(max(slice, 0.0)), u_cluster_grid.z - 1);
+  uvec2 tile = min(uvec2(gl_FragCoord.xy / u_screen_size * vec2(u_cluster_grid.xy)), u_cluster_grid.xy - 1);
+
+  return tile.x + u_cluster_grid.x * (tile.y + u_cluster_grid.y * z);
+}
+
+// Maps [0, 1] to blue -> green -> red
+vec3 heatmap(float t) {
+  return clamp(vec3(1.5) - abs(4.0 * t - vec3(3.0, 2.0, 1.0)), 0.0, 1.0);
 }
 
 void main() {
-  vec3 result = vec3(0, 0, 0);
+  vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+  float occlusion = texelFetch(sampler2D(t_occlusion, s_occlusion), ivec2(gl_FragCoord.xy), 0).r;
+  vec3 result = (u_ambient.rgb + environment_light()) * occlusion;
+
+  for (uint i = 0; i < u_directional_light_count; i++) {
+    result += calculate_directional_light(s_lights[i]);
+  }
 
-  for (int i = 0; i < MAX_LIGHTS; i++) {
-    vec3 light_position = light_positions[i];
-    vec3 light_position_tangent_space = v_light_positions[i];
-    vec3 light_color = light_colors[i];
+  uvec2 cluster = s_cluster_ranges[cluster_index()];
+  for (uint i = 0; i < cluster.y; i++) {
+    result += calculate_point_light(s_lights[s_cluster_light_indices[cluster.x + i]]);
+  }
+  result *= object_color.rgb;
+
+  // Emission isn't lit, and shows even in complete darkness
+  vec3 emissive = u_emissive.rgb;
+  if (u_emissive.w != 0.0) {
+    emissive *= texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb;
+  }
+  result += emissive;
 
-    result += calculate_light(light_position, light_position_tangent_space, light_color, i);
+  if (u_cluster_grid.w != 0) {
+    result = mix(result, heatmap(float(cluster.y) / 8.0), 0.75);
   }
   
   f_color = vec4(result, 1.0);
@@ -1,7 +1,5 @@
 #version 450
 
-const int MAX_LIGHTS = 2;
-
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
 layout(location=2) in vec3 a_normal;
@@ -9,31 +7,72 @@ layout(location=3) in vec3 a_tangent;
 layout(location=4) in vec3 a_bitangent;
 
 layout(location=0) out vec3 v_position;       // tangent space
-layout(location=1) out vec3 v_light_positions[MAX_LIGHTS]; // tangent space
-layout(location=3) out vec3 v_view_position;  // tangent space
-layout(location=4) out vec2 v_tex_coords;
-layout(location=5) out vec3 v_position_world_space;
+layout(location=1) out vec3 v_view_position;  // tangent space
+layout(location=2) out vec2 v_tex_coords;
+layout(location=3) out vec3 v_position_world_space;
+layout(location=4) out mat3 v_tangent_matrix; // world space -> tangent space
+layout(location=7) flat out uint v_instance_flags;
 
 layout(set=1, binding=0) uniform Globals {
   vec3 u_view_position; // world space
   mat4 u_view_proj;
 };
 
+struct Instance {
+  mat4 model;
+  uint flags;
+};
+
 layout(set=2, binding=0) buffer Instances {
-  mat4 s_models[];
+  Instance s_instances[];
+};
+
+struct MorphTargetVertex {
+  vec4 position;
+  vec4 normal;
+  vec4 tangent;
+};
+
+layout(set=4, binding=0) readonly buffer MorphTargets {
+  MorphTargetVertex s_morph_targets[]; // target major
 };
 
-layout(set=3, binding=0) uniform Light {
-  vec3 light_positions[MAX_LIGHTS]; // world space
-  vec3 light_colors[MAX_LIGHTS];
+layout(set=4, binding=1) readonly buffer MorphWeights {
+  float s_morph_weights[]; // instance major
+};
+
+layout(set=4, binding=2) uniform MorphInfo {
+  uint u_morph_vertex_count;
+  uint u_morph_target_count;
 };
 
 void main() {
+  // Blend in the morph targets using the weights of this instance
+  vec3 position = a_position;
+  vec3 morph_normal = a_normal;
+  vec3 morph_tangent = a_tangent;
+  vec3 morph_bitangent = a_bitangent;
+  if (u_morph_target_count > 0) {
+    for (uint i = 0; i < u_morph_target_count; i++) {
+      float weight = s_morph_weights[uint(gl_InstanceIndex) * u_morph_target_count + i];
+      if (weight != 0.0) {
+        MorphTargetVertex delta = s_morph_targets[i * u_morph_vertex_count + uint(gl_VertexIndex)];
+        position += weight * delta.position.xyz;
+        morph_normal += weight * delta.normal.xyz;
+        morph_tangent += weight * delta.tangent.xyz;
+      }
+    }
+
+    // Keep the handedness of the original tangent space
+    float handedness = sign(dot(cross(a_normal, a_tangent), a_bitangent));
+    morph_bitangent = cross(morph_normal, morph_tangent) * handedness;
+  }
+
   // Get the model matrix which will perform model->world transformation
-  mat4 model_matrix = s_models[gl_InstanceIndex];
+  mat4 model_matrix = s_instances[gl_InstanceIndex].model;
 
   // World position is a simple matrix multiplication of the model matrix and the model space position
-  vec4 world_position = model_matrix * vec4(a_position, 1.0);
+  vec4 world_position = model_matrix * vec4(position, 1.0);
 
   // Calculate normal matrix which will perform model-> world transformation for normals.
   // This is essentially just the rotation data from the model matrix (no scale or translations since these
@@ -42,21 +81,17 @@ void main() {
   mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
 
   // Calculate tangent matrix which will perform world->tangent transformation
-  vec3 normal = normalize(normal_matrix * a_normal);
-  vec3 tangent = normalize(normal_matrix * a_tangent);
-  vec3 bitangent = normalize(normal_matrix * a_bitangent);
+  vec3 normal = normalize(normal_matrix * morph_normal);
+  vec3 tangent = normalize(normal_matrix * morph_tangent);
+  vec3 bitangent = normalize(normal_matrix * morph_bitangent);
   mat3 tangent_matrix = transpose(mat3(tangent, bitangent, normal));
 
-  vec3 light_positions_tangent_space[MAX_LIGHTS];
-  for (int i = 0; i < MAX_LIGHTS; i++) {
-    light_positions_tangent_space[i] = tangent_matrix * light_positions[i];
-  }
-
   v_position = tangent_matrix * world_position.xyz;
-  v_light_positions = light_positions_tangent_space;
+  v_tangent_matrix = tangent_matrix;
   v_view_position = tangent_matrix * u_view_position;
   v_tex_coords = a_tex_coords;
   v_position_world_space = vec3(world_position);
+  v_instance_flags = s_instances[gl_InstanceIndex].flags;
 
   gl_Position = u_view_proj * world_position;
 }
@@ -5,19 +5,24 @@ layout(location=0) in vec4 frag_pos;
 layout(set=0, binding=0) uniform ShadowUniforms {
   mat4 u_light_proj;
   vec3 light_position; // world space
+  bool u_linear_depth;
+  float u_far;
 };
 
-float z_far = 100;
-
-// We store the linear distance to the fragment position from the light position
-// to make calculations easier later.
+// For point lights we store the linear distance to the fragment position from the light position
+// to make calculations easier later. Directional lights keep the regular projected depth.
 
 void main() {
+  if (!u_linear_depth) {
+    gl_FragDepth = gl_FragCoord.z;
+    return;
+  }
+
   // get distance between fragment and light source
   float light_distance = length(frag_pos.xyz - light_position);
 
   // map to [0;1] range by dividing by far_plane
-  light_distance = light_distance / z_far;
+  light_distance = light_distance / u_far;
 
   // write this as modified depth
   gl_FragDepth = light_distance;
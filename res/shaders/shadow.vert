@@ -7,15 +7,59 @@ layout(location=0) out vec4 v_position;
 layout(set=0, binding=0) uniform ShadowUniforms {
   mat4 u_light_proj;
   vec3 light_position; // world space
+  bool u_linear_depth;
+  float u_far;
 };
 
+struct Instance {
+  mat4 model;
+  uint flags;
+};
+
+// Matches model::INSTANCE_CASTS_SHADOWS
+const uint INSTANCE_CASTS_SHADOWS = 1;
+
 layout(set=1, binding=0) buffer Instances {
-  mat4 s_models[];
+  Instance s_instances[];
+};
+
+struct MorphTargetVertex {
+  vec4 position;
+  vec4 normal;
+  vec4 tangent;
+};
+
+layout(set=2, binding=0) readonly buffer MorphTargets {
+  MorphTargetVertex s_morph_targets[]; // target major
+};
+
+layout(set=2, binding=1) readonly buffer MorphWeights {
+  float s_morph_weights[]; // instance major
+};
+
+layout(set=2, binding=2) uniform MorphInfo {
+  uint u_morph_vertex_count;
+  uint u_morph_target_count;
 };
 
 void main() {
-  mat4 model_matrix = s_models[gl_InstanceIndex];
-  vec4 world_position = model_matrix * vec4(a_position, 1.0);
+  // Billboards are drawn all at once, so those that don't cast shadows are collapsed to a point
+  // outside the view here rather than left out
+  if ((s_instances[gl_InstanceIndex].flags & INSTANCE_CASTS_SHADOWS) == 0u) {
+    v_position = vec4(0.0);
+    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+    return;
+  }
+
+  // Shadows need to follow the morphed silhouette, so blend in the position deltas
+  vec3 position = a_position;
+  for (uint i = 0; i < u_morph_target_count; i++) {
+    float weight = s_morph_weights[uint(gl_InstanceIndex) * u_morph_target_count + i];
+    position += weight * s_morph_targets[i * u_morph_vertex_count + uint(gl_VertexIndex)].position.xyz;
+  }
+
+  mat4 model_matrix = s_instances[gl_InstanceIndex].model;
+  vec4 world_position = model_matrix * vec4(position, 1.0);
 
   v_position = world_position;
   gl_Position = u_light_proj * vec4(world_position.xyz, 1.0);
@@ -0,0 +1,7 @@
+#version 450
+
+// Clears a region of the shadow atlas to the far plane
+
+void main() {
+  gl_FragDepth = 1.0;
+}
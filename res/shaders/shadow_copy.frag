@@ -0,0 +1,10 @@
+#version 450
+
+// Copies a region of another shadow atlas of the same size, e.g. the baked static casters
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+
+void main() {
+  gl_FragDepth = texelFetch(sampler2D(t_source, s_source), ivec2(gl_FragCoord.xy), 0).r;
+}
@@ -0,0 +1,95 @@
+#version 450
+
+// Ambient occlusion of each pixel from the depth (and normals) of the prepass: the fraction of
+// samples in the hemisphere around the surface that lie behind other geometry
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out float f_occlusion;
+
+layout(set=0, binding=0) uniform SsaoUniforms {
+  mat4 u_proj;
+  mat4 u_inv_proj;
+  mat4 u_view; // world to view space
+  float u_radius; // world units
+  float u_bias;
+  float u_intensity;
+  uint u_sample_count;
+  bool u_normals_from_depth;
+  vec2 u_blur_direction;
+};
+
+const uint MAX_SAMPLES = 32; // matches ssao.rs
+
+layout(set=0, binding=1) uniform SsaoKernel {
+  vec4 u_kernel[MAX_SAMPLES]; // tangent space, in the unit hemisphere around +z
+};
+
+layout(set=1, binding=0) uniform texture2D t_depth;
+layout(set=1, binding=1) uniform texture2D t_normal; // world space, from the normal prepass
+layout(set=1, binding=3) uniform sampler s_texel;
+
+ivec2 target_size() {
+  return textureSize(sampler2D(t_depth, s_texel), 0);
+}
+
+vec3 view_position(ivec2 texel) {
+  texel = clamp(texel, ivec2(0), target_size() - 1);
+  float depth = texelFetch(sampler2D(t_depth, s_texel), texel, 0).r;
+  vec2 uv = (vec2(texel) + 0.5) / vec2(target_size());
+  vec4 position = u_inv_proj * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+  return position.xyz / position.w;
+}
+
+// From the closer neighbour on each axis, so that edges don't bend the normal
+vec3 reconstruct_normal(ivec2 texel, vec3 position) {
+  vec3 left = position - view_position(texel - ivec2(1, 0));
+  vec3 right = view_position(texel + ivec2(1, 0)) - position;
+  vec3 up = position - view_position(texel - ivec2(0, 1));
+  vec3 down = view_position(texel + ivec2(0, 1)) - position;
+  vec3 dx = abs(left.z) < abs(right.z) ? left : right;
+  vec3 dy = abs(up.z) < abs(down.z) ? up : down;
+  return normalize(cross(dy, dx));
+}
+
+// Interleaved gradient noise (Jimenez), rotating the kernel from pixel to pixel for the blur to
+// smooth out
+float noise(vec2 position) {
+  return fract(52.9829189 * fract(dot(position, vec2(0.06711056, 0.00583715))));
+}
+
+void main() {
+  ivec2 texel = ivec2(gl_FragCoord.xy);
+  if (texelFetch(sampler2D(t_depth, s_texel), texel, 0).r >= 1.0) {
+    // Nothing but the background
+    f_occlusion = 1.0;
+    return;
+  }
+
+  vec3 position = view_position(texel);
+  vec3 normal = u_normals_from_depth
+    ? reconstruct_normal(texel, position)
+    : normalize(mat3(u_view) * texelFetch(sampler2D(t_normal, s_texel), texel, 0).xyz);
+
+  float angle = noise(gl_FragCoord.xy) * 6.2831853;
+  vec3 random = vec3(cos(angle), sin(angle), 0.0);
+  vec3 tangent = normalize(random - normal * dot(random, normal));
+  vec3 bitangent = cross(normal, tangent);
+  mat3 tbn = mat3(tangent, bitangent, normal);
+
+  float occlusion = 0.0;
+  uint sample_count = min(u_sample_count, MAX_SAMPLES);
+  for (uint i = 0; i < sample_count; i++) {
+    vec3 sample_position = position + tbn * u_kernel[i].xyz * u_radius;
+
+    vec4 clip = u_proj * vec4(sample_position, 1.0);
+    vec2 uv = clip.xy / clip.w * vec2(0.5, -0.5) + 0.5;
+    float scene_z = view_position(ivec2(uv * vec2(target_size()))).z;
+
+    // Geometry far in front of the sample doesn't occlude it, it's just in the way of the camera
+    float range = smoothstep(0.0, 1.0, u_radius / abs(position.z - scene_z));
+    occlusion += (scene_z >= sample_position.z + u_bias ? 1.0 : 0.0) * range;
+  }
+
+  f_occlusion = pow(1.0 - occlusion / float(max(sample_count, 1)), u_intensity);
+}
@@ -0,0 +1,51 @@
+#version 450
+
+// Blurs the ambient occlusion along one axis, only across texels at a similar depth so that it
+// doesn't bleed over edges
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out float f_occlusion;
+
+layout(set=0, binding=0) uniform SsaoUniforms {
+  mat4 u_proj;
+  mat4 u_inv_proj;
+  mat4 u_view;
+  float u_radius;
+  float u_bias;
+  float u_intensity;
+  uint u_sample_count;
+  bool u_normals_from_depth;
+  vec2 u_blur_direction; // texels
+};
+
+layout(set=1, binding=0) uniform texture2D t_depth;
+layout(set=1, binding=1) uniform texture2D t_normal;
+layout(set=1, binding=2) uniform texture2D t_source; // occlusion
+layout(set=1, binding=3) uniform sampler s_texel;
+
+const int BLUR_RADIUS = 4;
+
+float view_depth(ivec2 texel) {
+  float depth = texelFetch(sampler2D(t_depth, s_texel), texel, 0).r;
+  vec4 position = u_inv_proj * vec4(0.0, 0.0, depth, 1.0);
+  return -position.z / position.w;
+}
+
+void main() {
+  ivec2 size = textureSize(sampler2D(t_source, s_texel), 0);
+  ivec2 texel = ivec2(gl_FragCoord.xy);
+  float center_depth = view_depth(texel);
+
+  float sum = 0.0;
+  float total_weight = 0.0;
+  for (int i = -BLUR_RADIUS; i <= BLUR_RADIUS; i++) {
+    ivec2 coords = clamp(texel + ivec2(u_blur_direction * float(i)), ivec2(0), size - 1);
+    float depth_difference = abs(view_depth(coords) - center_depth) / max(center_depth, 1e-4);
+    float weight = exp(-float(i * i) / 8.0) * exp(-depth_difference * 100.0);
+    sum += texelFetch(sampler2D(t_source, s_texel), coords, 0).r * weight;
+    total_weight += weight;
+  }
+
+  f_occlusion = sum / total_weight;
+}
@@ -0,0 +1,72 @@
+#version 450
+
+// Maps the HDR scene to the swap chain, see tonemap.rs
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform TonemapUniforms {
+  float u_exposure; // linear scale
+  float u_white; // input that maps to 1.0
+  uint u_tonemapper;
+};
+
+layout(set=0, binding=1) uniform texture2D t_source;
+layout(set=0, binding=2) uniform sampler s_source;
+
+// Matches tonemap.rs
+const uint TONEMAPPER_CLAMP = 0;
+const uint TONEMAPPER_REINHARD = 1;
+const uint TONEMAPPER_ACES = 2;
+const uint TONEMAPPER_FILMIC = 3;
+
+vec3 reinhard(vec3 color) {
+  // Applied to the luminance, so that bright colors don't wash out to white
+  float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+  float mapped = luminance * (1.0 + luminance / (u_white * u_white)) / (1.0 + luminance);
+  return color * (mapped / max(luminance, 1e-6));
+}
+
+vec3 aces(vec3 color) {
+  const float a = 2.51;
+  const float b = 0.03;
+  const float c = 2.43;
+  const float d = 0.59;
+  const float e = 0.14;
+  return (color * (a * color + b)) / (color * (c * color + d) + e);
+}
+
+vec3 hable(vec3 x) {
+  const float a = 0.15; // shoulder strength
+  const float b = 0.50; // linear strength
+  const float c = 0.10; // linear angle
+  const float d = 0.20; // toe strength
+  const float e = 0.02; // toe numerator
+  const float f = 0.30; // toe denominator
+  return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+vec3 filmic(vec3 color) {
+  // The curve was tuned for an exposure bias of 2
+  return hable(color * 2.0) / hable(vec3(u_white));
+}
+
+void main() {
+  vec3 color = texelFetch(sampler2D(t_source, s_source), ivec2(gl_FragCoord.xy), 0).rgb * u_exposure;
+
+  switch (u_tonemapper) {
+  case TONEMAPPER_REINHARD:
+    color = reinhard(color);
+    break;
+  case TONEMAPPER_ACES:
+    color = aces(color);
+    break;
+  case TONEMAPPER_FILMIC:
+    color = filmic(color);
+    break;
+  }
+
+  // The swap chain is sRGB, so it encodes the linear color itself
+  f_color = vec4(clamp(color, 0.0, 1.0), 1.0);
+}
@@ -0,0 +1,35 @@
+#version 450
+
+// Blurs the output of vsm_moments.frag vertically into the shadow map's region of the moments
+// atlas
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_moments;
+
+layout(set=0, binding=0) uniform VsmUniforms {
+  uvec2 u_origin; // of the shadow map in the atlas, in texels
+  uint u_size;
+  uint u_blur_radius; // texels
+  bool u_exponential;
+};
+
+layout(set=1, binding=0) uniform texture2D t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+void main() {
+  int size = int(u_size);
+  ivec2 texel = ivec2(gl_FragCoord.xy) - ivec2(u_origin);
+
+  int radius = int(u_blur_radius);
+  float sigma = max(float(radius) * 0.5, 0.5);
+  vec4 sum = vec4(0.0);
+  float total_weight = 0.0;
+  for (int y = -radius; y <= radius; y++) {
+    ivec2 coords = ivec2(texel.x, clamp(texel.y + y, 0, size - 1));
+    float weight = exp(-float(y * y) / (2.0 * sigma * sigma));
+    sum += texelFetch(sampler2D(t_source, s_source), coords, 0) * weight;
+    total_weight += weight;
+  }
+  f_moments = sum / total_weight;
+}
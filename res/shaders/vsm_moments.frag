@@ -0,0 +1,52 @@
+#version 450
+
+// Turns a shadow map in the atlas into depth moments, blurred horizontally. They're written to
+// the top left corner of the scratch texture.
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_moments;
+
+layout(set=0, binding=0) uniform VsmUniforms {
+  uvec2 u_origin; // of the shadow map in the atlas, in texels
+  uint u_size;
+  uint u_blur_radius; // texels
+  bool u_exponential;
+};
+
+layout(set=1, binding=0) uniform texture2D t_source;
+layout(set=1, binding=1) uniform sampler s_source;
+
+// Matches shader.frag
+const vec2 EVSM_EXPONENTS = vec2(5.0, 5.0);
+
+vec4 moments(float depth) {
+  if (u_exponential) {
+    // Warp the depth so that the Chebyshev bound is much tighter (see shader.frag)
+    depth = 2.0 * depth - 1.0;
+    float positive = exp(EVSM_EXPONENTS.x * depth);
+    float negative = -exp(-EVSM_EXPONENTS.y * depth);
+    return vec4(positive, positive * positive, negative, negative * negative);
+  }
+  return vec4(depth, depth * depth, 0.0, 0.0);
+}
+
+void main() {
+  ivec2 origin = ivec2(u_origin);
+  int size = int(u_size);
+  ivec2 texel = ivec2(gl_FragCoord.xy);
+
+  // Stay inside the shadow map, its neighbours in the atlas belong to other lights
+  int radius = int(u_blur_radius);
+  float sigma = max(float(radius) * 0.5, 0.5);
+  vec4 sum = vec4(0.0);
+  float total_weight = 0.0;
+  for (int x = -radius; x <= radius; x++) {
+    ivec2 coords = origin + ivec2(clamp(texel.x + x, 0, size - 1), texel.y);
+    float depth = texelFetch(sampler2D(t_source, s_source), coords, 0).r;
+    float weight = exp(-float(x * x) / (2.0 * sigma * sigma));
+    sum += moments(depth) * weight;
+    total_weight += weight;
+  }
+  f_moments = sum / total_weight;
+}
@@ -0,0 +1,451 @@
+use crate::camera;
+use crate::camera::Projection;
+use crate::environment;
+use crate::pipeline;
+use crate::prelude::*;
+use crate::sky;
+use crate::texture;
+use crate::Context;
+use crate::{compile_frag, compile_vertex};
+use std::num::NonZeroU32;
+
+/// What is drawn where no geometry covers the screen
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Background {
+    /// Only the clear color of the forward pass
+    ClearColor,
+    /// Blends from `bottom` straight down to `top` straight up
+    Gradient { top: [f32; 3], bottom: [f32; 3] },
+    /// A simple sky: a gradient from the horizon to the zenith, flat ground below the horizon and
+    /// a sun in the direction of `sun_direction` (pointing towards the sun)
+    Sky {
+        zenith: [f32; 3],
+        horizon: [f32; 3],
+        ground: [f32; 3],
+        sun_direction: [f32; 3],
+    },
+    /// The cubemap given to `BackgroundPass::set_cubemap`, scaled by `intensity`
+    Cubemap { intensity: f32 },
+    /// The equirectangular panorama given to `BackgroundPass::set_panorama`, scaled by
+    /// `intensity`
+    Panorama { intensity: f32 },
+    /// A physically based sky with the sun in it
+    PhysicalSky(sky::Sky),
+}
+
+impl Background {
+    /// Each background with reasonable parameters
+    pub const ALL: [Background; 6] = [
+        Background::ClearColor,
+        Background::Gradient {
+            top: [0.1, 0.2, 0.3],
+            bottom: [0.02, 0.02, 0.03],
+        },
+        Background::Sky {
+            zenith: [0.15, 0.35, 0.75],
+            horizon: [0.7, 0.8, 0.9],
+            ground: [0.2, 0.18, 0.15],
+            sun_direction: [0.4, 1.0, 0.3],
+        },
+        Background::Cubemap { intensity: 1.0 },
+        Background::Panorama { intensity: 1.0 },
+        Background::PhysicalSky(sky::Sky::DEFAULT),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Background::ClearColor => "Clear color",
+            Background::Gradient { .. } => "Gradient",
+            Background::Sky { .. } => "Sky",
+            Background::Cubemap { .. } => "Cubemap",
+            Background::Panorama { .. } => "Panorama",
+            Background::PhysicalSky(_) => "Physical sky",
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::ClearColor
+    }
+}
+
+// Matches background.frag
+const BACKGROUND_GRADIENT: u32 = 0;
+const BACKGROUND_SKY: u32 = 1;
+const BACKGROUND_CUBEMAP: u32 = 2;
+const BACKGROUND_PANORAMA: u32 = 3;
+const BACKGROUND_PHYSICAL_SKY: u32 = 4;
+
+/// Draws the background behind the geometry, as seen through the camera. It's drawn within the
+/// forward pass after the opaque geometry, and only covers what the geometry left at the far
+/// plane.
+pub struct BackgroundPass {
+    pub background: Background,
+    pipeline: wgpu::RenderPipeline,
+    /// Kept to rebuild the pipeline when the sample count of the forward pass changes
+    pipeline_layout: wgpu::PipelineLayout,
+    vs_module: wgpu::ShaderModule,
+    fs_module: wgpu::ShaderModule,
+    uniforms_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    cubemap: wgpu::TextureView,
+    panorama: wgpu::TextureView,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BackgroundUniforms {
+    /// Normalized device coordinates to world space directions from the camera
+    inv_view_proj: Matrix4,
+    /// Top of the gradient or zenith of the sky, or the intensity of the cubemap and panorama
+    /// in `w`
+    color_a: Vector4,
+    /// Bottom of the gradient or horizon of the sky
+    color_b: Vector4,
+    /// Ground of the sky
+    color_c: Vector4,
+    sun_direction: Vector4,
+    /// One of the `BACKGROUND_*` constants
+    mode: u32,
+    _padding: [u32; 3],
+    /// The Preetham model of the physical sky
+    sky: sky::SkyRaw,
+}
+
+unsafe impl bytemuck::Pod for BackgroundUniforms {}
+unsafe impl bytemuck::Zeroable for BackgroundUniforms {}
+
+impl BackgroundPass {
+    pub fn new(context: &mut Context) -> Self {
+        let device = &context.device;
+        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Background uniforms"),
+            size: std::mem::size_of::<BackgroundUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<
+                            BackgroundUniforms,
+                        >() as _),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::Cube,
+                        component_type: wgpu::TextureComponentType::Float,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+            label: Some("Background bind group layout"),
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Background"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            compare: None,
+            ..Default::default()
+        });
+
+        // Black until something is set
+        let cubemap =
+            create_black_texture(device, &context.queue, 6, environment::ENVIRONMENT_FORMAT)
+                .create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Background cubemap"),
+                    format: None,
+                    dimension: Some(wgpu::TextureViewDimension::Cube),
+                    aspect: wgpu::TextureAspect::All,
+                    base_mip_level: 0,
+                    level_count: None,
+                    base_array_layer: 0,
+                    array_layer_count: NonZeroU32::new(6),
+                });
+        let panorama =
+            create_black_texture(device, &context.queue, 1, environment::EQUIRECT_FORMAT)
+                .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            &uniforms_buffer,
+            &cubemap,
+            &panorama,
+            &sampler,
+        );
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Background"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&bind_group_layout],
+        });
+        let vs_module = compile_vertex!(
+            &context.device,
+            &mut context.shader_compiler,
+            "background.vert"
+        )
+        .unwrap();
+        let fs_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "background.frag"
+        )
+        .unwrap();
+        let pipeline = create_pipeline(&context.device, &layout, &vs_module, &fs_module, 1);
+
+        BackgroundPass {
+            background: Background::default(),
+            pipeline,
+            pipeline_layout: layout,
+            vs_module,
+            fs_module,
+            uniforms_buffer,
+            bind_group_layout,
+            bind_group,
+            sampler,
+            cubemap,
+            panorama,
+        }
+    }
+
+    /// Rebuilds the pipeline for a forward pass rendering with `sample_count` samples per pixel
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.vs_module,
+            &self.fs_module,
+            sample_count,
+        );
+    }
+
+    /// Sets the cubemap drawn by `Background::Cubemap`, such as the environment of the lights
+    pub fn set_cubemap(&mut self, device: &wgpu::Device, cubemap: &environment::Cubemap) {
+        self.cubemap = cubemap.create_view();
+        self.recreate_bind_group(device);
+    }
+
+    /// Sets the panorama drawn by `Background::Panorama`, as loaded by
+    /// `environment::load_equirect`
+    pub fn set_panorama(&mut self, device: &wgpu::Device, panorama: &wgpu::Texture) {
+        self.panorama = panorama.create_view(&wgpu::TextureViewDescriptor::default());
+        self.recreate_bind_group(device);
+    }
+
+    fn recreate_bind_group(&mut self, device: &wgpu::Device) {
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniforms_buffer,
+            &self.cubemap,
+            &self.panorama,
+            &self.sampler,
+        );
+    }
+
+    /// Uploads the background and the rotation of the camera
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        camera: &camera::Camera,
+        projection: &impl Projection,
+    ) {
+        // Only the rotation matters, the background is infinitely far away
+        let mut view = camera.calc_matrix();
+        view.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let inv_view_proj = (projection.calc_matrix() * view)
+            .invert()
+            .unwrap_or_else(Matrix4::identity);
+
+        let mut uniforms = BackgroundUniforms {
+            inv_view_proj,
+            color_a: Vector4::zero(),
+            color_b: Vector4::zero(),
+            color_c: Vector4::zero(),
+            sun_direction: Vector4::zero(),
+            mode: BACKGROUND_GRADIENT,
+            _padding: [0; 3],
+            sky: bytemuck::Zeroable::zeroed(),
+        };
+        match self.background {
+            Background::ClearColor => return,
+            Background::Gradient { top, bottom } => {
+                uniforms.color_a = Vector3::from(top).extend(0.0);
+                uniforms.color_b = Vector3::from(bottom).extend(0.0);
+            }
+            Background::Sky {
+                zenith,
+                horizon,
+                ground,
+                sun_direction,
+            } => {
+                uniforms.mode = BACKGROUND_SKY;
+                uniforms.color_a = Vector3::from(zenith).extend(0.0);
+                uniforms.color_b = Vector3::from(horizon).extend(0.0);
+                uniforms.color_c = Vector3::from(ground).extend(0.0);
+                uniforms.sun_direction = Vector3::from(sun_direction).normalize().extend(0.0);
+            }
+            Background::Cubemap { intensity } => {
+                uniforms.mode = BACKGROUND_CUBEMAP;
+                uniforms.color_a.w = intensity;
+            }
+            Background::Panorama { intensity } => {
+                uniforms.mode = BACKGROUND_PANORAMA;
+                uniforms.color_a.w = intensity;
+            }
+            Background::PhysicalSky(sky) => {
+                uniforms.mode = BACKGROUND_PHYSICAL_SKY;
+                uniforms.color_a = sky.sun_color().extend(sky.sun_intensity);
+                uniforms.sun_direction = sky.sun_direction.normalize().extend(0.0);
+                uniforms.sky = sky.to_raw();
+            }
+        }
+        queue.write_buffer(&self.uniforms_buffer, 0, bytemuck::bytes_of(&uniforms));
+    }
+
+    /// Draws the background into the forward pass, after the opaque geometry
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.background == Background::ClearColor {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    pipeline::create(
+        "background",
+        device,
+        layout,
+        vs_module,
+        fs_module,
+        Some(texture::Texture::HDR_FORMAT),
+        Some(pipeline::DepthConfig::no_bias().read_only()),
+        sample_count,
+        &[],
+    )
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniforms_buffer: &wgpu::Buffer,
+    cubemap: &wgpu::TextureView,
+    panorama: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: uniforms_buffer,
+                    offset: 0,
+                    size: None,
+                },
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(cubemap),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(panorama),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: Some("Background"),
+    })
+}
+
+/// A 1x1 texture with the given number of layers, cleared to black
+fn create_black_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: u32,
+    format: wgpu::TextureFormat,
+) -> wgpu::Texture {
+    let size = wgpu::Extent3d {
+        width: 1,
+        height: 1,
+        depth: layers,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        label: Some("Black"),
+    });
+
+    // Textures aren't cleared when they are created
+    let texel_size = match format {
+        wgpu::TextureFormat::Rgba32Float => 16,
+        _ => 8,
+    };
+    queue.write_texture(
+        wgpu::TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        &vec![0; (texel_size * layers) as usize],
+        wgpu::TextureDataLayout {
+            offset: 0,
+            bytes_per_row: texel_size,
+            rows_per_image: 1,
+        },
+        size,
+    );
+
+    texture
+}
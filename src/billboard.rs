@@ -1,3 +1,4 @@
+use crate::bounds::BoundingSphere;
 use crate::camera;
 use crate::geometry;
 use crate::geometry::Vertex;
@@ -5,17 +6,27 @@ use crate::model;
 use crate::model::MaterialId;
 use crate::pipeline;
 use crate::prelude::*;
+use crate::shadow;
+use crate::texture;
 use crate::Context;
 use crate::{compile_frag, compile_vertex};
 use std::collections::HashMap;
 
 const MAX_BILLBOARDS: u64 = 10000;
 
+/// Half the width and height of a billboard
+const BILLBOARD_SCALE: f32 = 0.5;
+
 pub type BillboardId = usize;
 
 pub struct Billboard {
     pub position: Vector3,
     pub material: MaterialId,
+    /// Scales the color of the texture, so that it can be brighter than white and bloom
+    pub emission: f32,
+    /// Whether the billboard is drawn into the shadow maps. Leave this off for billboards marking
+    /// a light, which would otherwise shadow everything around it.
+    pub casts_shadows: bool,
 }
 
 struct BillboardData {
@@ -88,7 +99,7 @@ impl Billboards {
 
         // TODO don't recalculate view matrix
         let view_mat = camera.calc_matrix();
-        let scale_mat = Matrix4::from_scale(0.5);
+        let scale_mat = Matrix4::from_scale(BILLBOARD_SCALE);
 
         for (id, billboard) in self.billboards.iter() {
             // From: https://swiftcoder.wordpress.com/2008/11/25/constructing-a-billboard-matrix/
@@ -111,9 +122,13 @@ impl Billboards {
                 billboard.position.z,
                 1.0,
             );
-            let instance = model::InstanceRaw {
-                model: billboard_transform * scale_mat,
+            let flags = if billboard.casts_shadows {
+                model::INSTANCE_CASTS_SHADOWS
+            } else {
+                0
             };
+            let mut instance = model::InstanceRaw::new(billboard_transform * scale_mat, flags);
+            instance.emission = billboard.emission;
 
             let buffer = &self.instances[&billboard.material].instance_buffer;
             context.queue.write_buffer(
@@ -124,6 +139,39 @@ impl Billboards {
         }
     }
 
+    /// The billboards to draw into the shadow maps, one caster per material that has billboards
+    /// casting shadows. `upload` has to be called first, as they face the camera.
+    pub fn shadow_casters(&self) -> Vec<shadow::BillboardCaster<'_>> {
+        // the quad spans the scale in every direction, whichever way it's turned
+        let radius = BILLBOARD_SCALE * std::f32::consts::SQRT_2;
+        self.instances
+            .iter()
+            .filter_map(|(material_id, data)| {
+                let bounds: Vec<_> = self
+                    .billboards
+                    .values()
+                    .filter(|billboard| {
+                        billboard.material == *material_id && billboard.casts_shadows
+                    })
+                    .map(|billboard| {
+                        BoundingSphere::new(Point3::from_vec(billboard.position), radius)
+                    })
+                    .collect();
+                if bounds.is_empty() {
+                    return None;
+                }
+                Some(shadow::BillboardCaster {
+                    vertex_buffer: &self.plane.vertex_buffer,
+                    index_buffer: &self.plane.index_buffer,
+                    indices: 0..geometry::PLANE_INDICES.len() as u32,
+                    instances_bind_group: &data.instance_bind_group,
+                    instances: 0..data.num_instances,
+                    bounds,
+                })
+            })
+            .collect()
+    }
+
     pub fn render<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
@@ -150,6 +198,7 @@ impl Billboards {
 pub fn create_pipeline(
     context: &mut Context,
     uniform_bind_group_layout: &wgpu::BindGroupLayout,
+    sample_count: u32,
 ) -> wgpu::RenderPipeline {
     let layout = context
         .device
@@ -182,8 +231,9 @@ pub fn create_pipeline(
         &layout,
         &vs_module,
         &fs_module,
-        Some(context.sc_desc.format),
+        Some(texture::Texture::HDR_FORMAT),
         Some(pipeline::DepthConfig::no_bias()),
+        sample_count,
         &[geometry::SimpleVertex::desc()],
     )
 }
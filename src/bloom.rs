@@ -0,0 +1,419 @@
+use crate::compile_frag;
+use crate::post::{self, Effect, Parameter, PostFrame};
+use crate::texture;
+use crate::Context;
+use std::mem;
+use std::num::NonZeroU32;
+
+/// Most mips in the chain, the first of which is half the size of the scene
+const MAX_MIP_COUNT: u32 = 6;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BloomUniforms {
+    threshold: f32,
+    knee: f32,
+    intensity: f32,
+    /// Of the upsampling filter, in texels of the smaller mip
+    radius: f32,
+    /// Whether the downsample is the first one, which applies the threshold
+    prefilter: u32,
+    mip_count: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for BloomUniforms {}
+unsafe impl bytemuck::Zeroable for BloomUniforms {}
+
+/// Light scattered around bright parts of the scene, as in a lens. The scene is downsampled
+/// into a chain of mips with a wide filter, which are then upsampled and added back up the chain
+/// (after Jorge Jimenez, "Next Generation Post Processing in Call of Duty: Advanced Warfare").
+pub struct Bloom {
+    /// Only light brighter than this scatters
+    pub threshold: f32,
+    /// Width of the soft transition around the threshold, as a fraction of it
+    pub knee: f32,
+    /// Fraction of the scattered light added to the scene
+    pub intensity: f32,
+    /// Spread of the upsampling filter, in texels
+    pub radius: f32,
+    downsample_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    uniforms_buffer: wgpu::Buffer,
+    uniforms_bind_group: wgpu::BindGroup,
+    source_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    chain: BloomChain,
+}
+
+/// The mips of the chain, half the size of the scene and smaller
+struct BloomChain {
+    _texture: wgpu::Texture,
+    views: Vec<wgpu::TextureView>,
+    /// For sampling each mip on its own
+    bind_groups: Vec<wgpu::BindGroup>,
+}
+
+impl Bloom {
+    pub fn new(context: &mut Context, fullscreen: &post::Fullscreen) -> Self {
+        let device = &context.device;
+        let uniforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: true,
+                        min_binding_size: uniforms_binding_size(),
+                    },
+                    count: None,
+                }],
+                label: Some("Bloom uniforms bind group layout"),
+            });
+        let source_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                        count: None,
+                    },
+                ],
+                label: Some("Bloom source bind group layout"),
+            });
+
+        // The first downsample, and everything else
+        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Bloom uniforms"),
+            size: 2 * wgpu::BIND_BUFFER_ALIGNMENT,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uniforms_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniforms_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniforms_buffer,
+                    offset: 0,
+                    size: uniforms_binding_size(),
+                },
+            }],
+            label: Some("Bloom uniforms bind group"),
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Bloom"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bloom pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout, &source_bind_group_layout],
+        });
+        let composite_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bloom composite pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[
+                &uniforms_bind_group_layout,
+                &source_bind_group_layout,
+                &source_bind_group_layout,
+            ],
+        });
+        let downsample_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "bloom_downsample.frag"
+        )
+        .unwrap();
+        let upsample_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "bloom_upsample.frag"
+        )
+        .unwrap();
+        let composite_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "bloom_composite.frag"
+        )
+        .unwrap();
+
+        let downsample_pipeline = fullscreen.create_pipeline(
+            "Bloom downsample",
+            &context.device,
+            &layout,
+            &downsample_module,
+            texture::Texture::HDR_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        // Each mip is added to the one above it
+        let upsample_pipeline = fullscreen.create_pipeline(
+            "Bloom upsample",
+            &context.device,
+            &layout,
+            &upsample_module,
+            texture::Texture::HDR_FORMAT,
+            wgpu::BlendDescriptor {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        );
+        let composite_pipeline = fullscreen.create_pipeline(
+            "Bloom composite",
+            &context.device,
+            &composite_layout,
+            &composite_module,
+            texture::Texture::HDR_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        let chain = BloomChain::new(
+            &context.device,
+            &context.sc_desc,
+            &source_bind_group_layout,
+            &sampler,
+        );
+
+        Bloom {
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.3,
+            radius: 1.0,
+            downsample_pipeline,
+            upsample_pipeline,
+            composite_pipeline,
+            uniforms_buffer,
+            uniforms_bind_group,
+            source_bind_group_layout,
+            sampler,
+            chain,
+        }
+    }
+
+    fn source_bind_group(
+        &self,
+        device: &wgpu::Device,
+        source: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        create_source_bind_group(
+            device,
+            &self.source_bind_group_layout,
+            source,
+            &self.sampler,
+        )
+    }
+}
+
+impl Effect for Bloom {
+    fn name(&self) -> &str {
+        "Bloom"
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("Threshold", &mut self.threshold, 0.0..=10.0),
+            Parameter::new("Knee", &mut self.knee, 0.0..=1.0),
+            Parameter::new("Intensity", &mut self.intensity, 0.0..=2.0),
+            Parameter::new("Radius", &mut self.radius, 0.5..=4.0),
+        ]
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
+        self.chain = BloomChain::new(
+            device,
+            sc_desc,
+            &self.source_bind_group_layout,
+            &self.sampler,
+        );
+    }
+
+    fn render(&mut self, encoder: &mut wgpu::CommandEncoder, frame: &PostFrame) {
+        let mip_count = self.chain.views.len();
+        for (draw_index, prefilter) in [true, false].iter().enumerate() {
+            let uniforms = BloomUniforms {
+                threshold: self.threshold,
+                knee: self.knee,
+                intensity: self.intensity,
+                radius: self.radius,
+                prefilter: *prefilter as u32,
+                mip_count: mip_count as u32,
+                _padding: [0; 2],
+            };
+            frame.queue.write_buffer(
+                &self.uniforms_buffer,
+                draw_offset(draw_index) as wgpu::BufferAddress,
+                bytemuck::bytes_of(&uniforms),
+            );
+        }
+        let prefilter_offset = [draw_offset(0) as u32];
+        let offset = [draw_offset(1) as u32];
+
+        let draw = |encoder: &mut wgpu::CommandEncoder,
+                    output: &wgpu::TextureView,
+                    load: wgpu::LoadOp<wgpu::Color>,
+                    pipeline: &wgpu::RenderPipeline,
+                    offset: &[u32],
+                    sources: &[&wgpu::BindGroup]| {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load, store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &self.uniforms_bind_group, offset);
+            for (i, source) in sources.iter().enumerate() {
+                render_pass.set_bind_group(i as u32 + 1, source, &[]);
+            }
+            render_pass.draw(0..3, 0..1);
+        };
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::BLACK);
+
+        // Down the chain, from the scene
+        let scene = self.source_bind_group(frame.device, &frame.source.view);
+        draw(
+            encoder,
+            &self.chain.views[0],
+            clear,
+            &self.downsample_pipeline,
+            &prefilter_offset,
+            &[&scene],
+        );
+        for mip in 1..mip_count {
+            draw(
+                encoder,
+                &self.chain.views[mip],
+                clear,
+                &self.downsample_pipeline,
+                &offset,
+                &[&self.chain.bind_groups[mip - 1]],
+            );
+        }
+
+        // And back up, adding each mip to the one above it
+        for mip in (0..mip_count - 1).rev() {
+            draw(
+                encoder,
+                &self.chain.views[mip],
+                wgpu::LoadOp::Load,
+                &self.upsample_pipeline,
+                &offset,
+                &[&self.chain.bind_groups[mip + 1]],
+            );
+        }
+
+        draw(
+            encoder,
+            &frame.output.view,
+            clear,
+            &self.composite_pipeline,
+            &offset,
+            &[&self.chain.bind_groups[0], &scene],
+        );
+    }
+}
+
+impl BloomChain {
+    fn new(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+    ) -> Self {
+        let width = (sc_desc.width / 2).max(1);
+        let height = (sc_desc.height / 2).max(1);
+        // Down to a few texels on the short side
+        let mip_count = (32 - width.min(height).leading_zeros()).min(MAX_MIP_COUNT);
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Bloom"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture::Texture::HDR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let views: Vec<wgpu::TextureView> = (0..mip_count)
+            .map(|mip| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Bloom mip"),
+                    format: None,
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    aspect: wgpu::TextureAspect::All,
+                    base_mip_level: mip,
+                    level_count: NonZeroU32::new(1),
+                    base_array_layer: 0,
+                    array_layer_count: NonZeroU32::new(1),
+                })
+            })
+            .collect();
+        let bind_groups = views
+            .iter()
+            .map(|view| create_source_bind_group(device, layout, view, sampler))
+            .collect();
+
+        BloomChain {
+            _texture: texture,
+            views,
+            bind_groups,
+        }
+    }
+}
+
+fn create_source_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    source: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(source),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: Some("Bloom source bind group"),
+    })
+}
+
+fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+    wgpu::BufferSize::new(mem::size_of::<BloomUniforms>() as _)
+}
+
+fn draw_offset(draw_index: usize) -> usize {
+    draw_index * wgpu::BIND_BUFFER_ALIGNMENT as usize
+}
@@ -0,0 +1,381 @@
+use crate::prelude::*;
+
+/// Axis-aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3,
+    pub max: Point3,
+}
+
+impl Aabb {
+    pub fn new(min: Point3, max: Point3) -> Self {
+        Aabb { min, max }
+    }
+
+    /// An inverted box which contains nothing. Growing it by any point yields a box around that
+    /// point.
+    pub fn empty() -> Self {
+        Aabb {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Point3>>(points: I) -> Self {
+        points.into_iter().fold(Aabb::empty(), |aabb, p| aabb.grow(p))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&self, p: Point3) -> Self {
+        Aabb {
+            min: Point3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+            max: Point3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z)),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        if other.is_empty() {
+            return *self;
+        }
+        self.grow(other.min).grow(other.max)
+    }
+
+    pub fn center(&self) -> Point3 {
+        self.min.midpoint(self.max)
+    }
+
+    /// Half the size of the box along each axis
+    pub fn extents(&self) -> Vector3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn contains(&self, p: Point3) -> bool {
+        p.x >= self.min.x
+            && p.x <= self.max.x
+            && p.y >= self.min.y
+            && p.y <= self.max.y
+            && p.z >= self.min.z
+            && p.z <= self.max.z
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    /// Returns the box enclosing this box after it has been transformed by the given matrix.
+    pub fn transform(&self, m: &Matrix4) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+
+        // From "Transforming Axis-Aligned Bounding Boxes" by Jim Arvo (Graphics Gems, 1990).
+        // Transforming the center and then adding the absolute value of the rotated extents
+        // gives the tightest box without transforming all 8 corners.
+        let center = m.transform_point(self.center());
+        let e = self.extents();
+        let extents = Vector3::new(
+            m.x.x.abs() * e.x + m.y.x.abs() * e.y + m.z.x.abs() * e.z,
+            m.x.y.abs() * e.x + m.y.y.abs() * e.y + m.z.y.abs() * e.z,
+            m.x.z.abs() * e.x + m.y.z.abs() * e.y + m.z.z.abs() * e.z,
+        );
+        Aabb {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+
+    /// Squared distance from a point to the closest point of the box (zero if inside)
+    pub fn distance2(&self, p: Point3) -> f32 {
+        let dx = (self.min.x - p.x).max(0.0).max(p.x - self.max.x);
+        let dy = (self.min.y - p.y).max(0.0).max(p.y - self.max.y);
+        let dz = (self.min.z - p.z).max(0.0).max(p.z - self.max.z);
+        dx * dx + dy * dy + dz * dz
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn new(center: Point3, radius: f32) -> Self {
+        BoundingSphere { center, radius }
+    }
+
+    /// Creates a sphere centered on the bounding box of the points. Not the tightest sphere
+    /// possible, but cheap and good enough for culling.
+    pub fn from_points(points: &[Point3]) -> Self {
+        let center = Aabb::from_points(points.iter().copied()).center();
+        let radius = points
+            .iter()
+            .map(|p| p.distance2(center))
+            .fold(0.0, f32::max)
+            .sqrt();
+        BoundingSphere { center, radius }
+    }
+
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        BoundingSphere {
+            center: aabb.center(),
+            radius: aabb.extents().magnitude(),
+        }
+    }
+
+    /// Returns the sphere after it has been transformed by the given matrix. Non-uniform scaling
+    /// is handled conservatively by using the largest scale factor.
+    pub fn transform(&self, m: &Matrix4) -> Self {
+        let scale = m
+            .x
+            .truncate()
+            .magnitude()
+            .max(m.y.truncate().magnitude())
+            .max(m.z.truncate().magnitude());
+        BoundingSphere {
+            center: m.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        aabb.distance2(self.center) <= self.radius * self.radius
+    }
+}
+
+/// The six planes enclosing the volume visible through a view-projection matrix. Each plane is
+/// stored as (normal, distance) with the normal pointing into the frustum.
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    pub planes: [Vector4; 6],
+}
+
+impl Frustum {
+    /// Extracts the frustum planes from a view-projection matrix, using the method from "Fast
+    /// Extraction of Viewing Frustum Planes from the World-View-Projection Matrix" by Gribb and
+    /// Hartmann. Our projections map depth into wgpu's [0, 1] range, so the near plane is just the
+    /// third row rather than the sum of the third and fourth rows as in OpenGL.
+    pub fn from_matrix(m: &Matrix4) -> Self {
+        let row = |i: usize| Vector4::new(m.x[i], m.y[i], m.z[i], m.w[i]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+        let mut planes = [
+            r3 + r0, // left
+            r3 - r0, // right
+            r3 + r1, // bottom
+            r3 - r1, // top
+            r2,      // near
+            r3 - r2, // far
+        ];
+        for plane in planes.iter_mut() {
+            *plane /= plane.truncate().magnitude();
+        }
+
+        Frustum { planes }
+    }
+
+    fn distance(plane: &Vector4, p: Point3) -> f32 {
+        plane.x * p.x + plane.y * p.y + plane.z * p.z + plane.w
+    }
+
+    pub fn contains_point(&self, p: Point3) -> bool {
+        self.planes.iter().all(|plane| Self::distance(plane, p) >= 0.0)
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Self::distance(plane, sphere.center) >= -sphere.radius)
+    }
+
+    /// Conservative box test: may report boxes near the frustum corners as visible, but never
+    /// rejects a box which is (partially) inside.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // Test the corner furthest along the plane normal
+            let p = Point3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            Self::distance(plane, p) >= 0.0
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: Point3,
+    pub direction: Vector3,
+}
+
+impl Ray {
+    pub fn new(origin: Point3, direction: Vector3) -> Self {
+        Ray { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Point3 {
+        self.origin + self.direction * t
+    }
+
+    /// Transforms the ray by the given matrix. The direction is not renormalized, so hit
+    /// distances along the transformed ray are the same as along the original one.
+    pub fn transform(&self, m: &Matrix4) -> Self {
+        Ray {
+            origin: m.transform_point(self.origin),
+            direction: m.transform_vector(self.direction),
+        }
+    }
+
+    /// Slab test. Returns the distance along the ray to where it enters the box, or zero if the
+    /// origin is inside the box.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut t_min = 0.0f32;
+        let mut t_max = f32::INFINITY;
+        for axis in 0..3 {
+            let inv_d = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inv_d;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+
+    /// Möller–Trumbore ray/triangle intersection. Returns the distance along the ray and the
+    /// barycentric coordinates (u, v) of the hit, where the hit point is
+    /// `(1 - u - v) * a + u * b + v * c`. Both sides of the triangle are hit.
+    pub fn intersect_triangle(&self, a: Point3, b: Point3, c: Point3) -> Option<(f32, f32, f32)> {
+        const EPSILON: f32 = 1e-7;
+
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let det = edge1.dot(p);
+        if det.abs() < EPSILON {
+            return None; // ray is parallel to the triangle
+        }
+
+        let inv_det = 1.0 / det;
+        let s = self.origin - a;
+        let u = s.dot(p) * inv_det;
+        if u < 0.0 || u > 1.0 {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = self.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(q) * inv_det;
+        if t < 0.0 {
+            return None;
+        }
+
+        Some((t, u, v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn ray_hits_and_misses_aabb() {
+        let aabb = unit_box();
+
+        let hit = Ray::new(Point3::new(-2.0, 0.5, 0.5), Vector3::unit_x());
+        assert_eq!(hit.intersect_aabb(&aabb), Some(2.0));
+
+        let from_inside = Ray::new(Point3::new(0.5, 0.5, 0.5), Vector3::unit_y());
+        assert_eq!(from_inside.intersect_aabb(&aabb), Some(0.0));
+
+        let beside = Ray::new(Point3::new(-2.0, 1.5, 0.5), Vector3::unit_x());
+        assert_eq!(beside.intersect_aabb(&aabb), None);
+
+        let backwards = Ray::new(Point3::new(-2.0, 0.5, 0.5), -Vector3::unit_x());
+        assert_eq!(backwards.intersect_aabb(&aabb), None);
+
+        // Parallel to the x slabs, outside of them
+        let parallel = Ray::new(Point3::new(2.0, -1.0, 0.5), Vector3::unit_y());
+        assert_eq!(parallel.intersect_aabb(&aabb), None);
+    }
+
+    #[test]
+    fn ray_hits_and_misses_triangle() {
+        let (a, b, c) = (
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        );
+
+        let ray = Ray::new(Point3::new(0.25, 0.25, 1.0), -Vector3::unit_z());
+        let (t, u, v) = ray.intersect_triangle(a, b, c).unwrap();
+        assert!((t - 1.0).abs() < 1e-6);
+        assert!((u - 0.25).abs() < 1e-6 && (v - 0.25).abs() < 1e-6);
+
+        // Both sides are hit
+        let below = Ray::new(Point3::new(0.25, 0.25, -1.0), Vector3::unit_z());
+        assert!(below.intersect_triangle(a, b, c).is_some());
+
+        let outside = Ray::new(Point3::new(0.75, 0.75, 1.0), -Vector3::unit_z());
+        assert!(outside.intersect_triangle(a, b, c).is_none());
+
+        let parallel = Ray::new(Point3::new(-1.0, 0.25, 0.0), Vector3::unit_x());
+        assert!(parallel.intersect_triangle(a, b, c).is_none());
+    }
+
+    #[test]
+    fn frustum_inside_outside_and_straddling() {
+        // Looks down -z from the origin with a 90 degree field of view, from z = -1 to z = -10
+        let projection =
+            camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(Deg(90.0), 1.0, 1.0, 10.0);
+        let frustum = Frustum::from_matrix(&projection);
+
+        let at = |x: f32, z: f32| {
+            Aabb::new(
+                Point3::new(x - 0.5, -0.5, z - 0.5),
+                Point3::new(x + 0.5, 0.5, z + 0.5),
+            )
+        };
+        assert!(frustum.intersects_aabb(&at(0.0, -5.0)));
+        assert!(frustum.contains_point(Point3::new(0.0, 0.0, -5.0)));
+
+        // Beside, behind, in front of the near plane and past the far plane
+        assert!(!frustum.intersects_aabb(&at(8.0, -5.0)));
+        assert!(!frustum.intersects_aabb(&at(0.0, 5.0)));
+        assert!(!frustum.intersects_aabb(&at(0.0, -0.2)));
+        assert!(!frustum.intersects_aabb(&at(0.0, -11.0)));
+
+        // Across the side, the near and the far plane
+        assert!(frustum.intersects_aabb(&at(5.0, -5.0)));
+        assert!(frustum.intersects_aabb(&at(0.0, -1.0)));
+        assert!(frustum.intersects_aabb(&at(0.0, -10.0)));
+
+        let sphere = BoundingSphere::new(Point3::new(5.5, 0.0, -5.0), 1.0);
+        assert!(frustum.intersects_sphere(&sphere));
+        let sphere = BoundingSphere::new(Point3::new(8.0, 0.0, -5.0), 1.0);
+        assert!(!frustum.intersects_sphere(&sphere));
+    }
+}
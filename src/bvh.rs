@@ -0,0 +1,700 @@
+use crate::bounds::{Aabb, BoundingSphere, Frustum, Ray};
+use crate::model;
+use crate::prelude::*;
+use std::mem;
+use std::ops::Range;
+
+/// Maximum number of primitives stored in a leaf node
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+enum NodeKind {
+    /// Range into the primitive index list
+    Leaf { first: usize, count: usize },
+    Interior { left: usize, right: usize },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    aabb: Aabb,
+    kind: NodeKind,
+}
+
+/// Bounding volume hierarchy over a list of primitives, each represented by its bounding box.
+///
+/// The tree only stores primitive indices, so the same structure is used both for the triangles
+/// of a mesh and for the instances of a scene. Queries report the indices of candidate
+/// primitives, and callers do the exact tests themselves.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    primitives: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn build(aabbs: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(aabbs.len() * 2),
+            primitives: (0..aabbs.len()).collect(),
+        };
+        if !aabbs.is_empty() {
+            let centroids: Vec<Point3> = aabbs.iter().map(|aabb| aabb.center()).collect();
+            bvh.build_node(aabbs, &centroids, 0, aabbs.len());
+        }
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Bounds of everything in the tree
+    pub fn aabb(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |node| node.aabb)
+    }
+
+    fn build_node(&mut self, aabbs: &[Aabb], centroids: &[Point3], start: usize, end: usize) -> usize {
+        let aabb = self.primitives[start..end]
+            .iter()
+            .fold(Aabb::empty(), |acc, &p| acc.union(&aabbs[p]));
+
+        // Parents are always pushed before their children, which lets `refit` update the tree
+        // with a single reverse pass over the nodes
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            aabb,
+            kind: NodeKind::Leaf {
+                first: start,
+                count: end - start,
+            },
+        });
+
+        let count = end - start;
+        if count <= MAX_LEAF_SIZE {
+            return index;
+        }
+
+        // Split along the longest axis of the centroid bounds, at the median centroid
+        let centroid_bounds =
+            Aabb::from_points(self.primitives[start..end].iter().map(|&p| centroids[p]));
+        let size = centroid_bounds.max - centroid_bounds.min;
+        let axis = if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        };
+
+        // All centroids in the same place can't be split meaningfully
+        if size[axis] <= 0.0 {
+            return index;
+        }
+
+        self.primitives[start..end].sort_unstable_by(|&a, &b| {
+            centroids[a][axis]
+                .partial_cmp(&centroids[b][axis])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mid = start + count / 2;
+
+        let left = self.build_node(aabbs, centroids, start, mid);
+        let right = self.build_node(aabbs, centroids, mid, end);
+        self.nodes[index].kind = NodeKind::Interior { left, right };
+
+        index
+    }
+
+    /// Updates the node bounds after primitives have moved, keeping the tree topology. This is a
+    /// lot cheaper than rebuilding, but the tree gets less efficient the further primitives move
+    /// from where they were when it was built.
+    pub fn refit(&mut self, aabbs: &[Aabb]) {
+        for i in (0..self.nodes.len()).rev() {
+            self.nodes[i].aabb = match self.nodes[i].kind {
+                NodeKind::Leaf { first, count } => self.primitives[first..first + count]
+                    .iter()
+                    .fold(Aabb::empty(), |acc, &p| acc.union(&aabbs[p])),
+                NodeKind::Interior { left, right } => {
+                    self.nodes[left].aabb.union(&self.nodes[right].aabb)
+                }
+            };
+        }
+    }
+
+    /// Visits every primitive in leaves whose bounds pass the given node test
+    fn traverse<T: Fn(&Aabb) -> bool, F: FnMut(usize)>(&self, test: T, mut f: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if !test(&node.aabb) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { first, count } => {
+                    for &p in &self.primitives[first..first + count] {
+                        f(p);
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+    }
+
+    pub fn query_aabb<F: FnMut(usize)>(&self, aabb: &Aabb, f: F) {
+        self.traverse(|node| node.intersects(aabb), f);
+    }
+
+    pub fn query_sphere<F: FnMut(usize)>(&self, sphere: &BoundingSphere, f: F) {
+        self.traverse(|node| sphere.intersects_aabb(node), f);
+    }
+
+    pub fn query_frustum<F: FnMut(usize)>(&self, frustum: &Frustum, f: F) {
+        self.traverse(|node| frustum.intersects_aabb(node), f);
+    }
+
+    /// Finds the closest primitive hit by the ray. The `hit` callback does the exact test for a
+    /// primitive and returns the distance along the ray, if it was hit. Nodes are visited front
+    /// to back so that most of the tree can be skipped once a hit has been found.
+    pub fn cast_ray<F: FnMut(usize) -> Option<f32>>(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        mut hit: F,
+    ) -> Option<(usize, f32)> {
+        let mut closest: Option<(usize, f32)> = None;
+        let mut closest_t = max_distance;
+
+        let root_t = match self.nodes.first().and_then(|n| ray.intersect_aabb(&n.aabb)) {
+            Some(t) => t,
+            None => return None,
+        };
+
+        let mut stack = vec![(0, root_t)];
+        while let Some((i, t_enter)) = stack.pop() {
+            if t_enter > closest_t {
+                continue;
+            }
+            match self.nodes[i].kind {
+                NodeKind::Leaf { first, count } => {
+                    for &p in &self.primitives[first..first + count] {
+                        if let Some(t) = hit(p) {
+                            if t < closest_t {
+                                closest_t = t;
+                                closest = Some((p, t));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    let t_left = ray.intersect_aabb(&self.nodes[left].aabb);
+                    let t_right = ray.intersect_aabb(&self.nodes[right].aabb);
+
+                    // Push the furthest child first so that the nearest is visited first
+                    match (t_left, t_right) {
+                        (Some(tl), Some(tr)) if tl <= tr => {
+                            stack.push((right, tr));
+                            stack.push((left, tl));
+                        }
+                        (Some(tl), Some(tr)) => {
+                            stack.push((left, tl));
+                            stack.push((right, tr));
+                        }
+                        (Some(tl), None) => stack.push((left, tl)),
+                        (None, Some(tr)) => stack.push((right, tr)),
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    /// Finds the primitive closest to a point, within `max_distance`. The `distance` callback
+    /// returns the exact distance from the point to a primitive.
+    pub fn nearest<F: FnMut(usize) -> f32>(
+        &self,
+        point: Point3,
+        max_distance: f32,
+        mut distance: F,
+    ) -> Option<(usize, f32)> {
+        let mut closest: Option<(usize, f32)> = None;
+        let mut closest_d2 = max_distance * max_distance;
+
+        let mut stack = match self.nodes.first() {
+            Some(root) => vec![(0, root.aabb.distance2(point))],
+            None => return None,
+        };
+        while let Some((i, d2)) = stack.pop() {
+            if d2 > closest_d2 {
+                continue;
+            }
+            match self.nodes[i].kind {
+                NodeKind::Leaf { first, count } => {
+                    for &p in &self.primitives[first..first + count] {
+                        let d = distance(p);
+                        if d * d < closest_d2 {
+                            closest_d2 = d * d;
+                            closest = Some((p, d));
+                        }
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    let d_left = self.nodes[left].aabb.distance2(point);
+                    let d_right = self.nodes[right].aabb.distance2(point);
+                    if d_left <= d_right {
+                        stack.push((right, d_right));
+                        stack.push((left, d_left));
+                    } else {
+                        stack.push((left, d_left));
+                        stack.push((right, d_right));
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+/// An instance of a model in a `SceneBvh`. The model index refers to the slice of models the
+/// scene was built from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InstanceRef {
+    pub model: usize,
+    pub instance: usize,
+}
+
+/// A ray hit against the triangles of an instance
+#[derive(Copy, Clone, Debug)]
+pub struct SceneHit {
+    pub instance: InstanceRef,
+    pub mesh: usize,
+    pub triangle: usize,
+    /// Distance along the ray
+    pub distance: f32,
+    /// Barycentric coordinates of the hit within the triangle
+    pub barycentric: (f32, f32),
+}
+
+/// An instance that moved or changed how it casts shadows, as recorded by `SceneBvh::update_instance`
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceChange {
+    pub instance: InstanceRef,
+    pub mobility: model::Mobility,
+    /// Bounds before and after the move
+    pub old_aabb: Aabb,
+    pub new_aabb: Aabb,
+}
+
+struct SceneEntry {
+    instance: InstanceRef,
+    transform: Matrix4,
+    inverse: Matrix4,
+    aabb: Aabb,
+    mobility: model::Mobility,
+    casts_shadows: bool,
+}
+
+/// BVH over all instances of a set of models, in world space. Spatial queries first search the
+/// instances and, where needed, continue into the triangle BVHs of the meshes.
+pub struct SceneBvh {
+    entries: Vec<SceneEntry>,
+    aabbs: Vec<Aabb>,
+    /// Range of entries belonging to each model
+    model_entries: Vec<Range<usize>>,
+    bvh: Bvh,
+    needs_refit: bool,
+    /// Moves since the last `take_changes`
+    changes: Vec<InstanceChange>,
+}
+
+impl SceneBvh {
+    pub fn build(models: &[(&model::Model, &[model::Instance])]) -> Self {
+        let bounds: Vec<_> = models
+            .iter()
+            .map(|(model, instances)| (model.aabb, *instances))
+            .collect();
+        Self::build_from_bounds(&bounds)
+    }
+
+    /// Like `build`, with only the model space bounds of each model
+    fn build_from_bounds(models: &[(Aabb, &[model::Instance])]) -> Self {
+        let mut entries = Vec::new();
+        let mut model_entries = Vec::new();
+        for (model_index, (model_aabb, instances)) in models.iter().enumerate() {
+            let start = entries.len();
+            for (instance_index, instance) in instances.iter().enumerate() {
+                let transform = instance.to_raw().model;
+                entries.push(SceneEntry {
+                    instance: InstanceRef {
+                        model: model_index,
+                        instance: instance_index,
+                    },
+                    transform,
+                    inverse: transform.invert().unwrap_or_else(Matrix4::identity),
+                    aabb: model_aabb.transform(&transform),
+                    mobility: instance.mobility,
+                    casts_shadows: instance.casts_shadows,
+                });
+            }
+            model_entries.push(start..entries.len());
+        }
+
+        let aabbs: Vec<Aabb> = entries.iter().map(|e| e.aabb).collect();
+        let bvh = Bvh::build(&aabbs);
+        SceneBvh {
+            entries,
+            aabbs,
+            model_entries,
+            bvh,
+            needs_refit: false,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Records the new transform of an instance. The tree is refitted lazily by `update`, which
+    /// has to be called before the next query.
+    pub fn update_instance(
+        &mut self,
+        model_index: usize,
+        instance_index: usize,
+        model: &model::Model,
+        instance: &model::Instance,
+    ) {
+        self.move_instance(model_index, instance_index, &model.aabb, instance);
+    }
+
+    fn move_instance(
+        &mut self,
+        model_index: usize,
+        instance_index: usize,
+        model_aabb: &Aabb,
+        instance: &model::Instance,
+    ) {
+        let entry_index = self.model_entries[model_index].start + instance_index;
+        let entry = &mut self.entries[entry_index];
+        let old_aabb = entry.aabb;
+        entry.transform = instance.to_raw().model;
+        entry.inverse = entry.transform.invert().unwrap_or_else(Matrix4::identity);
+        entry.aabb = model_aabb.transform(&entry.transform);
+        entry.mobility = instance.mobility;
+        entry.casts_shadows = instance.casts_shadows;
+        self.aabbs[entry_index] = entry.aabb;
+        self.needs_refit = true;
+
+        self.changes.push(InstanceChange {
+            instance: entry.instance,
+            mobility: entry.mobility,
+            old_aabb,
+            new_aabb: entry.aabb,
+        });
+    }
+
+    /// Returns the instances that moved since the last call, e.g. to find the shadow maps they
+    /// invalidated
+    pub fn take_changes(&mut self) -> Vec<InstanceChange> {
+        mem::take(&mut self.changes)
+    }
+
+    /// Refits the tree if any instance has moved since the last update
+    pub fn update(&mut self) {
+        if self.needs_refit {
+            self.bvh.refit(&self.aabbs);
+            self.needs_refit = false;
+        }
+    }
+
+    /// Rebuilds the tree from scratch, restoring query performance after instances have moved
+    /// far from where they were when the tree was built
+    pub fn rebuild(&mut self) {
+        self.bvh = Bvh::build(&self.aabbs);
+        self.needs_refit = false;
+    }
+
+    pub fn instance_aabb(&self, instance: InstanceRef) -> Aabb {
+        self.entry(instance).aabb
+    }
+
+    pub fn instance_transform(&self, instance: InstanceRef) -> Matrix4 {
+        self.entry(instance).transform
+    }
+
+    pub fn instance_mobility(&self, instance: InstanceRef) -> model::Mobility {
+        self.entry(instance).mobility
+    }
+
+    pub fn instance_casts_shadows(&self, instance: InstanceRef) -> bool {
+        self.entry(instance).casts_shadows
+    }
+
+    /// Number of instances of a model
+    pub fn instance_count(&self, model_index: usize) -> usize {
+        self.model_entries[model_index].len()
+    }
+
+    /// Queries walk the boxes of the tree, which miss instances that moved out of them until
+    /// `update` refits it
+    fn assert_refitted(&self) {
+        debug_assert!(
+            !self.needs_refit,
+            "SceneBvh queried after update_instance without update"
+        );
+    }
+
+    fn entry(&self, instance: InstanceRef) -> &SceneEntry {
+        &self.entries[self.model_entries[instance.model].start + instance.instance]
+    }
+
+    pub fn query_frustum(&self, frustum: &Frustum) -> Vec<InstanceRef> {
+        self.assert_refitted();
+        let mut result = Vec::new();
+        self.bvh.query_frustum(frustum, |i| {
+            if frustum.intersects_aabb(&self.entries[i].aabb) {
+                result.push(self.entries[i].instance);
+            }
+        });
+        result
+    }
+
+    /// Returns the instances of a model inside the frustum, as ranges of consecutive instance
+    /// indices suitable for instanced draw calls.
+    pub fn visible_ranges(&self, model_index: usize, frustum: &Frustum) -> Vec<Range<u32>> {
+        self.visible_ranges_where(model_index, frustum, |_| true)
+    }
+
+    /// Like `visible_ranges`, but only including the instances the filter accepts
+    pub fn visible_ranges_where<F: Fn(InstanceRef) -> bool>(
+        &self,
+        model_index: usize,
+        frustum: &Frustum,
+        filter: F,
+    ) -> Vec<Range<u32>> {
+        let mut visible: Vec<u32> = self
+            .query_frustum(frustum)
+            .into_iter()
+            .filter(|i| i.model == model_index && filter(*i))
+            .map(|i| i.instance as u32)
+            .collect();
+        visible.sort_unstable();
+        merge_ranges(&visible)
+    }
+
+    /// Instances whose bounding boxes overlap the sphere
+    pub fn overlap_sphere(&self, sphere: &BoundingSphere) -> Vec<InstanceRef> {
+        self.assert_refitted();
+        let mut result = Vec::new();
+        self.bvh.query_sphere(sphere, |i| {
+            if sphere.intersects_aabb(&self.entries[i].aabb) {
+                result.push(self.entries[i].instance);
+            }
+        });
+        result
+    }
+
+    /// The instance whose bounding box is closest to the point
+    pub fn nearest(&self, point: Point3, max_distance: f32) -> Option<(InstanceRef, f32)> {
+        self.assert_refitted();
+        self.bvh
+            .nearest(point, max_distance, |i| {
+                self.entries[i].aabb.distance2(point).sqrt()
+            })
+            .map(|(i, d)| (self.entries[i].instance, d))
+    }
+
+    /// Finds the closest triangle hit by a world space ray. The models must be the same (and in
+    /// the same order) as the ones the scene was built from.
+    pub fn cast_ray(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        models: &[&model::Model],
+    ) -> Option<SceneHit> {
+        self.assert_refitted();
+        let mut closest: Option<SceneHit> = None;
+        self.bvh.cast_ray(ray, max_distance, |i| {
+            let entry = &self.entries[i];
+            let model = models[entry.instance.model];
+
+            // Test in model space, where the triangle BVHs live
+            let local_ray = ray.transform(&entry.inverse);
+            let mut best: Option<SceneHit> = None;
+            for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+                let max_t = best.map_or(max_distance, |hit| hit.distance);
+                if let Some(hit) = mesh.cast_ray(&local_ray, max_t) {
+                    best = Some(SceneHit {
+                        instance: entry.instance,
+                        mesh: mesh_index,
+                        triangle: hit.triangle,
+                        distance: hit.distance,
+                        barycentric: hit.barycentric,
+                    });
+                }
+            }
+
+            let hit = best?;
+            if closest.map_or(true, |c| hit.distance < c.distance) {
+                closest = Some(hit);
+            }
+            Some(hit.distance)
+        });
+        closest
+    }
+}
+
+/// Groups sorted indices into ranges of consecutive ones. Duplicates are skipped.
+fn merge_ranges(sorted: &[u32]) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    for &i in sorted {
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            Some(range) if range.end > i => {}
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera;
+
+    fn unit_box(x: f32) -> Aabb {
+        Aabb::new(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 1.0, 1.0))
+    }
+
+    /// Boxes at x = 0, 2, 4, ... along the x axis
+    fn row(count: usize) -> Vec<Aabb> {
+        (0..count).map(|i| unit_box(i as f32 * 2.0)).collect()
+    }
+
+    fn cast(bvh: &Bvh, aabbs: &[Aabb], ray: &Ray) -> Option<(usize, f32)> {
+        bvh.cast_ray(ray, f32::INFINITY, |i| ray.intersect_aabb(&aabbs[i]))
+    }
+
+    #[test]
+    fn cast_ray_hits_the_closest_primitive() {
+        let aabbs = row(20);
+        let bvh = Bvh::build(&aabbs);
+
+        let ray = Ray::new(Point3::new(-5.0, 0.5, 0.5), Vector3::unit_x());
+        let (hit, t) = cast(&bvh, &aabbs, &ray).unwrap();
+        assert_eq!(hit, 0);
+        assert!((t - 5.0).abs() < 1e-5);
+
+        let ray = Ray::new(Point3::new(100.0, 0.5, 0.5), -Vector3::unit_x());
+        assert_eq!(cast(&bvh, &aabbs, &ray).map(|(i, _)| i), Some(19));
+    }
+
+    #[test]
+    fn cast_ray_misses() {
+        let aabbs = row(20);
+        let bvh = Bvh::build(&aabbs);
+
+        // Passes above the row, and points away from it
+        let above = Ray::new(Point3::new(-5.0, 2.0, 0.5), Vector3::unit_x());
+        assert!(cast(&bvh, &aabbs, &above).is_none());
+        let away = Ray::new(Point3::new(-5.0, 0.5, 0.5), -Vector3::unit_x());
+        assert!(cast(&bvh, &aabbs, &away).is_none());
+
+        // Hits past the maximum distance don't count
+        let ray = Ray::new(Point3::new(-5.0, 0.5, 0.5), Vector3::unit_x());
+        assert!(bvh
+            .cast_ray(&ray, 4.0, |i| ray.intersect_aabb(&aabbs[i]))
+            .is_none());
+
+        assert!(cast(&Bvh::build(&[]), &[], &ray).is_none());
+    }
+
+    #[test]
+    fn query_frustum_skips_boxes_outside() {
+        // Looks down -z from the origin, seeing x and y in [-1, 1] between z = -1 and z = -10
+        let projection =
+            camera::OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-1.0, 1.0, -1.0, 1.0, 1.0, 10.0);
+        let frustum = Frustum::from_matrix(&projection);
+
+        let inside = Aabb::new(Point3::new(-0.5, -0.5, -5.0), Point3::new(0.5, 0.5, -4.0));
+        let outside = Aabb::new(Point3::new(2.0, -0.5, -5.0), Point3::new(3.0, 0.5, -4.0));
+        let behind = Aabb::new(Point3::new(-0.5, -0.5, 1.0), Point3::new(0.5, 0.5, 2.0));
+        let straddling = Aabb::new(Point3::new(0.5, -0.5, -5.0), Point3::new(1.5, 0.5, -4.0));
+        let aabbs = [inside, outside, behind, straddling];
+        let bvh = Bvh::build(&aabbs);
+        let mut found = Vec::new();
+        bvh.query_frustum(&frustum, |i| {
+            if frustum.intersects_aabb(&aabbs[i]) {
+                found.push(i);
+            }
+        });
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 3]);
+    }
+
+    fn instance_at(x: f32) -> model::Instance {
+        model::Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            rotation: cgmath::Quaternion::from_axis_angle(Vector3::unit_y(), Deg(0.0)),
+            mobility: model::Mobility::Dynamic,
+            casts_shadows: true,
+            receives_shadows: true,
+        }
+    }
+
+    #[test]
+    fn moved_instances_are_found_after_update() {
+        // A row of unit boxes, the first of which moves far along the row
+        let model_aabb = unit_box(0.0);
+        let instances: Vec<_> = (0..20).map(|i| instance_at(i as f32 * 2.0)).collect();
+        let mut scene = SceneBvh::build_from_bounds(&[(model_aabb, &instances)]);
+
+        // Sees x in [99, 101]
+        let projection =
+            camera::OPENGL_TO_WGPU_MATRIX * cgmath::ortho(99.0, 101.0, -1.0, 2.0, -1.0, 1.0);
+        let frustum = Frustum::from_matrix(&projection);
+        assert!(scene.visible_ranges(0, &frustum).is_empty());
+
+        scene.move_instance(0, 0, &model_aabb, &instance_at(100.0));
+        scene.update();
+        assert_eq!(scene.visible_ranges(0, &frustum), vec![0..1]);
+        let nearest = scene.nearest(Point3::new(100.5, 0.5, 0.5), 1.0);
+        assert_eq!(nearest.map(|(i, _)| i.instance), Some(0));
+
+        // Where it was, only its neighbour is left
+        let nearest = scene.nearest(Point3::new(0.5, 0.5, 0.5), 2.0);
+        assert_eq!(nearest.map(|(i, _)| i.instance), Some(1));
+
+        let changes = scene.take_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].old_aabb, model_aabb);
+        assert!(scene.take_changes().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn queries_before_update_panic() {
+        let model_aabb = unit_box(0.0);
+        let instances = [instance_at(0.0)];
+        let mut scene = SceneBvh::build_from_bounds(&[(model_aabb, &instances)]);
+        scene.move_instance(0, 0, &model_aabb, &instance_at(100.0));
+        scene.query_frustum(&Frustum::from_matrix(&Matrix4::identity()));
+    }
+
+    #[test]
+    fn merge_ranges_groups_consecutive_indices() {
+        assert_eq!(merge_ranges(&[]), vec![]);
+        assert_eq!(merge_ranges(&[3]), vec![3..4]);
+        assert_eq!(merge_ranges(&[0, 1, 2, 5, 6, 9]), vec![0..3, 5..7, 9..10]);
+        assert_eq!(merge_ranges(&[0, 0, 1, 1, 3]), vec![0..2, 3..4]);
+        assert_eq!(
+            merge_ranges(&[u32::MAX - 1]),
+            vec![(u32::MAX - 1)..u32::MAX]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bounds::{Frustum, Ray};
 use crate::prelude::*;
 use std::f32::consts::FRAC_PI_2;
 use std::time::Duration;
@@ -40,6 +41,117 @@ impl Camera {
     }
 }
 
+pub trait Projection {
+    fn calc_matrix(&self) -> Matrix4;
+
+    /// The volume visible through this projection when looking through the given camera
+    fn frustum(&self, camera: &Camera) -> Frustum {
+        Frustum::from_matrix(&(self.calc_matrix() * camera.calc_matrix()))
+    }
+}
+
+/// The camera the scene is rendered through, with its projection and the size of the surface in
+/// physical pixels
+#[derive(Copy, Clone)]
+pub struct View<'a> {
+    pub camera: &'a Camera,
+    pub projection: &'a PerspectiveProjection,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl<'a> View<'a> {
+    pub fn screen_space(&self) -> ScreenSpace {
+        ScreenSpace::new(self.camera, self.projection, self.width, self.height)
+    }
+}
+
+/// Converts between window, normalized device and world coordinates for a camera looking through
+/// a projection onto a surface of a given size.
+///
+/// Window coordinates are in physical pixels with the origin in the top left corner, as reported
+/// by winit. Depth follows wgpu's convention of 0 at the near plane and 1 at the far plane. The
+/// matrices are captured when this is created, so it has to be recreated whenever the camera moves
+/// or the window is resized (after the projection itself has been resized).
+#[derive(Copy, Clone, Debug)]
+pub struct ScreenSpace {
+    pub view_proj: Matrix4,
+    pub inv_view_proj: Matrix4,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenSpace {
+    /// The window size is clamped to at least one pixel, so that a minimized window doesn't
+    /// divide by zero
+    pub fn new(camera: &Camera, projection: &impl Projection, width: u32, height: u32) -> Self {
+        let view_proj = projection.calc_matrix() * camera.calc_matrix();
+        Self {
+            view_proj,
+            inv_view_proj: view_proj.invert().unwrap_or_else(Matrix4::identity),
+            width: width.max(1),
+            height: height.max(1),
+        }
+    }
+
+    /// Window coordinates to normalized device coordinates, where y points up
+    pub fn window_to_ndc(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            2.0 * x / self.width as f32 - 1.0,
+            1.0 - 2.0 * y / self.height as f32,
+        )
+    }
+
+    /// Normalized device coordinates to window coordinates
+    pub fn ndc_to_window(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            (x + 1.0) * 0.5 * self.width as f32,
+            (1.0 - y) * 0.5 * self.height as f32,
+        )
+    }
+
+    /// Returns the world space point at the given window position and depth buffer value
+    pub fn unproject(&self, x: f32, y: f32, depth: f32) -> Point3 {
+        let (ndc_x, ndc_y) = self.window_to_ndc(x, y);
+        Point3::from_homogeneous(self.inv_view_proj * Vector4::new(ndc_x, ndc_y, depth, 1.0))
+    }
+
+    /// Projects a world space point into the window. Returns the window position in x and y and
+    /// the depth buffer value in z, or `None` if the point is behind the camera.
+    pub fn project(&self, point: Point3) -> Option<Point3> {
+        let clip = self.view_proj * point.to_homogeneous();
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let ndc = clip.truncate() / clip.w;
+        let (x, y) = self.ndc_to_window(ndc.x, ndc.y);
+        Some(Point3::new(x, y, ndc.z))
+    }
+
+    /// Whether the point projects inside the window and between the near and far planes
+    pub fn is_on_screen(&self, point: Point3) -> bool {
+        match self.project(point) {
+            Some(p) => {
+                p.x >= 0.0
+                    && p.x <= self.width as f32
+                    && p.y >= 0.0
+                    && p.y <= self.height as f32
+                    && p.z >= 0.0
+                    && p.z <= 1.0
+            }
+            None => false,
+        }
+    }
+
+    /// A world space ray starting at the near plane and going through the given window position.
+    /// Works for both perspective and orthographic projections.
+    pub fn view_ray(&self, x: f32, y: f32) -> Ray {
+        let near = self.unproject(x, y, 0.0);
+        let far = self.unproject(x, y, 1.0);
+        Ray::new(near, (far - near).normalize())
+    }
+}
+
 pub struct PerspectiveProjection {
     aspect: f32,
     fovy: Rad<f32>,
@@ -50,18 +162,39 @@ pub struct PerspectiveProjection {
 impl PerspectiveProjection {
     pub fn new<F: Into<Rad<f32>>>(width: u32, height: u32, fovy: F, znear: f32, zfar: f32) -> Self {
         Self {
-            aspect: width as f32 / height as f32,
+            aspect: aspect_ratio(width, height),
             fovy: fovy.into(),
             znear,
             zfar,
         }
     }
 
+    /// Keeps the last aspect ratio while the window is minimized to zero pixels
     pub fn resize(&mut self, width: u32, height: u32) {
-        self.aspect = width as f32 / height as f32;
+        if width > 0 && height > 0 {
+            self.aspect = aspect_ratio(width, height);
+        }
     }
 
-    pub fn calc_matrix(&self) -> Matrix4 {
+    pub fn fovy(&self) -> Rad<f32> {
+        self.fovy
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
+    }
+}
+
+fn aspect_ratio(width: u32, height: u32) -> f32 {
+    width.max(1) as f32 / height.max(1) as f32
+}
+
+impl Projection for PerspectiveProjection {
+    fn calc_matrix(&self) -> Matrix4 {
         OPENGL_TO_WGPU_MATRIX * cgmath::perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
 }
@@ -86,8 +219,10 @@ impl OrthographicProjection {
             zfar,
         }
     }
+}
 
-    pub fn calc_matrix(&self) -> Matrix4 {
+impl Projection for OrthographicProjection {
+    fn calc_matrix(&self) -> Matrix4 {
         OPENGL_TO_WGPU_MATRIX
             * cgmath::ortho(
                 self.left,
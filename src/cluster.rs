@@ -0,0 +1,242 @@
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::camera;
+use crate::camera::Projection;
+use crate::prelude::*;
+use std::mem;
+
+/// Number of clusters along the x and y axis of the screen, and the number of depth slices
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+
+/// Number of light indices the index buffer has room for before it needs to grow
+const INITIAL_INDEX_CAPACITY: usize = 4096;
+
+/// Lights assigned to clusters of the view frustum, so that the forward pass only has to evaluate
+/// the lights which can reach a fragment.
+///
+/// The frustum is split into a grid of tiles in screen space and into depth slices which grow
+/// exponentially with the distance from the camera. Each light is assigned to every cluster its
+/// sphere of influence intersects. The lookup happens in `shader.frag`.
+pub struct Clusters {
+    /// Shows the number of lights per cluster instead of the lit scene
+    pub debug_heatmap: bool,
+
+    pub uniforms_buffer: wgpu::Buffer,
+    /// Offset into the light index buffer and light count for each cluster
+    pub ranges_buffer: wgpu::Buffer,
+    pub indices_buffer: wgpu::Buffer,
+
+    /// Number of light indices that fit in `indices_buffer`
+    index_capacity: usize,
+
+    /// View space bounds of each cluster, along with the projection they were computed for
+    bounds: Vec<Aabb>,
+    bounds_projection: Option<Matrix4>,
+
+    /// Scratch space for the light lists of each cluster, kept around to avoid reallocating
+    cluster_lights: Vec<Vec<u32>>,
+}
+
+impl Clusters {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster uniforms"),
+            size: mem::size_of::<ClusterUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let ranges_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster ranges"),
+            size: (cluster_count() * mem::size_of::<[u32; 2]>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let indices_buffer = create_indices_buffer(device, INITIAL_INDEX_CAPACITY);
+
+        Self {
+            debug_heatmap: false,
+            uniforms_buffer,
+            ranges_buffer,
+            indices_buffer,
+            index_capacity: INITIAL_INDEX_CAPACITY,
+            bounds: Vec::new(),
+            bounds_projection: None,
+            cluster_lights: vec![Vec::new(); cluster_count()],
+        }
+    }
+
+    /// Assigns the lights (as world space spheres of influence, along with their index in the
+    /// light buffer) to clusters and uploads the result. Returns true if the index buffer had to
+    /// grow, in which case bind groups referring to it must be recreated.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        lights: &[(u32, BoundingSphere)],
+        view: &camera::View,
+    ) -> bool {
+        let projection = view.projection;
+        let proj = projection.calc_matrix();
+        if self.bounds_projection != Some(proj) {
+            self.bounds = cluster_bounds(&proj, projection.znear(), projection.zfar());
+            self.bounds_projection = Some(proj);
+        }
+
+        for cluster in self.cluster_lights.iter_mut() {
+            cluster.clear();
+        }
+
+        let view_matrix = view.camera.calc_matrix();
+        let [_, _, slices] = CLUSTER_GRID;
+        for (light_index, light) in lights {
+            let sphere = light.transform(&view_matrix);
+
+            // Only visit the slices the light can reach. The camera looks down negative z.
+            let depth = -sphere.center.z;
+            let first = slice_index(depth - sphere.radius, projection);
+            let last = slice_index(depth + sphere.radius, projection);
+            if depth + sphere.radius < projection.znear() || first >= slices {
+                continue;
+            }
+
+            for z in first..=last.min(slices - 1) {
+                for cluster in slice_clusters(z) {
+                    if sphere.intersects_aabb(&self.bounds[cluster]) {
+                        self.cluster_lights[cluster].push(*light_index);
+                    }
+                }
+            }
+        }
+
+        // Flatten the light lists into one index buffer
+        let mut ranges = Vec::with_capacity(cluster_count());
+        let mut indices = Vec::new();
+        for cluster in &self.cluster_lights {
+            ranges.push([indices.len() as u32, cluster.len() as u32]);
+            indices.extend_from_slice(cluster);
+        }
+
+        let mut reallocated = false;
+        if indices.len() > self.index_capacity {
+            self.index_capacity = indices.len().next_power_of_two();
+            self.indices_buffer = create_indices_buffer(device, self.index_capacity);
+            reallocated = true;
+        }
+
+        let uniforms = ClusterUniforms {
+            grid: [
+                CLUSTER_GRID[0],
+                CLUSTER_GRID[1],
+                CLUSTER_GRID[2],
+                self.debug_heatmap as u32,
+            ],
+            screen_size: [view.width as f32, view.height as f32],
+            z_near: projection.znear(),
+            z_far: projection.zfar(),
+        };
+        queue.write_buffer(&self.uniforms_buffer, 0, bytemuck::bytes_of(&uniforms));
+        queue.write_buffer(&self.ranges_buffer, 0, bytemuck::cast_slice(&ranges));
+        if !indices.is_empty() {
+            queue.write_buffer(&self.indices_buffer, 0, bytemuck::cast_slice(&indices));
+        }
+
+        reallocated
+    }
+
+    pub fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+        wgpu::BufferSize::new(mem::size_of::<ClusterUniforms>() as _)
+    }
+}
+
+pub fn cluster_count() -> usize {
+    (CLUSTER_GRID[0] * CLUSTER_GRID[1] * CLUSTER_GRID[2]) as usize
+}
+
+fn cluster_index(x: u32, y: u32, z: u32) -> usize {
+    let [gx, gy, _] = CLUSTER_GRID;
+    (x + gx * (y + gy * z)) as usize
+}
+
+fn slice_clusters(z: u32) -> impl Iterator<Item = usize> {
+    let [gx, gy, _] = CLUSTER_GRID;
+    (0..gy).flat_map(move |y| (0..gx).map(move |x| cluster_index(x, y, z)))
+}
+
+/// Depth (distance along the view direction) where the given slice starts. Slices are spaced
+/// exponentially, so that clusters stay roughly cube shaped with distance.
+fn slice_depth(z: u32, znear: f32, zfar: f32) -> f32 {
+    znear * (zfar / znear).powf(z as f32 / CLUSTER_GRID[2] as f32)
+}
+
+/// The slice containing the given depth, using the same mapping as `shader.frag`
+fn slice_index(depth: f32, projection: &camera::PerspectiveProjection) -> u32 {
+    let (znear, zfar) = (projection.znear(), projection.zfar());
+    if depth <= znear {
+        return 0;
+    }
+    let slice = (depth / znear).ln() / (zfar / znear).ln() * CLUSTER_GRID[2] as f32;
+    slice.floor() as u32
+}
+
+/// Computes the view space bounding box of every cluster
+fn cluster_bounds(proj: &Matrix4, znear: f32, zfar: f32) -> Vec<Aabb> {
+    let [gx, gy, gz] = CLUSTER_GRID;
+    let inv_proj = proj.invert().unwrap_or_else(Matrix4::identity);
+
+    // View space direction through a point on the screen, scaled to a depth of 1
+    let direction = |ndc_x: f32, ndc_y: f32| {
+        let p = Point3::from_homogeneous(inv_proj * Vector4::new(ndc_x, ndc_y, 1.0, 1.0));
+        p.to_vec() / -p.z
+    };
+
+    let mut bounds = vec![Aabb::empty(); cluster_count()];
+    for y in 0..gy {
+        for x in 0..gx {
+            // Tiles are numbered from the top left corner, like window coordinates
+            let x0 = -1.0 + 2.0 * x as f32 / gx as f32;
+            let x1 = -1.0 + 2.0 * (x + 1) as f32 / gx as f32;
+            let y0 = 1.0 - 2.0 * y as f32 / gy as f32;
+            let y1 = 1.0 - 2.0 * (y + 1) as f32 / gy as f32;
+            let corners = [
+                direction(x0, y0),
+                direction(x1, y0),
+                direction(x0, y1),
+                direction(x1, y1),
+            ];
+
+            for z in 0..gz {
+                let near = slice_depth(z, znear, zfar);
+                let far = slice_depth(z + 1, znear, zfar);
+                bounds[cluster_index(x, y, z)] = Aabb::from_points(
+                    corners
+                        .iter()
+                        .flat_map(|d| vec![Point3::from_vec(d * near), Point3::from_vec(d * far)]),
+                );
+            }
+        }
+    }
+    bounds
+}
+
+fn create_indices_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Cluster light indices"),
+        size: (capacity * mem::size_of::<u32>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ClusterUniforms {
+    /// Cluster counts along each axis, with the heatmap flag in the last component
+    grid: [u32; 4],
+    screen_size: [f32; 2],
+    z_near: f32,
+    z_far: f32,
+}
+
+unsafe impl bytemuck::Zeroable for ClusterUniforms {}
+unsafe impl bytemuck::Pod for ClusterUniforms {}
@@ -0,0 +1,708 @@
+use crate::{compile_frag, compile_vertex};
+use anyhow::Context;
+use std::fs::File;
+use std::io::BufReader;
+use std::mem;
+use std::num::NonZeroU32;
+use std::path::Path;
+use wgpu::util::DeviceExt;
+
+/// Format of the environment cubemaps, which hold values beyond 1.0
+pub const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Format of panoramas loaded by `load_equirect`
+pub const EQUIRECT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+/// Width and height of each face of the environment cubemap
+const ENVIRONMENT_SIZE: u32 = 512;
+
+/// Width and height of each face of the irradiance cubemap. Irradiance changes slowly with the
+/// direction, so it can be tiny.
+const IRRADIANCE_SIZE: u32 = 32;
+
+/// Width and height of each face of the specular cubemap at roughness 0
+const SPECULAR_SIZE: u32 = 128;
+
+/// Number of roughness levels in the specular cubemap, one per mip from 0 to 1
+const SPECULAR_MIPS: u32 = 5;
+
+/// Width and height of the BRDF lookup table
+const BRDF_LUT_SIZE: u32 = 256;
+
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+/// A cubemap with a view of all faces and mips, for sampling
+pub struct Cubemap {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    /// Width and height of each face at mip 0
+    pub size: u32,
+    pub mip_level_count: u32,
+}
+
+impl Cubemap {
+    pub fn new(device: &wgpu::Device, size: u32, mip_level_count: u32, label: &str) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth: 6,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ENVIRONMENT_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::COPY_DST,
+            label: Some(label),
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(label),
+            format: None,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            level_count: None,
+            base_array_layer: 0,
+            array_layer_count: NonZeroU32::new(6),
+        });
+
+        Self {
+            texture,
+            view,
+            size,
+            mip_level_count,
+        }
+    }
+
+    /// Another view like `view`, for bind groups that outlive this cubemap
+    pub fn create_view(&self) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cubemap"),
+            format: None,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            level_count: None,
+            base_array_layer: 0,
+            array_layer_count: NonZeroU32::new(6),
+        })
+    }
+
+    /// 2D view of a single face and mip, for rendering into
+    pub fn face_view(&self, face: u32, mip: u32) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cubemap face"),
+            format: None,
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: mip,
+            level_count: NonZeroU32::new(1),
+            base_array_layer: face,
+            array_layer_count: NonZeroU32::new(1),
+        })
+    }
+
+    /// Cube view of a single mip, for sampling while rendering into the next one
+    fn mip_view(&self, mip: u32) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cubemap mip"),
+            format: None,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: mip,
+            level_count: NonZeroU32::new(1),
+            base_array_layer: 0,
+            array_layer_count: NonZeroU32::new(6),
+        })
+    }
+}
+
+/// Image based lighting from the surroundings, precomputed from an environment map with the split
+/// sum approximation: diffuse light is looked up in `irradiance` by the normal, and specular light
+/// in `specular` by the reflection vector, scaled by `brdf_lut`. `shader.frag` adds both to the
+/// ambient light.
+pub struct Environment {
+    /// The environment itself, with a full mip chain
+    pub cubemap: Cubemap,
+    /// Cosine weighted light from the hemisphere around each direction
+    pub irradiance: Cubemap,
+    /// The environment convolved with the GGX distribution, with the roughness going from 0 at
+    /// mip 0 to 1 at the last mip
+    pub specular: Cubemap,
+    /// Scale (r) and bias (g) to the Fresnel reflectance at normal incidence, by the cosine of the
+    /// view angle (u) and the roughness (v)
+    pub brdf_lut: wgpu::Texture,
+    pub brdf_lut_view: wgpu::TextureView,
+    /// Trilinear, for all of the above
+    pub sampler: wgpu::Sampler,
+}
+
+impl Environment {
+    /// Loads an equirectangular HDR panorama (see `load_equirect`) and precomputes the lighting
+    /// from it on the GPU
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        compiler: &mut shaderc::Compiler,
+        path: P,
+    ) -> Result<Self, anyhow::Error> {
+        let equirect = load_equirect(device, queue, path)?;
+        Ok(Self::from_equirect(device, queue, compiler, &equirect))
+    }
+
+    /// Precomputes the lighting from a panorama loaded by `load_equirect`, e.g. one that's also
+    /// drawn as the background
+    pub fn from_equirect(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        compiler: &mut shaderc::Compiler,
+        equirect: &wgpu::Texture,
+    ) -> Self {
+        let baker = EnvironmentBaker::new(device, compiler);
+        let environment = Self::allocate(device, ENVIRONMENT_SIZE, IRRADIANCE_SIZE, SPECULAR_SIZE);
+        let equirect_view = equirect.create_view(&wgpu::TextureViewDescriptor::default());
+        queue.submit(std::iter::once(baker.bake(
+            device,
+            &environment,
+            &equirect_view,
+        )));
+
+        environment
+    }
+
+    /// An environment without any light, for when there's nothing better
+    pub fn empty(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let environment = Self::allocate(device, 1, 1, 1);
+
+        // Textures aren't cleared when they are created
+        let cubemaps = [
+            &environment.cubemap,
+            &environment.irradiance,
+            &environment.specular,
+        ];
+        for cubemap in cubemaps.iter() {
+            for mip in 0..cubemap.mip_level_count {
+                queue.write_texture(
+                    wgpu::TextureCopyView {
+                        texture: &cubemap.texture,
+                        mip_level: mip,
+                        origin: wgpu::Origin3d::ZERO,
+                    },
+                    &[0; 6 * 8],
+                    wgpu::TextureDataLayout {
+                        offset: 0,
+                        bytes_per_row: 8,
+                        rows_per_image: 1,
+                    },
+                    wgpu::Extent3d {
+                        width: 1,
+                        height: 1,
+                        depth: 6,
+                    },
+                );
+            }
+        }
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &environment.brdf_lut,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &[0; 4],
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4,
+                rows_per_image: 1,
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+        );
+
+        environment
+    }
+
+    fn allocate(
+        device: &wgpu::Device,
+        environment_size: u32,
+        irradiance_size: u32,
+        specular_size: u32,
+    ) -> Self {
+        let cubemap = Cubemap::new(
+            device,
+            environment_size,
+            mip_level_count(environment_size),
+            "Environment",
+        );
+        let irradiance = Cubemap::new(device, irradiance_size, 1, "Irradiance");
+        let specular = Cubemap::new(
+            device,
+            specular_size,
+            SPECULAR_MIPS.min(mip_level_count(specular_size)),
+            "Specular",
+        );
+
+        let brdf_lut_size = if environment_size > 1 {
+            BRDF_LUT_SIZE
+        } else {
+            1
+        };
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: brdf_lut_size,
+                height: brdf_lut_size,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::COPY_DST,
+            label: Some("BRDF lookup table"),
+        });
+        let brdf_lut_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            compare: None,
+            ..Default::default()
+        });
+
+        Self {
+            cubemap,
+            irradiance,
+            specular,
+            brdf_lut,
+            brdf_lut_view,
+            sampler,
+        }
+    }
+}
+
+/// Loads an equirectangular HDR panorama (in the Radiance `.hdr` format) into an `Rgba32Float`
+/// texture. Such textures can't be filtered, so they have to be sampled with `Nearest` filtering
+/// (or `texelFetch`).
+pub fn load_equirect<P: AsRef<Path>>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    path: P,
+) -> Result<wgpu::Texture, anyhow::Error> {
+    let path = path.as_ref();
+    let file =
+        File::open(path).with_context(|| format!("environment path: {}", path.to_string_lossy()))?;
+    let decoder = image::hdr::HdrDecoder::new(BufReader::new(file))?;
+    let metadata = decoder.metadata();
+    let pixels: Vec<[f32; 4]> = decoder
+        .read_image_hdr()?
+        .into_iter()
+        .map(|pixel| [pixel[0], pixel[1], pixel[2], 1.0])
+        .collect();
+
+    let size = wgpu::Extent3d {
+        width: metadata.width,
+        height: metadata.height,
+        depth: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: EQUIRECT_FORMAT,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        label: path.to_str(),
+    });
+    queue.write_texture(
+        wgpu::TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        bytemuck::cast_slice(&pixels),
+        wgpu::TextureDataLayout {
+            offset: 0,
+            bytes_per_row: 16 * metadata.width,
+            rows_per_image: metadata.height,
+        },
+        size,
+    );
+
+    Ok(texture)
+}
+
+fn mip_level_count(size: u32) -> u32 {
+    32 - size.leading_zeros()
+}
+
+/// Renders the precomputed lighting of an `Environment`, one fullscreen triangle per cube face and
+/// mip
+struct EnvironmentBaker {
+    equirect_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    irradiance_pipeline: wgpu::RenderPipeline,
+    specular_pipeline: wgpu::RenderPipeline,
+    brdf_pipeline: wgpu::RenderPipeline,
+    uniforms_bind_group_layout: wgpu::BindGroupLayout,
+    equirect_bind_group_layout: wgpu::BindGroupLayout,
+    cube_bind_group_layout: wgpu::BindGroupLayout,
+    equirect_sampler: wgpu::Sampler,
+    cube_sampler: wgpu::Sampler,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BakeUniforms {
+    /// Cube face being rendered, in the order of cube samplers (+x, -x, +y, -y, +z, -z)
+    face: u32,
+    /// Roughness of the specular mip being rendered
+    roughness: f32,
+    /// Face size of the environment cubemap at mip 0
+    source_size: f32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for BakeUniforms {}
+unsafe impl bytemuck::Zeroable for BakeUniforms {}
+
+/// A face of a cubemap to render, along with the uniforms to do it with
+struct BakeDraw {
+    view: wgpu::TextureView,
+    uniforms: BakeUniforms,
+}
+
+impl EnvironmentBaker {
+    fn new(device: &wgpu::Device, compiler: &mut shaderc::Compiler) -> Self {
+        let uniforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: true,
+                        min_binding_size: uniforms_binding_size(),
+                    },
+                    count: None,
+                }],
+                label: Some("Environment uniforms bind group layout"),
+            });
+        let equirect_bind_group_layout =
+            create_source_layout(device, wgpu::TextureViewDimension::D2);
+        let cube_bind_group_layout = create_source_layout(device, wgpu::TextureViewDimension::Cube);
+
+        // The equirectangular image is Rgba32Float, which can't be filtered
+        let equirect_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Equirectangular"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let cube_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment bake"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let equirect_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Environment equirect"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout, &equirect_bind_group_layout],
+        });
+        let cube_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Environment cube"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout, &cube_bind_group_layout],
+        });
+        let brdf_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("BRDF lookup table"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout],
+        });
+
+        let vs_module = compile_vertex!(device, compiler, "fullscreen.vert").unwrap();
+        let equirect_module = compile_frag!(device, compiler, "ibl_equirect.frag").unwrap();
+        let downsample_module = compile_frag!(device, compiler, "ibl_downsample.frag").unwrap();
+        let irradiance_module = compile_frag!(device, compiler, "ibl_irradiance.frag").unwrap();
+        let specular_module = compile_frag!(device, compiler, "ibl_specular.frag").unwrap();
+        let brdf_module = compile_frag!(device, compiler, "ibl_brdf.frag").unwrap();
+
+        let pipeline =
+            |layout, module, format| create_pipeline(device, layout, &vs_module, module, format);
+        Self {
+            equirect_pipeline: pipeline(&equirect_layout, &equirect_module, ENVIRONMENT_FORMAT),
+            downsample_pipeline: pipeline(&cube_layout, &downsample_module, ENVIRONMENT_FORMAT),
+            irradiance_pipeline: pipeline(&cube_layout, &irradiance_module, ENVIRONMENT_FORMAT),
+            specular_pipeline: pipeline(&cube_layout, &specular_module, ENVIRONMENT_FORMAT),
+            brdf_pipeline: pipeline(&brdf_layout, &brdf_module, BRDF_LUT_FORMAT),
+            uniforms_bind_group_layout,
+            equirect_bind_group_layout,
+            cube_bind_group_layout,
+            equirect_sampler,
+            cube_sampler,
+        }
+    }
+
+    /// Renders the cubemap from the equirectangular image, then its mips, and everything derived
+    /// from it
+    fn bake(
+        &self,
+        device: &wgpu::Device,
+        environment: &Environment,
+        equirect: &wgpu::TextureView,
+    ) -> wgpu::CommandBuffer {
+        let source_size = environment.cubemap.size as f32;
+        let faces = |cubemap: &Cubemap, mip: u32, roughness: f32| -> Vec<BakeDraw> {
+            (0..6)
+                .map(|face| BakeDraw {
+                    view: cubemap.face_view(face, mip),
+                    uniforms: BakeUniforms {
+                        face,
+                        roughness,
+                        source_size,
+                        _padding: 0,
+                    },
+                })
+                .collect()
+        };
+
+        let equirect_source = self.create_source_bind_group(
+            device,
+            &self.equirect_bind_group_layout,
+            equirect,
+            &self.equirect_sampler,
+        );
+        let mip_sources: Vec<wgpu::BindGroup> = (1..environment.cubemap.mip_level_count)
+            .map(|mip| {
+                let view = environment.cubemap.mip_view(mip - 1);
+                self.create_source_bind_group(
+                    device,
+                    &self.cube_bind_group_layout,
+                    &view,
+                    &self.cube_sampler,
+                )
+            })
+            .collect();
+        let cubemap_source = self.create_source_bind_group(
+            device,
+            &self.cube_bind_group_layout,
+            &environment.cubemap.view,
+            &self.cube_sampler,
+        );
+
+        // Each stage samples the ones before it, so they are drawn in order
+        let mut stages: Vec<(
+            &wgpu::RenderPipeline,
+            Option<&wgpu::BindGroup>,
+            Vec<BakeDraw>,
+        )> = vec![(
+            &self.equirect_pipeline,
+            Some(&equirect_source),
+            faces(&environment.cubemap, 0, 0.0),
+        )];
+        for (mip, source) in (1..).zip(mip_sources.iter()) {
+            stages.push((
+                &self.downsample_pipeline,
+                Some(source),
+                faces(&environment.cubemap, mip, 0.0),
+            ));
+        }
+        stages.push((
+            &self.irradiance_pipeline,
+            Some(&cubemap_source),
+            faces(&environment.irradiance, 0, 0.0),
+        ));
+        let specular_mips = environment.specular.mip_level_count;
+        for mip in 0..specular_mips {
+            let roughness = mip as f32 / (specular_mips - 1).max(1) as f32;
+            stages.push((
+                &self.specular_pipeline,
+                Some(&cubemap_source),
+                faces(&environment.specular, mip, roughness),
+            ));
+        }
+        stages.push((
+            &self.brdf_pipeline,
+            None,
+            vec![BakeDraw {
+                view: environment
+                    .brdf_lut
+                    .create_view(&wgpu::TextureViewDescriptor::default()),
+                uniforms: bytemuck::Zeroable::zeroed(),
+            }],
+        ));
+
+        let draw_count = stages.iter().map(|(_, _, draws)| draws.len()).sum();
+        let mut uniforms = vec![0u8; draw_offset(draw_count)];
+        for (i, draw) in stages.iter().flat_map(|(_, _, draws)| draws).enumerate() {
+            let offset = draw_offset(i);
+            uniforms[offset..offset + mem::size_of::<BakeUniforms>()]
+                .copy_from_slice(bytemuck::bytes_of(&draw.uniforms));
+        }
+        let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment uniforms"),
+            contents: &uniforms,
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        let uniforms_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.uniforms_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniforms_buffer,
+                    offset: 0,
+                    size: uniforms_binding_size(),
+                },
+            }],
+            label: Some("Environment uniforms bind group"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment bake"),
+        });
+        let mut draw_index = 0;
+        for (pipeline, source, draws) in stages.iter() {
+            for draw in draws {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &draw.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                });
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(
+                    0,
+                    &uniforms_bind_group,
+                    &[draw_offset(draw_index) as wgpu::DynamicOffset],
+                );
+                if let Some(source) = source {
+                    render_pass.set_bind_group(1, source, &[]);
+                }
+                render_pass.draw(0..3, 0..1);
+                draw_index += 1;
+            }
+        }
+
+        encoder.finish()
+    }
+
+    fn create_source_bind_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        view: &wgpu::TextureView,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("Environment source"),
+        })
+    }
+}
+
+fn create_source_layout(
+    device: &wgpu::Device,
+    dimension: wgpu::TextureViewDimension,
+) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::SampledTexture {
+                    multisampled: false,
+                    dimension,
+                    component_type: wgpu::TextureComponentType::Float,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler { comparison: false },
+                count: None,
+            },
+        ],
+        label: Some("Environment source bind group layout"),
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Environment bake"),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::None,
+            ..Default::default()
+        }),
+        color_states: &[wgpu::ColorStateDescriptor {
+            format,
+            color_blend: wgpu::BlendDescriptor::REPLACE,
+            alpha_blend: wgpu::BlendDescriptor::REPLACE,
+            write_mask: wgpu::ColorWrite::ALL,
+        }],
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        depth_stencil_state: None,
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: wgpu::IndexFormat::Uint32,
+            vertex_buffers: &[],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    })
+}
+
+fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+    wgpu::BufferSize::new(mem::size_of::<BakeUniforms>() as _)
+}
+
+fn draw_offset(draw_index: usize) -> usize {
+    draw_index * wgpu::BIND_BUFFER_ALIGNMENT as usize
+}
@@ -1,8 +1,10 @@
 use crate::camera;
+use crate::camera::Projection;
 use crate::geometry::Vertex;
 use crate::model;
 use crate::pipeline;
 use crate::prelude::*;
+use crate::ssao;
 use crate::texture;
 use crate::Context;
 use crate::{compile_frag, compile_vertex};
@@ -16,11 +18,47 @@ pub struct ForwardPass {
     pub uniform_bind_group: wgpu::BindGroup,
 
     pub depth_texture: texture::Texture,
+    /// The lit scene, in linear color that can go past 1.0. `tonemap::TonemapPass` maps it to
+    /// the swap chain.
+    pub hdr_texture: texture::Texture,
+    /// World space normals written by the prepass, when asked for
+    pub normal_texture: texture::Texture,
+    /// Ambient occlusion of each pixel, written by `ssao::SsaoPass` between the prepass and the
+    /// forward pass
+    pub occlusion_texture: texture::Texture,
+    /// Color of whatever no geometry or background covers
+    pub clear_color: wgpu::Color,
+    /// Samples per pixel of the forward pass, 1 to disable multisampling. Changed with
+    /// `set_sample_count`.
+    pub sample_count: u32,
+    /// Whether the depth prepass is asked for, e.g. by the ambient occlusion. Changed with
+    /// `set_prepass`.
+    pub prepass: bool,
+    /// Targets the forward pass renders into when multisampling, resolved into `hdr_texture`
+    multisampled: Option<MultisampledTargets>,
     pub pipeline: wgpu::RenderPipeline,
     pub billboard_pipeline: wgpu::RenderPipeline,
+    /// Draw the same models as `pipeline`, into the depth texture and optionally the normals
+    pub depth_prepass_pipeline: wgpu::RenderPipeline,
+    pub normal_prepass_pipeline: wgpu::RenderPipeline,
+    /// Kept to rebuild `pipeline` when the sample count changes
+    pipeline_layout: wgpu::PipelineLayout,
+    vs_module: wgpu::ShaderModule,
+    fs_module: wgpu::ShaderModule,
+}
+
+/// The multisampled color and depth of the forward pass. The depth of the prepass can't be
+/// reused, so the forward pass tests against its own.
+struct MultisampledTargets {
+    color: texture::Texture,
+    depth: texture::Texture,
 }
 
 impl ForwardPass {
+    /// Sample counts the forward pass can render with. Only these are guaranteed for the HDR
+    /// color and depth formats, and wgpu has no way to ask the device for others.
+    pub const SAMPLE_COUNTS: [u32; 2] = [1, 4];
+
     pub fn new(context: &mut Context) -> Self {
         let uniforms = Uniforms::new();
         let uniform_buffer = context
@@ -35,40 +73,65 @@ impl ForwardPass {
             context
                 .device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::UniformBuffer {
-                            dynamic: false,
-                            min_binding_size: wgpu::BufferSize::new(
-                                std::mem::size_of::<Uniforms>() as _,
-                            ),
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::UniformBuffer {
+                                dynamic: false,
+                                min_binding_size: wgpu::BufferSize::new(
+                                    std::mem::size_of::<Uniforms>() as _,
+                                ),
+                            },
+                            count: None,
                         },
-                        count: None,
-                    }],
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::SampledTexture {
+                                multisampled: false,
+                                dimension: wgpu::TextureViewDimension::D2,
+                                component_type: wgpu::TextureComponentType::Float,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler { comparison: false },
+                            count: None,
+                        },
+                    ],
                     label: Some("uniform_bind_group_layout"),
                 });
 
-        let uniform_bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer {
-                    buffer: &uniform_buffer,
-                    offset: 0,
-                    size: None,
-                },
-            }],
-            label: Some("uniform_bind_group"),
-        });
-
         let depth_texture = texture::Texture::create_depth_texture(
             &context.device,
             &context.sc_desc,
+            1,
             "depth_texture",
         );
+        let hdr_texture =
+            texture::Texture::create_hdr_texture(&context.device, &context.sc_desc, "hdr_texture");
+        let normal_texture = texture::Texture::create_hdr_texture(
+            &context.device,
+            &context.sc_desc,
+            "normal_texture",
+        );
+        let occlusion_texture = ssao::create_occlusion_texture(
+            &context.device,
+            &context.sc_desc,
+            "occlusion_texture",
+        );
+
+        let uniform_bind_group = create_uniform_bind_group(
+            &context.device,
+            &uniform_bind_group_layout,
+            &uniform_buffer,
+            &occlusion_texture,
+        );
 
-        let pipeline = {
+        let (pipeline_layout, vs_module, fs_module) = {
             let layout = context
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -79,6 +142,7 @@ impl ForwardPass {
                         &uniform_bind_group_layout,
                         &context.instances_bind_group_layout,
                         &context.light_bind_group_layout,
+                        &context.morph_bind_group_layout,
                     ],
                 });
 
@@ -86,24 +150,62 @@ impl ForwardPass {
                 compile_vertex!(&context.device, &mut context.shader_compiler, "shader.vert")
                     .unwrap();
             let fs_module =
-                compile_frag!(&context.device, &mut context.shader_compiler, "shader.frag").unwrap();
+                compile_frag!(&context.device, &mut context.shader_compiler, "shader.frag")
+                    .unwrap();
 
-            pipeline::create(
-                &"forward",
+            (layout, vs_module, fs_module)
+        };
+
+        let (pipeline, depth_prepass_pipeline, normal_prepass_pipeline) = {
+            let depth_module = compile_frag!(
                 &context.device,
-                &layout,
+                &mut context.shader_compiler,
+                "prepass.frag"
+            )
+            .unwrap();
+            let normals_module = compile_frag!(
+                &context.device,
+                &mut context.shader_compiler,
+                "prepass_normals.frag"
+            )
+            .unwrap();
+
+            let forward = create_pipeline(
+                &context.device,
+                &pipeline_layout,
                 &vs_module,
                 &fs_module,
-                Some(context.sc_desc.format),
+                1,
+                true,
+            );
+            // Always single sampled, for the ambient occlusion and the post effects to read
+            let depth_prepass = pipeline::create(
+                "depth prepass",
+                &context.device,
+                &pipeline_layout,
+                &vs_module,
+                &depth_module,
+                None,
                 Some(pipeline::DepthConfig::no_bias()),
+                1,
                 &[model::ModelVertex::desc()],
-            )
+            );
+            let normal_prepass = pipeline::create(
+                "normal prepass",
+                &context.device,
+                &pipeline_layout,
+                &vs_module,
+                &normals_module,
+                Some(texture::Texture::HDR_FORMAT),
+                Some(pipeline::DepthConfig::no_bias()),
+                1,
+                &[model::ModelVertex::desc()],
+            );
+            (forward, depth_prepass, normal_prepass)
         };
 
-        let billboard_pipeline = crate::billboard::create_pipeline(
-            context,
-            &uniform_bind_group_layout,
-        );
+        let billboard_pipeline =
+            crate::billboard::create_pipeline(context, &uniform_bind_group_layout, 1);
 
         ForwardPass {
             uniform_bind_group_layout,
@@ -113,11 +215,80 @@ impl ForwardPass {
             uniform_bind_group,
 
             depth_texture,
+            hdr_texture,
+            normal_texture,
+            occlusion_texture,
+            clear_color: wgpu::Color {
+                r: 0.1,
+                g: 0.2,
+                b: 0.3,
+                a: 1.0,
+            },
+            sample_count: 1,
+            prepass: true,
+            multisampled: None,
             pipeline,
             billboard_pipeline,
+            depth_prepass_pipeline,
+            normal_prepass_pipeline,
+            pipeline_layout,
+            vs_module,
+            fs_module,
         }
     }
 
+    /// Renders the forward pass with `sample_count` samples per pixel, one of `SAMPLE_COUNTS`,
+    /// rebuilding its pipelines and targets. Pipelines drawing within the forward pass elsewhere,
+    /// like `background::BackgroundPass`, have to be rebuilt as well.
+    pub fn set_sample_count(&mut self, context: &mut Context, sample_count: u32) {
+        assert!(
+            Self::SAMPLE_COUNTS.contains(&sample_count),
+            "unsupported sample count {}",
+            sample_count
+        );
+        self.sample_count = sample_count;
+        self.multisampled =
+            create_multisampled_targets(&context.device, &context.sc_desc, sample_count);
+        self.pipeline = create_pipeline(
+            &context.device,
+            &self.pipeline_layout,
+            &self.vs_module,
+            &self.fs_module,
+            sample_count,
+            self.prepass,
+        );
+        self.billboard_pipeline = crate::billboard::create_pipeline(
+            context,
+            &self.uniform_bind_group_layout,
+            sample_count,
+        );
+    }
+
+    /// Asks for the depth prepass or not, rebuilding the forward pipeline if that changes whether
+    /// it reuses the depth of the prepass
+    pub fn set_prepass(&mut self, device: &wgpu::Device, prepass: bool) {
+        if prepass == self.prepass {
+            return;
+        }
+        self.prepass = prepass;
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.vs_module,
+            &self.fs_module,
+            self.sample_count,
+            prepass,
+        );
+    }
+
+    /// Whether the prepass has to be drawn (see `begin_prepass`). When multisampling it always is,
+    /// at the cost of drawing the scene twice, since it's the only single sampled depth for the
+    /// post effects. Otherwise the forward pass writes that depth itself if the prepass isn't
+    /// asked for.
+    pub fn runs_prepass(&self) -> bool {
+        self.prepass || self.sample_count > 1
+    }
+
     pub fn upload_uniforms(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
         let staging_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Staging"),
@@ -136,34 +307,98 @@ impl ForwardPass {
 
     pub fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
         self.depth_texture =
-            texture::Texture::create_depth_texture(device, sc_desc, "depth_texture");
+            texture::Texture::create_depth_texture(device, sc_desc, 1, "depth_texture");
+        self.hdr_texture = texture::Texture::create_hdr_texture(device, sc_desc, "hdr_texture");
+        self.normal_texture =
+            texture::Texture::create_hdr_texture(device, sc_desc, "normal_texture");
+        self.occlusion_texture =
+            ssao::create_occlusion_texture(device, sc_desc, "occlusion_texture");
+        self.uniform_bind_group = create_uniform_bind_group(
+            device,
+            &self.uniform_bind_group_layout,
+            &self.uniform_buffer,
+            &self.occlusion_texture,
+        );
+        self.multisampled = create_multisampled_targets(device, sc_desc, self.sample_count);
     }
 
-    pub fn begin<'a>(
+    /// Starts rendering the depth of the scene, and its normals into `normal_texture` if asked
+    /// to, with the pipeline set. The models drawn in the forward pass have to be drawn here as
+    /// well, since it only draws what's at the depth written here.
+    pub fn begin_prepass<'a>(
         &'a self,
-        output: &'a wgpu::TextureView,
         encoder: &'a mut wgpu::CommandEncoder,
-    ) -> wgpu::RenderPass {
-        let back_color = wgpu::Color {
-            r: 0.1,
-            g: 0.2,
-            b: 0.3,
-            a: 1.0,
+        normals: bool,
+    ) -> wgpu::RenderPass<'a> {
+        let normal_attachment = wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: &self.normal_texture.view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: true,
+            },
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: if normals {
+                std::slice::from_ref(&normal_attachment)
+            } else {
+                &[]
+            },
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(if normals {
+            &self.normal_prepass_pipeline
+        } else {
+            &self.depth_prepass_pipeline
+        });
+
+        render_pass
+    }
+
+    /// Starts rendering into `hdr_texture`, over the depth of the prepass if there was one. When
+    /// multisampling, it renders into its own targets instead, with a fresh depth, and resolves
+    /// the color into `hdr_texture` at the end.
+    pub fn begin<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder) -> wgpu::RenderPass {
+        let prepass_depth = if self.prepass {
+            wgpu::LoadOp::Load
+        } else {
+            wgpu::LoadOp::Clear(1.0)
+        };
+        let (attachment, resolve_target, depth, depth_load) = match &self.multisampled {
+            Some(targets) => (
+                &targets.color.view,
+                Some(&self.hdr_texture.view),
+                &targets.depth.view,
+                wgpu::LoadOp::Clear(1.0),
+            ),
+            None => (
+                &self.hdr_texture.view,
+                None,
+                &self.depth_texture.view,
+                prepass_depth,
+            ),
         };
         let render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             // where we're going to draw our color to
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: output,
-                resolve_target: None,
+                attachment,
+                resolve_target,
                 ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(back_color),
+                    load: wgpu::LoadOp::Clear(self.clear_color),
                     store: true,
                 },
             }],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                attachment: &self.depth_texture.view,
+                attachment: depth,
                 depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
+                    load: depth_load,
                     store: true,
                 }),
                 stencil_ops: None,
@@ -174,6 +409,88 @@ impl ForwardPass {
     }
 }
 
+/// The pipeline of the forward pass proper. Single sampled with a prepass, it only tests against
+/// the depth the prepass wrote. Otherwise it tests and writes the depth as usual.
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    sample_count: u32,
+    prepass: bool,
+) -> wgpu::RenderPipeline {
+    let depth_config = if sample_count == 1 && prepass {
+        pipeline::DepthConfig::no_bias().read_only()
+    } else {
+        pipeline::DepthConfig::no_bias()
+    };
+    pipeline::create(
+        "forward",
+        device,
+        layout,
+        vs_module,
+        fs_module,
+        Some(texture::Texture::HDR_FORMAT),
+        Some(depth_config),
+        sample_count,
+        &[model::ModelVertex::desc()],
+    )
+}
+
+fn create_multisampled_targets(
+    device: &wgpu::Device,
+    sc_desc: &wgpu::SwapChainDescriptor,
+    sample_count: u32,
+) -> Option<MultisampledTargets> {
+    if sample_count <= 1 {
+        return None;
+    }
+    Some(MultisampledTargets {
+        color: texture::Texture::create_multisampled_hdr_texture(
+            device,
+            sc_desc,
+            sample_count,
+            "multisampled_hdr_texture",
+        ),
+        depth: texture::Texture::create_depth_texture(
+            device,
+            sc_desc,
+            sample_count,
+            "multisampled_depth_texture",
+        ),
+    })
+}
+
+fn create_uniform_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform_buffer: &wgpu::Buffer,
+    occlusion_texture: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: None,
+                },
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(&occlusion_texture.sampler),
+            },
+        ],
+        label: Some("uniform_bind_group"),
+    })
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct Uniforms {
@@ -1,35 +1,114 @@
+use crate::bounds::BoundingSphere;
+use crate::camera;
+use crate::cluster;
+use crate::environment::Environment;
 use crate::model;
 use crate::prelude::*;
 use crate::shadow;
+use std::mem;
 use wgpu::util::DeviceExt;
 
-pub type LightId = usize;
+/// Handle to a light in `Lights`. Slots of removed lights are reused, but handles to the removed
+/// light stay invalid since the slot's generation changes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LightId {
+    index: u32,
+    generation: u32,
+}
+
+impl LightId {
+    /// Index of the light's slot, which is reused by lights added after it's removed
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
 
-/// The maximum number of lights supported at once
-pub const MAX_LIGHTS: usize = 2;
+/// Number of lights the GPU buffer and shadow maps have room for before they need to grow
+const INITIAL_CAPACITY: usize = 4;
+
+/// Default brightness of point and spot lights at a distance of 1
+pub const DEFAULT_INTENSITY: f32 = 100.0;
+
+/// Default distance at which point and spot lights fade out completely
+pub const DEFAULT_RANGE: f32 = 45.0;
+
+/// Default ambient light contributed by each light, as a fraction of its color
+pub const DEFAULT_AMBIENT: f32 = 0.1;
 
 pub struct Lights {
-    pub lights: [Option<Light>; MAX_LIGHTS],
+    /// Light slots, indexed by `LightId`. Removed lights leave an empty slot behind.
+    slots: Vec<LightSlot>,
 
-    /// Cubemap textures for each light in the world
-    pub shadow_textures: [wgpu::Texture; MAX_LIGHTS],
+    /// Shadow maps of all lights: the cube faces of point lights, the cascades of directional
+    /// lights and the single shadow map of spot lights
+    pub shadow_atlas: shadow::ShadowAtlas,
 
     /// Material used to render light billboards
     pub material: model::MaterialId,
 
+    /// Ambient light on top of what each light contributes, such as the light of the sky (see
+    /// `sky::Sky::apply`)
+    pub ambient: Vector3,
+
     pub config: LightConfig,
+    /// Blurred depth moments of the shadow maps, laid out like `shadow_atlas`. Only allocated while
+    /// `config.shadow_filter` is a variance filter.
+    pub shadow_moments: shadow::ShadowAtlas,
+    /// Shadow settings of lights that don't override them
+    pub shadow_settings: shadow::ShadowSettings,
+    pub clusters: cluster::Clusters,
     pub buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+
+    /// Image based lighting added to the ambient light of every light, see `set_environment`
+    environment: Environment,
+
+    /// Number of lights that fit in `buffer`
+    buffer_capacity: usize,
+    shadow_sampler: wgpu::Sampler,
+    shadow_compare_sampler: wgpu::Sampler,
+
+    /// The lights in the order they are stored on the GPU
+    gpu_lights: Vec<GpuLight>,
+    /// Number of shadow layers in use, summed over all lights
+    layer_count: usize,
+    /// Light space projection and atlas region of each shadow layer
+    layers_buffer: wgpu::Buffer,
+    /// Number of shadow layers that fit in `layers_buffer`
+    layers_capacity: usize,
+}
+
+#[derive(Default)]
+struct LightSlot {
+    /// Incremented every time a light is added to the slot
+    generation: u32,
+    light: Option<Light>,
+}
+
+/// Where a light is stored on the GPU, as assigned by `Lights::upload`. Lights are packed tightly
+/// every upload, so removing a light moves the lights after it (and their shadow maps).
+struct GpuLight {
+    light: LightId,
+    /// First of the light's consecutive shadow layers
+    first_layer: usize,
+    /// Region of the atlas of each shadow layer: six cube faces for point lights, one per cascade
+    /// for directional lights and one for spot lights. Empty if the light didn't fit the atlas.
+    rects: Vec<shadow::AtlasRect>,
+    /// Light space projection of each shadow layer
+    view_proj: Vec<Matrix4>,
+    /// View depth where each cascade ends, for directional lights
+    splits: [f32; shadow::CASCADE_COUNT],
 }
 
 impl Lights {
     pub fn new(
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         light_bind_group_layout: &wgpu::BindGroupLayout,
         material: model::MaterialId,
     ) -> Self {
         let config = LightConfig::new(device);
-        let lights = [None; MAX_LIGHTS];
+        let clusters = cluster::Clusters::new(device);
 
         let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("shadow"),
@@ -42,121 +121,798 @@ impl Lights {
             compare: None,
             ..Default::default()
         });
+        let shadow_compare_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow compare"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
 
-        let (shadow_textures, shadow_texture_views) = {
-            let shadow_cubemap1 = shadow::ShadowCubemap::new(device);
-            let shadow_cubemap2 = shadow::ShadowCubemap::new(device);
+        let shadow_settings = shadow::ShadowSettings::default();
+        let shadow_atlas = shadow::ShadowAtlas::new(
+            device,
+            shadow_settings.rounded_atlas_size(),
+            shadow_settings.format,
+        );
+        let shadow_moments = shadow::ShadowAtlas::empty(device);
+        // Room for the six faces of each light
+        let layers_capacity = 6 * INITIAL_CAPACITY;
+        let layers_buffer = Self::create_layers_buffer(device, layers_capacity);
+        let buffer = Self::create_buffer(device, INITIAL_CAPACITY);
+        let environment = Environment::empty(device, queue);
+        let bind_group = Self::create_bind_group(
+            device,
+            light_bind_group_layout,
+            &buffer,
+            &shadow_atlas,
+            &shadow_sampler,
+            &config,
+            &clusters,
+            &layers_buffer,
+            &shadow_moments,
+            &shadow_compare_sampler,
+            &environment,
+        );
 
-            (
-                [shadow_cubemap1.texture, shadow_cubemap2.texture],
-                [shadow_cubemap1.texture_view, shadow_cubemap2.texture_view],
-            )
+        Self {
+            slots: Vec::new(),
+            shadow_atlas,
+            shadow_moments,
+            material,
+            ambient: Vector3::zero(),
+            config,
+            shadow_settings,
+            clusters,
+            buffer,
+            bind_group,
+            environment,
+            buffer_capacity: INITIAL_CAPACITY,
+            shadow_sampler,
+            shadow_compare_sampler,
+            gpu_lights: Vec::new(),
+            layer_count: 0,
+            layers_buffer,
+            layers_capacity,
+        }
+    }
+
+    /// Adds a light to the world, replacing its `id` with the handle it's stored under
+    pub fn add_light(&mut self, mut light: Light) -> LightId {
+        let index = match self.slots.iter().position(|slot| slot.light.is_none()) {
+            Some(i) => i,
+            None => {
+                self.slots.push(LightSlot::default());
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.generation += 1;
+        light.id = LightId {
+            index: index as u32,
+            generation: slot.generation,
         };
+        slot.light = Some(light);
+        light.id
+    }
 
-        let buffer_data = Self::map_raw(&lights);
+    /// Removes a light from the world, returning it if the handle was still valid. The remaining
+    /// lights are repacked by the next `upload`.
+    pub fn remove_light(&mut self, id: LightId) -> Option<Light> {
+        self.slot_mut(id).and_then(|slot| slot.light.take())
+    }
 
-        // We'll want to update our lights position, so we use COPY_DST
-        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    pub fn get(&self, id: LightId) -> Option<&Light> {
+        self.slots
+            .get(id.index())
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.light.as_ref())
+    }
+
+    /// Changes to the light are picked up by the next `upload`
+    pub fn get_mut(&mut self, id: LightId) -> Option<&mut Light> {
+        self.slot_mut(id).and_then(|slot| slot.light.as_mut())
+    }
+
+    fn slot_mut(&mut self, id: LightId) -> Option<&mut LightSlot> {
+        self.slots
+            .get_mut(id.index())
+            .filter(|slot| slot.generation == id.generation)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Light> {
+        self.slots.iter().filter_map(|slot| slot.light.as_ref())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Light> {
+        self.slots.iter_mut().filter_map(|slot| slot.light.as_mut())
+    }
+
+    /// Number of lights in the world
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The shadow settings a light uses: its own if it overrides them, the global ones otherwise
+    pub fn light_shadow_settings(&self, light: &Light) -> shadow::ShadowSettings {
+        match light.shadow_settings {
+            Some(settings) => shadow::ShadowSettings {
+                atlas_size: self.shadow_settings.atlas_size,
+                format: self.shadow_settings.format,
+                ..settings
+            },
+            None => self.shadow_settings,
+        }
+    }
+
+    fn gpu_light(&self, id: LightId) -> Option<&GpuLight> {
+        self.gpu_lights.iter().find(|gpu_light| gpu_light.light == id)
+    }
+
+    /// Index of the light in the light buffer, as assigned by the last `upload`
+    pub fn gpu_index(&self, id: LightId) -> Option<usize> {
+        self.gpu_lights
+            .iter()
+            .position(|gpu_light| gpu_light.light == id)
+    }
+
+    /// Number of lights stored on the GPU by the last `upload`
+    pub fn gpu_count(&self) -> usize {
+        self.gpu_lights.len()
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Lights the world with the given environment from now on, replacing the previous one
+    pub fn set_environment(
+        &mut self,
+        device: &wgpu::Device,
+        light_bind_group_layout: &wgpu::BindGroupLayout,
+        environment: Environment,
+    ) {
+        self.environment = environment;
+        self.bind_group = Self::create_bind_group(
+            device,
+            light_bind_group_layout,
+            &self.buffer,
+            &self.shadow_atlas,
+            &self.shadow_sampler,
+            &self.config,
+            &self.clusters,
+            &self.layers_buffer,
+            &self.shadow_moments,
+            &self.shadow_compare_sampler,
+            &self.environment,
+        );
+    }
+
+    /// Number of shadow layers in use as of the last `upload`
+    pub fn shadow_layer_count(&self) -> usize {
+        self.layer_count
+    }
+
+    /// The shadow layers a light renders its shadows into, as assigned by the last `upload`: the
+    /// first layer along with the light space projection of each layer. Point lights use one
+    /// layer per cube face and directional lights one per cascade. `None` if the light has no
+    /// room in the atlas.
+    pub fn shadow_layers(&self, id: LightId) -> Option<(usize, &[Matrix4])> {
+        self.gpu_light(id)
+            .filter(|gpu_light| !gpu_light.rects.is_empty())
+            .map(|gpu_light| (gpu_light.first_layer, gpu_light.view_proj.as_slice()))
+    }
+
+    /// The shadow maps a light renders its shadows into, in the order of its shadow layers
+    pub fn shadow_targets(&self, id: LightId) -> Vec<shadow::ShadowTarget> {
+        match self.gpu_light(id) {
+            Some(gpu_light) => gpu_light
+                .rects
+                .iter()
+                .enumerate()
+                .map(|(i, rect)| shadow::ShadowTarget {
+                    layer: gpu_light.first_layer + i,
+                    rect: *rect,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Packs the lights and writes them to the GPU, packs their shadow maps into the atlas, fits
+    /// the cascades of directional lights to the view and assigns the other lights to the clusters
+    /// of the view frustum. The light buffer is reallocated (and the bind group recreated) if
+    /// lights have been added beyond its capacity.
+    ///
+    /// Shadow maps get a resolution by how much of the view the light covers (see
+    /// `shadow::light_importance`), and are packed anew every upload, so a light may end up with a
+    /// region that held the shadows of another light. The shadow pass clears every region it
+    /// renders, so nothing of the other light's shadows is left once it has run.
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        light_bind_group_layout: &wgpu::BindGroupLayout,
+        view: &camera::View,
+    ) {
+        let (camera, projection) = (view.camera, view.projection);
+        let requests: Vec<shadow::AtlasRequest> = self
+            .packed()
+            .map(|light| {
+                let settings = self.light_shadow_settings(light);
+                let importance = shadow::light_importance(light, camera, projection);
+                let count = match light.light_type {
+                    LightType::Directional => shadow::CASCADE_COUNT,
+                    LightType::Spot { .. } => 1,
+                    LightType::Point => 6,
+                };
+                shadow::AtlasRequest {
+                    count,
+                    resolution: shadow::importance_resolution(settings.resolution, importance),
+                    importance,
+                }
+            })
+            .collect();
+        let atlas_size = self.shadow_settings.rounded_atlas_size();
+        let rects = shadow::pack_atlas(atlas_size, &requests);
+
+        // Directional lights come first, matching the order of the light buffer
+        let mut gpu_lights = Vec::new();
+        let mut layer_count = 0;
+        for (light, rects) in self.packed().zip(rects) {
+            let settings = self.light_shadow_settings(light);
+            let resolution = rects.first().map_or(0, |rect| rect.size);
+            let (view_proj, splits) = match light.light_type {
+                _ if rects.is_empty() => (Vec::new(), [0.0; shadow::CASCADE_COUNT]),
+                LightType::Directional => {
+                    let cascades = shadow::calculate_cascades(
+                        light.direction,
+                        camera,
+                        projection,
+                        resolution,
+                    );
+                    (cascades.view_proj.to_vec(), cascades.splits)
+                }
+                LightType::Spot {
+                    inner_angle,
+                    outer_angle,
+                } => {
+                    let (_, outer_angle) = clamp_spot_angles(inner_angle, outer_angle);
+                    let view_proj = shadow::create_spot_view_proj(
+                        light.position,
+                        light.direction,
+                        outer_angle,
+                        &settings,
+                    );
+                    (vec![view_proj], [0.0; shadow::CASCADE_COUNT])
+                }
+                LightType::Point => (
+                    shadow::create_light_proj_cube(Point3::from_vec(light.position), &settings),
+                    [0.0; shadow::CASCADE_COUNT],
+                ),
+            };
+
+            let first_layer = layer_count;
+            layer_count += rects.len();
+            gpu_lights.push(GpuLight {
+                light: light.id,
+                first_layer,
+                rects,
+                view_proj,
+                splits,
+            });
+        }
+        self.gpu_lights = gpu_lights;
+        self.layer_count = layer_count;
+
+        let mut recreate_bind_group = false;
+
+        if self.gpu_lights.len() > self.buffer_capacity {
+            self.buffer_capacity = self.gpu_lights.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.buffer_capacity);
+            recreate_bind_group = true;
+        }
+
+        if layer_count > self.layers_capacity {
+            self.layers_capacity = layer_count.next_power_of_two();
+            self.layers_buffer = Self::create_layers_buffer(device, self.layers_capacity);
+            recreate_bind_group = true;
+        }
+
+        let format = self.shadow_settings.format;
+        if atlas_size != self.shadow_atlas.size || format != self.shadow_atlas.format {
+            self.shadow_atlas = shadow::ShadowAtlas::new(device, atlas_size, format);
+            recreate_bind_group = true;
+        }
+
+        // The moments mirror the atlas, but are only needed by the variance filters
+        let uses_moments = self.config.shadow_filter.moments_blur_radius().is_some();
+        let moments_outdated = if uses_moments {
+            self.shadow_moments.size != atlas_size
+        } else {
+            !self.shadow_moments.is_empty()
+        };
+        if moments_outdated {
+            self.shadow_moments = if uses_moments {
+                shadow::ShadowAtlas::new(device, atlas_size, shadow::MOMENTS_FORMAT)
+            } else {
+                shadow::ShadowAtlas::empty(device)
+            };
+            recreate_bind_group = true;
+        }
+
+        let (header, lights) = self.to_raw();
+
+        // Directional lights reach everywhere, so only the other lights are assigned to clusters
+        let spheres: Vec<(u32, BoundingSphere)> = self
+            .packed()
+            .enumerate()
+            .filter(|(_, light)| !light.is_directional())
+            .map(|(i, light)| {
+                let center = Point3::from_vec(light.position);
+                (i as u32, BoundingSphere::new(center, light.influence_radius()))
+            })
+            .collect();
+        recreate_bind_group |= self.clusters.update(device, queue, &spheres, view);
+
+        if recreate_bind_group {
+            self.bind_group = Self::create_bind_group(
+                device,
+                light_bind_group_layout,
+                &self.buffer,
+                &self.shadow_atlas,
+                &self.shadow_sampler,
+                &self.config,
+                &self.clusters,
+                &self.layers_buffer,
+                &self.shadow_moments,
+                &self.shadow_compare_sampler,
+                &self.environment,
+            );
+        }
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&header));
+        if !lights.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                mem::size_of::<LightsRaw>() as wgpu::BufferAddress,
+                bytemuck::cast_slice(&lights),
+            );
+        }
+
+        let layers: Vec<shadow::ShadowLayerRaw> = self
+            .gpu_lights
+            .iter()
+            .flat_map(|gpu_light| gpu_light.view_proj.iter().zip(&gpu_light.rects))
+            .map(|(view_proj, rect)| shadow::ShadowLayerRaw::new(*view_proj, rect.uv(atlas_size)))
+            .collect();
+        if !layers.is_empty() {
+            queue.write_buffer(&self.layers_buffer, 0, bytemuck::cast_slice(&layers));
+        }
+    }
+
+    /// The lights in the order they are packed on the GPU: directional lights first, followed by
+    /// the rest
+    fn packed(&self) -> impl Iterator<Item = &Light> {
+        self.iter()
+            .filter(|light| light.is_directional())
+            .chain(self.iter().filter(|light| !light.is_directional()))
+    }
+
+    /// The lights as stored on the GPU by the last `upload`, skipping lights removed since then
+    fn gpu_lights(&self) -> impl Iterator<Item = (&GpuLight, &Light)> {
+        self.gpu_lights
+            .iter()
+            .filter_map(move |gpu_light| Some((gpu_light, self.get(gpu_light.light)?)))
+    }
+
+    /// The buffer header followed by the lights as packed by the last `upload`
+    pub fn to_raw(&self) -> (LightsRaw, Vec<LightRaw>) {
+        let mut directional_count = 0;
+        let lights: Vec<LightRaw> = self
+            .gpu_lights()
+            .map(|(gpu_light, light)| {
+                if light.is_directional() {
+                    directional_count += 1;
+                }
+
+                let cascade_splits = gpu_light.splits.into();
+                let shadow_index = if gpu_light.rects.is_empty() {
+                    NO_SHADOW
+                } else {
+                    gpu_light.first_layer as u32
+                };
+                let settings = self.light_shadow_settings(light);
+                let shadow_params = Vector4::new(settings.far, settings.bias, 0.0, 0.0);
+
+                let (light_type, cos_inner, cos_outer) = match light.light_type {
+                    LightType::Directional => (LIGHT_TYPE_DIRECTIONAL, 0.0, 0.0),
+                    LightType::Point => (LIGHT_TYPE_POINT, 0.0, 0.0),
+                    LightType::Spot {
+                        inner_angle,
+                        outer_angle,
+                    } => {
+                        let (inner, outer) = clamp_spot_angles(inner_angle, outer_angle);
+                        (LIGHT_TYPE_SPOT, inner.cos(), outer.cos())
+                    }
+                };
+
+                LightRaw {
+                    position: light.position.extend(light.influence_radius()),
+                    color: light.color.extend(light.intensity),
+                    direction: light.direction.extend(0.0),
+                    cascade_splits,
+                    shadow_params,
+                    shadow_index,
+                    light_type,
+                    cos_inner,
+                    cos_outer,
+                }
+            })
+            .collect();
+
+        // Ambient light doesn't depend on the distance to the light, so it's summed up here rather
+        // than in the clustered light loop
+        let ambient = self
+            .iter()
+            .fold(self.ambient, |sum, light| sum + light.color * light.ambient);
+
+        let header = LightsRaw {
+            ambient: ambient.extend(0.0),
+            count: lights.len() as u32,
+            directional_count,
+            _padding: [0; 2],
+        };
+
+        (header, lights)
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Lights"),
-            contents: bytemuck::cast_slice(&[buffer_data]),
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-        });
+            size: (mem::size_of::<LightsRaw>() + capacity * mem::size_of::<LightRaw>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_layers_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow layers"),
+            size: (capacity * mem::size_of::<shadow::ShadowLayerRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: light_bind_group_layout,
+    #[allow(clippy::too_many_arguments)]
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        shadow_atlas: &shadow::ShadowAtlas,
+        shadow_sampler: &wgpu::Sampler,
+        config: &LightConfig,
+        clusters: &cluster::Clusters,
+        layers_buffer: &wgpu::Buffer,
+        shadow_moments: &shadow::ShadowAtlas,
+        shadow_compare_sampler: &wgpu::Sampler,
+        environment: &Environment,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: wgpu::BindingResource::Buffer {
-                        buffer: &buffer,
+                        buffer,
                         offset: 0,
                         size: None,
                     },
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::TextureViewArray(&shadow_texture_views),
+                    resource: wgpu::BindingResource::TextureView(&shadow_atlas.texture_view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&shadow_sampler),
+                    resource: wgpu::BindingResource::Sampler(shadow_sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
                     resource: config.binding_resource(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &clusters.uniforms_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &clusters.ranges_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &clusters.indices_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: layers_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureView(&shadow_moments.texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(shadow_compare_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::TextureView(&environment.irradiance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: wgpu::BindingResource::TextureView(&environment.specular.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: wgpu::BindingResource::TextureView(&environment.brdf_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: wgpu::BindingResource::Sampler(&environment.sampler),
+                },
             ],
-            label: None,
-        });
-
-        Self {
-            lights,
-            shadow_textures,
-            material,
-            config,
-            buffer,
-            bind_group,
-        }
-    }
-
-    pub fn add_light(&mut self, position: Vector3) -> Option<LightId> {
-        let index = self.lights.iter().position(|l| l.is_none());
-        match index {
-            Some(i) => self.lights[i] = Some(Light::new(i, position, (1.0, 1.0, 1.0))),
-            None => eprintln!("Unable to add light - max count reached"),
-        }
-        index
-    }
-
-    pub fn to_raw(&self) -> LightsRaw {
-        Self::map_raw(&self.lights)
-    }
-
-    fn map_raw(lights: &[Option<Light>]) -> LightsRaw {
-        let mut positions = [Vector4::zero(); MAX_LIGHTS];
-        let mut colors = [Vector4::zero(); MAX_LIGHTS];
-
-        for i in 0..MAX_LIGHTS {
-            let light = &lights[i];
-            if let Some(light) = light {
-                positions[i] = light.position.extend(0.0);
-                colors[i] = light.color.extend(0.0);
-            }
-        }
-
-        LightsRaw { positions, colors }
+            label: Some("Lights"),
+        })
     }
 }
 
+/// A light in the world. Start from one of the constructors and override the remaining fields
+/// with struct update syntax.
 #[derive(Copy, Clone)]
 pub struct Light {
+    /// Assigned by `Lights::add_light`
     pub id: LightId,
+    /// Unused by directional lights
     pub position: Vector3,
+    /// Unused by point lights
+    pub direction: Vector3,
     pub color: Vector3,
+    /// Brightness of point and spot lights at a distance of 1, or the brightness of directional
+    /// lights
+    pub intensity: f32,
+    /// Distance at which point and spot lights fade out completely. Unused by directional lights.
+    pub range: f32,
+    /// Light added everywhere regardless of distance, as a fraction of `color`
+    pub ambient: f32,
+    /// Overrides `Lights::shadow_settings` for this light
+    pub shadow_settings: Option<shadow::ShadowSettings>,
+    /// Shadow maps of static lights are kept until the light or a shadow caster in view changes,
+    /// while those of dynamic lights are re-rendered every frame
+    pub mobility: model::Mobility,
     pub light_type: LightType,
 }
 
 impl Light {
-    fn new<P: Into<Vector3>, C: Into<Vector3>>(
-        id: LightId,
-        position: P,
-        color: C,
-    ) -> Self {
+    /// A white light shining in all directions
+    pub fn point<P: Into<Vector3>>(position: P) -> Self {
         Light {
-            id,
+            id: LightId::default(),
             position: position.into(),
-            color: color.into(),
+            direction: -Vector3::unit_y(),
+            color: (1.0, 1.0, 1.0).into(),
+            intensity: DEFAULT_INTENSITY,
+            range: DEFAULT_RANGE,
+            ambient: DEFAULT_AMBIENT,
+            shadow_settings: None,
+            mobility: model::Mobility::Dynamic,
             light_type: LightType::Point,
         }
     }
+
+    /// A white light infinitely far away shining in the given direction, like the sun
+    pub fn directional<D: Into<Vector3>>(direction: D) -> Self {
+        Light {
+            position: Vector3::zero(),
+            direction: direction.into().normalize(),
+            intensity: 1.0,
+            light_type: LightType::Directional,
+            ..Self::point(Vector3::zero())
+        }
+    }
+
+    /// A white light shining a cone in the given direction. It fades out between the inner and
+    /// outer angle, measured from the direction. The outer angle is limited to `MAX_SPOT_ANGLE`
+    /// and the inner one to the outer one.
+    pub fn spot<P: Into<Vector3>, D: Into<Vector3>>(
+        position: P,
+        direction: D,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    ) -> Self {
+        let (inner_angle, outer_angle) = clamp_spot_angles(inner_angle, outer_angle);
+        Light {
+            direction: direction.into().normalize(),
+            light_type: LightType::Spot {
+                inner_angle,
+                outer_angle,
+            },
+            ..Self::point(position)
+        }
+    }
+
+    pub fn is_directional(&self) -> bool {
+        match self.light_type {
+            LightType::Directional => true,
+            LightType::Point | LightType::Spot { .. } => false,
+        }
+    }
+
+    /// Distance beyond which the light has no effect
+    pub fn influence_radius(&self) -> f32 {
+        match self.light_type {
+            LightType::Directional => f32::INFINITY,
+            LightType::Point | LightType::Spot { .. } => self.range,
+        }
+    }
 }
 
+/// Header of the light storage buffer. It's followed by `count` instances of `LightRaw`, of which
+/// the first `directional_count` are directional lights.
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct LightsRaw {
-    // We store these as Vector4 because vectors require 16 byte alignment
-    pub positions: [Vector4; MAX_LIGHTS],
-    pub colors: [Vector4; MAX_LIGHTS],
+    /// Sum of the ambient contribution of all lights
+    pub ambient: Vector4,
+    pub count: u32,
+    pub directional_count: u32,
+    // The light array starts at the next 16 byte boundary
+    _padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct LightRaw {
+    // We store these as Vector4 because vectors require 16 byte alignment. The w component of the
+    // position holds the influence radius, and the w component of the color the intensity.
+    pub position: Vector4,
+    pub color: Vector4,
+    pub direction: Vector4,
+    /// View depth where each cascade ends, for directional lights
+    pub cascade_splits: Vector4,
+    /// Far plane and bias (see `ShadowSettings`)
+    pub shadow_params: Vector4,
+    /// First of the light's shadow layers (see `Lights::shadow_layers`), or `NO_SHADOW` if it has
+    /// no room in the atlas
+    pub shadow_index: u32,
+    /// One of the `LIGHT_TYPE_*` constants (matches `shader.frag`)
+    pub light_type: u32,
+    /// Cosine of the inner and outer cone angle of spot lights
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+}
+
+pub const LIGHT_TYPE_DIRECTIONAL: u32 = 0;
+pub const LIGHT_TYPE_POINT: u32 = 1;
+pub const LIGHT_TYPE_SPOT: u32 = 2;
+
+/// `LightRaw::shadow_index` of lights without shadow maps
+pub const NO_SHADOW: u32 = u32::MAX;
+
+pub const SHADOW_FILTER_HARDWARE: u32 = 0;
+pub const SHADOW_FILTER_PCF: u32 = 1;
+pub const SHADOW_FILTER_PCSS: u32 = 2;
+pub const SHADOW_FILTER_VSM: u32 = 3;
+pub const SHADOW_FILTER_EVSM: u32 = 4;
+
+/// Most samples the PCF and PCSS filters take (matches `shader.frag`)
+pub const MAX_FILTER_TAPS: u32 = 32;
+
+/// How shadow maps are sampled when shading
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShadowFilter {
+    /// A single comparison, bilinearly filtered by the sampler
+    Hardware,
+    /// Percentage closer filtering over a rotated Poisson disk, with the radius in shadow map
+    /// texels
+    Pcf { taps: u32, radius: f32 },
+    /// Percentage closer soft shadows, which are sharp near the caster and soften with the
+    /// distance to it. `light_size` is in world units for point and spot lights, and `sun_size`
+    /// is the tangent of the angular radius of directional lights.
+    Pcss {
+        taps: u32,
+        light_size: f32,
+        sun_size: f32,
+    },
+    /// Variance shadow maps, filtered by blurring the depth moments over `blur_radius` texels (see
+    /// `vsm::VsmPass`). `light_bleed_reduction` cuts off the lowest visibility, where light bleeds
+    /// through overlapping casters.
+    Vsm {
+        blur_radius: u32,
+        light_bleed_reduction: f32,
+    },
+    /// Variance shadow maps over exponentially warped depth, which bleed far less light
+    Evsm {
+        blur_radius: u32,
+        light_bleed_reduction: f32,
+    },
+}
+
+impl ShadowFilter {
+    /// Each filter with reasonable parameters
+    pub const ALL: [ShadowFilter; 5] = [
+        ShadowFilter::Hardware,
+        ShadowFilter::Pcf {
+            taps: 20,
+            radius: 1.5,
+        },
+        ShadowFilter::Pcss {
+            taps: 24,
+            light_size: 0.5,
+            sun_size: 0.01,
+        },
+        ShadowFilter::Vsm {
+            blur_radius: 2,
+            light_bleed_reduction: 0.3,
+        },
+        ShadowFilter::Evsm {
+            blur_radius: 2,
+            light_bleed_reduction: 0.1,
+        },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShadowFilter::Hardware => "Hardware",
+            ShadowFilter::Pcf { .. } => "PCF",
+            ShadowFilter::Pcss { .. } => "PCSS",
+            ShadowFilter::Vsm { .. } => "VSM",
+            ShadowFilter::Evsm { .. } => "EVSM",
+        }
+    }
+
+    /// Radius of the blur over the depth moments, if the filter samples them rather than the
+    /// shadow maps
+    pub fn moments_blur_radius(&self) -> Option<u32> {
+        match *self {
+            ShadowFilter::Vsm { blur_radius, .. } | ShadowFilter::Evsm { blur_radius, .. } => {
+                Some(blur_radius)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for ShadowFilter {
+    fn default() -> Self {
+        ShadowFilter::ALL[1]
+    }
 }
 
 #[repr(C)]
@@ -164,20 +920,30 @@ pub struct LightsRaw {
 struct LightConfigRaw {
     pub shadows_enabled: bool,
     _padding: [u8; 3],
+    /// One of the `SHADOW_FILTER_*` constants (matches `shader.frag`)
+    pub shadow_filter: u32,
+    pub filter_taps: u32,
+    pub filter_radius: f32,
+    pub light_size: f32,
+    pub sun_size: f32,
+    pub light_bleed_reduction: f32,
+    pub environment_intensity: f32,
 }
 
 pub struct LightConfig {
     pub shadows_enabled: bool,
+    pub shadow_filter: ShadowFilter,
+    /// Scale of the image based lighting from `Lights::environment`
+    pub environment_intensity: f32,
     buffer: wgpu::Buffer,
 }
 
 impl LightConfig {
     pub fn new(device: &wgpu::Device) -> Self {
         let shadows_enabled = true;
-        let contents = LightConfigRaw {
-            shadows_enabled,
-            _padding: [0; 3],
-        };
+        let shadow_filter = ShadowFilter::default();
+        let environment_intensity = 1.0;
+        let contents = LightConfigRaw::new(shadows_enabled, shadow_filter, environment_intensity);
         let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&[contents]),
@@ -185,6 +951,8 @@ impl LightConfig {
         });
         LightConfig {
             shadows_enabled,
+            shadow_filter,
+            environment_intensity,
             buffer,
         }
     }
@@ -206,21 +974,89 @@ impl LightConfig {
     }
 
     fn to_raw(&self) -> LightConfigRaw {
-        LightConfigRaw {
-            shadows_enabled: self.shadows_enabled,
+        LightConfigRaw::new(
+            self.shadows_enabled,
+            self.shadow_filter,
+            self.environment_intensity,
+        )
+    }
+}
+
+impl LightConfigRaw {
+    fn new(shadows_enabled: bool, shadow_filter: ShadowFilter, environment_intensity: f32) -> Self {
+        let mut raw = LightConfigRaw {
+            shadows_enabled,
             _padding: [0; 3],
+            shadow_filter: SHADOW_FILTER_HARDWARE,
+            filter_taps: 1,
+            filter_radius: 0.0,
+            light_size: 0.0,
+            sun_size: 0.0,
+            light_bleed_reduction: 0.0,
+            environment_intensity,
+        };
+        match shadow_filter {
+            ShadowFilter::Hardware => {}
+            ShadowFilter::Pcf { taps, radius } => {
+                raw.shadow_filter = SHADOW_FILTER_PCF;
+                raw.filter_taps = taps;
+                raw.filter_radius = radius;
+            }
+            ShadowFilter::Pcss {
+                taps,
+                light_size,
+                sun_size,
+            } => {
+                raw.shadow_filter = SHADOW_FILTER_PCSS;
+                raw.filter_taps = taps;
+                raw.light_size = light_size;
+                raw.sun_size = sun_size;
+            }
+            ShadowFilter::Vsm {
+                light_bleed_reduction,
+                ..
+            } => {
+                raw.shadow_filter = SHADOW_FILTER_VSM;
+                raw.light_bleed_reduction = light_bleed_reduction;
+            }
+            ShadowFilter::Evsm {
+                light_bleed_reduction,
+                ..
+            } => {
+                raw.shadow_filter = SHADOW_FILTER_EVSM;
+                raw.light_bleed_reduction = light_bleed_reduction;
+            }
         }
+        raw
     }
 }
 
 unsafe impl bytemuck::Zeroable for LightConfigRaw {}
 unsafe impl bytemuck::Pod for LightConfigRaw {}
 
+/// Widest outer angle of a spot light. Its shadow map is a perspective projection with twice the
+/// angle as field of view, which has to stay below 180 degrees.
+pub const MAX_SPOT_ANGLE: Rad<f32> = Rad(std::f32::consts::FRAC_PI_2 - 0.05);
+
+/// Limits the outer angle to `MAX_SPOT_ANGLE` and keeps the inner angle within it
+fn clamp_spot_angles(inner: Rad<f32>, outer: Rad<f32>) -> (Rad<f32>, Rad<f32>) {
+    let outer = Rad(outer.0.clamp(0.0, MAX_SPOT_ANGLE.0));
+    (Rad(inner.0.clamp(0.0, outer.0)), outer)
+}
+
 #[derive(Copy, Clone)]
 pub enum LightType {
     Directional,
     Point,
+    /// A cone of light, fading out between the inner and outer angle. They are clamped as by
+    /// `Light::spot` when uploaded.
+    Spot {
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
 }
 
 unsafe impl bytemuck::Zeroable for LightsRaw {}
 unsafe impl bytemuck::Pod for LightsRaw {}
+unsafe impl bytemuck::Zeroable for LightRaw {}
+unsafe impl bytemuck::Pod for LightRaw {}
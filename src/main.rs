@@ -2,7 +2,9 @@ use futures::executor::block_on;
 use geometry::Vertex;
 use kanvas::prelude::*;
 use kanvas::*;
+use camera::Projection;
 use model::DrawModel;
+use std::collections::HashMap;
 use std::iter;
 use wgpu::util::DeviceExt;
 use winit::{
@@ -66,20 +68,38 @@ fn main() {
     });
 }
 
+/// A model in the scene and its instances
+struct SceneModel {
+    model: model::Model,
+    instances: Vec<model::Instance>,
+    instances_bind_group: wgpu::BindGroup,
+}
+
 struct State {
     context: Context,
     camera: camera::Camera,
     projection: camera::PerspectiveProjection,
     camera_controller: camera::CameraController,
     forward_pass: forward::ForwardPass,
-    instances_bind_group: wgpu::BindGroup,
-    instances: Vec<model::Instance>,
-    obj_model: model::Model,
+    background_pass: background::BackgroundPass,
+    ssao_pass: ssao::SsaoPass,
+    post_stack: post::PostStack,
+    tonemap_pass: tonemap::TonemapPass,
+    /// Everything drawn, picked and shadowed, in the order `scene_bvh` was built from. Added with
+    /// `add_model`.
+    models: Vec<SceneModel>,
+    scene_bvh: bvh::SceneBvh,
     billboards: billboard::Billboards,
     shadow_pass: shadow::ShadowPass,
+    vsm_pass: vsm::VsmPass,
     debug_pass: debug::DebugPass,
     debug_ui: ui::DebugUi,
-    light_billboards: [Option<billboard::BillboardId>; light::MAX_LIGHTS],
+    id_picker: picking::IdBufferPicker,
+    cursor_position: winit::dpi::PhysicalPosition<f64>,
+    pick_requested: bool,
+    light_billboards: HashMap<light::LightId, billboard::BillboardId>,
+    /// The directional light, and how it's set up when the physical sky isn't driving it
+    sun: (light::LightId, light::Light),
 }
 
 impl State {
@@ -96,6 +116,12 @@ impl State {
         let camera_controller = camera::CameraController::new(4.0, 0.8);
 
         let forward_pass = forward::ForwardPass::new(&mut context);
+        let mut background_pass = background::BackgroundPass::new(&mut context);
+        let mut post_stack = post::PostStack::new(&mut context);
+        let bloom = bloom::Bloom::new(&mut context, post_stack.fullscreen());
+        post_stack.push(Box::new(bloom));
+        let ssao_pass = ssao::SsaoPass::new(&mut context, post_stack.fullscreen(), &forward_pass);
+        let tonemap_pass = tonemap::TonemapPass::new(&mut context, &forward_pass.hdr_texture);
 
         let instances = vec![model::Instance {
             position: Vector3 {
@@ -104,34 +130,10 @@ impl State {
                 z: 0.0,
             },
             rotation: cgmath::Quaternion::from_axis_angle(Vector3::unit_z(), cgmath::Deg(0.0)),
+            mobility: model::Mobility::Static,
+            casts_shadows: true,
+            receives_shadows: true,
         }];
-        let instance_data = instances
-            .iter()
-            .map(model::Instance::to_raw)
-            .collect::<Vec<_>>();
-        let instance_buffer =
-            context
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Instances"),
-                    contents: bytemuck::cast_slice(&instance_data),
-                    usage: wgpu::BufferUsage::STORAGE,
-                });
-
-        let instances_bind_group = context
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &context.instances_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer {
-                        buffer: &instance_buffer,
-                        offset: 0,
-                        size: None,
-                    },
-                }],
-                label: Some("instances_bind_group"),
-            });
 
         let vertex_descs = [model::ModelVertex::desc()];
 
@@ -139,19 +141,45 @@ impl State {
             &context.device,
             &mut context.shader_compiler,
             &context.instances_bind_group_layout,
+            &context.morph_bind_group_layout,
             &vertex_descs,
+            &context.lights.shadow_settings,
         );
+        let vsm_pass = vsm::VsmPass::new(&context.device, &mut context.shader_compiler);
 
         let (obj_model, cmds) = model::Model::load(
             &context.device,
             &context.texture_normal_bind_group_layout,
+            &context.morph_bind_group_layout,
             "res/models/scene.obj",
         )
         .unwrap();
         context.queue.submit(cmds);
 
+        // The environment map isn't checked in, so the scene is lit without one unless it's there
+        let environment_path = std::path::Path::new("res/tex/environment.hdr");
+        let has_environment = environment_path.exists();
+        if has_environment {
+            let panorama =
+                environment::load_equirect(&context.device, &context.queue, environment_path)
+                    .unwrap();
+            let environment = environment::Environment::from_equirect(
+                &context.device,
+                &context.queue,
+                &mut context.shader_compiler,
+                &panorama,
+            );
+            background_pass.set_cubemap(&context.device, &environment.cubemap);
+            background_pass.set_panorama(&context.device, &panorama);
+            context.lights.set_environment(
+                &context.device,
+                &context.light_bind_group_layout,
+                environment,
+            );
+        }
+
         let mut billboards = billboard::Billboards::new(&context);
-        let mut light_billboards: [Option<billboard::BillboardId>; light::MAX_LIGHTS] = [None; light::MAX_LIGHTS];
+        let mut light_billboards = HashMap::new();
 
         {
             let position: Vector3 = (-15.0, 12.0, 8.0).into();
@@ -160,10 +188,12 @@ impl State {
                 billboard::Billboard {
                     position,
                     material: context.lights.material,
+                    emission: 8.0,
+                    casts_shadows: false,
                 },
             );
-            let light_id = context.lights.add_light(position).unwrap();
-            light_billboards[light_id] = Some(billboard);
+            let light_id = context.lights.add_light(light::Light::point(position));
+            light_billboards.insert(light_id, billboard);
         }
 
         {
@@ -173,36 +203,172 @@ impl State {
                 billboard::Billboard {
                     position,
                     material: context.lights.material,
+                    emission: 8.0,
+                    casts_shadows: false,
+                },
+            );
+            let light_id = context.lights.add_light(light::Light {
+                color: (1.0, 0.85, 0.6).into(),
+                intensity: 60.0,
+                range: 30.0,
+                ..light::Light::point(position)
+            });
+            light_billboards.insert(light_id, billboard);
+        }
+
+        {
+            let position: Vector3 = (0.0, 15.0, -10.0).into();
+            let billboard = billboards.insert(
+                &context,
+                billboard::Billboard {
+                    position,
+                    material: context.lights.material,
+                    emission: 8.0,
+                    casts_shadows: false,
                 },
             );
-            let light_id = context.lights.add_light(position).unwrap();
-            light_billboards[light_id] = Some(billboard);
+            let light_id = context.lights.add_light(light::Light {
+                intensity: 200.0,
+                range: 40.0,
+                ambient: 0.0,
+                mobility: model::Mobility::Static,
+                ..light::Light::spot(
+                    position,
+                    (0.0, -1.0, 0.3),
+                    Deg(20.0).into(),
+                    Deg(30.0).into(),
+                )
+            });
+            light_billboards.insert(light_id, billboard);
         }
 
+        let sun = {
+            let light = light::Light {
+                intensity: 0.3,
+                ambient: 0.03,
+                mobility: model::Mobility::Static,
+                ..light::Light::directional((-0.4, -1.0, -0.3))
+            };
+            (context.lights.add_light(light), light)
+        };
+
         let debug_pass = debug::DebugPass::new(&mut context);
-        let debug_ui = ui::DebugUi::new(&context, &context.lights);
+        let mut debug_ui = ui::DebugUi::new(&context, &context.lights);
+        let clear_color = forward_pass.clear_color;
+        debug_ui.clear_color = [
+            clear_color.r as f32,
+            clear_color.g as f32,
+            clear_color.b as f32,
+        ];
+        if has_environment {
+            debug_ui.background = background::Background::Cubemap { intensity: 1.0 };
+        }
+        let id_picker =
+            picking::IdBufferPicker::new(&mut context, &forward_pass.uniform_bind_group_layout);
 
-        State {
+        let mut state = State {
             context,
             camera,
             projection,
             camera_controller,
             forward_pass,
-            instances_bind_group,
-            instances,
-            obj_model,
+            background_pass,
+            ssao_pass,
+            post_stack,
+            tonemap_pass,
+            models: Vec::new(),
+            scene_bvh: bvh::SceneBvh::build(&[]),
             billboards,
             shadow_pass,
+            vsm_pass,
             debug_pass,
             debug_ui,
+            id_picker,
+            cursor_position: winit::dpi::PhysicalPosition::new(0.0, 0.0),
+            pick_requested: false,
             light_billboards,
-        }
+            sun,
+        };
+        state.add_model(obj_model, instances);
+        state
+    }
+
+    /// Adds a model to the scene, to be drawn, picked and to cast shadows with the given instances.
+    /// Returns its index in `models`.
+    fn add_model(&mut self, model: model::Model, instances: Vec<model::Instance>) -> usize {
+        let instance_data = instances
+            .iter()
+            .map(model::Instance::to_raw)
+            .collect::<Vec<_>>();
+        let instance_buffer =
+            self.context
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Instances"),
+                    contents: bytemuck::cast_slice(&instance_data),
+                    usage: wgpu::BufferUsage::STORAGE,
+                });
+
+        let instances_bind_group =
+            self.context
+                .device
+                .create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &self.context.instances_bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &instance_buffer,
+                            offset: 0,
+                            size: None,
+                        },
+                    }],
+                    label: Some("instances_bind_group"),
+                });
+
+        self.models.push(SceneModel {
+            model,
+            instances,
+            instances_bind_group,
+        });
+
+        // The new instances can be in any shadow map
+        let scene: Vec<_> = self
+            .models
+            .iter()
+            .map(|scene_model| (&scene_model.model, scene_model.instances.as_slice()))
+            .collect();
+        self.scene_bvh = bvh::SceneBvh::build(&scene);
+        self.shadow_pass.invalidate();
+
+        self.models.len() - 1
+    }
+
+    fn scene_models(&self) -> Vec<&model::Model> {
+        self.models
+            .iter()
+            .map(|scene_model| &scene_model.model)
+            .collect()
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        // Minimized, there's nothing to render into and the targets can't be empty
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
         self.context.resize(new_size);
         self.forward_pass
             .resize(&self.context.device, &self.context.sc_desc);
+        self.ssao_pass.resize(
+            &self.context.device,
+            &self.context.sc_desc,
+            &self.forward_pass,
+        );
+        self.post_stack
+            .resize(&self.context.device, &self.context.sc_desc);
+        self.tonemap_pass
+            .set_source(&self.context.device, &self.forward_pass.hdr_texture);
+        self.id_picker
+            .resize(&self.context.device, &self.context.sc_desc);
         self.projection.resize(new_size.width, new_size.height);
     }
 
@@ -242,10 +408,85 @@ impl State {
                 }
                 true
             }
+            WindowEvent::MouseInput {
+                button: MouseButton::Right,
+                state: ElementState::Pressed,
+                ..
+            } => {
+                if !self.camera_controller.is_active
+                    && !self.debug_ui.context.io().want_capture_mouse
+                {
+                    if self.debug_ui.gpu_picking {
+                        // The id buffer is rendered and read at the end of the next frame
+                        self.pick_requested = true;
+                    } else {
+                        self.pick_cpu();
+                    }
+                }
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = *position;
+                false
+            }
             _ => false,
         }
     }
 
+    fn screen_space(&self) -> camera::ScreenSpace {
+        camera::ScreenSpace::new(
+            &self.camera,
+            &self.projection,
+            self.context.sc_desc.width,
+            self.context.sc_desc.height,
+        )
+    }
+
+    fn pick_cpu(&mut self) {
+        let ray = self.screen_space().view_ray(
+            self.cursor_position.x as f32,
+            self.cursor_position.y as f32,
+        );
+        self.debug_ui.picked = picking::pick(&self.scene_bvh, &self.scene_models(), &ray);
+    }
+
+    fn pick_gpu(&mut self) {
+        // The cursor can be left of or above the window, which the id picker can't tell
+        if self.cursor_position.x < 0.0 || self.cursor_position.y < 0.0 {
+            self.debug_ui.picked = None;
+            return;
+        }
+
+        let mut encoder = self.context.create_encoder();
+        let targets: Vec<_> = self
+            .models
+            .iter()
+            .enumerate()
+            .map(|(model_index, scene_model)| picking::PickTarget {
+                model_index,
+                model: &scene_model.model,
+                instances: 0..scene_model.instances.len() as u32,
+                instances_bind_group: &scene_model.instances_bind_group,
+            })
+            .collect();
+        self.id_picker.render(
+            &self.context.queue,
+            &mut encoder,
+            &self.forward_pass.uniform_bind_group,
+            &targets,
+        );
+        self.context.queue.submit(iter::once(encoder.finish()));
+
+        self.debug_ui.picked = self.id_picker.pick(
+            &self.context,
+            self.cursor_position.x as u32,
+            self.cursor_position.y as u32,
+            &self.screen_space(),
+            &self.scene_bvh,
+            &self.scene_models(),
+        );
+    }
+
     fn grab_camera(&mut self) {
         self.camera_controller.is_active = true;
         self.context.window.set_cursor_visible(false);
@@ -274,44 +515,113 @@ impl State {
         self.forward_pass
             .uniforms
             .update_view_proj(&self.camera, &self.projection);
+        // Refit after any instance moved, before this frame's culling, picking and shadows
+        self.scene_bvh.update();
 
         let mut encoder = self.context.create_encoder();
 
         self.forward_pass
             .upload_uniforms(&self.context.device, &mut encoder);
 
+        // The physical sky places the sun, colors the sunlight and lights the ambient
+        let (sun, sun_light) = self.sun;
+        if let background::Background::PhysicalSky(mut sky) = self.debug_ui.background {
+            sky.sun_direction = sky::sun_direction(self.debug_ui.time_of_day, Deg(40.0));
+            sky.apply(&mut self.context.lights, sun);
+            self.background_pass.background = background::Background::PhysicalSky(sky);
+        } else {
+            if let Some(light) = self.context.lights.get_mut(sun) {
+                light.direction = sun_light.direction;
+                light.color = sun_light.color;
+                light.intensity = sun_light.intensity;
+            }
+            self.context.lights.ambient = Vector3::zero();
+            self.background_pass.background = self.debug_ui.background;
+        }
+        let [r, g, b] = self.debug_ui.clear_color;
+        self.forward_pass.clear_color = wgpu::Color {
+            r: r as f64,
+            g: g as f64,
+            b: b as f64,
+            a: 1.0,
+        };
+        self.background_pass
+            .update(&self.context.queue, &self.camera, &self.projection);
+
+        let sample_count = self.debug_ui.sample_count;
+        if sample_count != self.forward_pass.sample_count {
+            self.forward_pass
+                .set_sample_count(&mut self.context, sample_count);
+            self.background_pass
+                .set_sample_count(&self.context.device, sample_count);
+        }
+
+        self.ssao_pass.settings = self.debug_ui.ssao;
+        self.forward_pass
+            .set_prepass(&self.context.device, self.ssao_pass.settings.enabled);
+        self.ssao_pass
+            .update(&self.context.queue, &self.camera, &self.projection);
+
+        // Effects toggled in the debug UI
+        for (effect, settings) in self
+            .post_stack
+            .effects
+            .iter_mut()
+            .zip(&self.debug_ui.post_effects)
+        {
+            effect.enabled = settings.enabled;
+            let parameters = effect.effect.parameters();
+            for (parameter, &(_, value, _)) in parameters.into_iter().zip(&settings.parameters) {
+                *parameter.value = value;
+            }
+        }
+        self.debug_ui.post_effects = self
+            .post_stack
+            .effects
+            .iter_mut()
+            .map(|effect| ui::PostEffectSettings {
+                name: effect.effect.name().to_string(),
+                enabled: effect.enabled,
+                parameters: effect
+                    .effect
+                    .parameters()
+                    .into_iter()
+                    .map(|parameter| (parameter.name, *parameter.value, parameter.range))
+                    .collect(),
+            })
+            .collect();
+
         // Update the light
         {
-            for light in self.context.lights.lights.iter_mut() {
-                if let Some(light) = light {
+            for light in self.context.lights.iter_mut() {
+                if light.mobility == model::Mobility::Dynamic && !light.is_directional() {
                     let old_position = light.position;
                     light.position = cgmath::Quaternion::from_axis_angle(
                         (0.0, 1.0, 0.0).into(),
                         cgmath::Deg(60.0 * dt.as_secs_f32()),
                     ) * old_position;
 
-                    let light_billboard = self.light_billboards[light.id].unwrap();
-                    if let Some(billboard) = self.billboards.get(light_billboard) {
-                        billboard.position = light.position;
+                    if let Some(&light_billboard) = self.light_billboards.get(&light.id) {
+                        if let Some(billboard) = self.billboards.get(light_billboard) {
+                            billboard.position = light.position;
+                        }
                     }
                 }
             }
 
-            let raw_lights = self.context.lights.to_raw();
-            let staging_buffer =
-                self.context
-                    .device
-                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: Some("Staging"),
-                        contents: bytemuck::cast_slice(&[raw_lights]),
-                        usage: wgpu::BufferUsage::COPY_SRC,
-                    });
-            encoder.copy_buffer_to_buffer(
-                &staging_buffer,
-                0,
-                &self.context.lights.buffer,
-                0,
-                std::mem::size_of::<light::LightsRaw>() as wgpu::BufferAddress,
+            // The filter decides whether the shadow map moments are allocated
+            self.context.lights.config.shadow_filter = self.debug_ui.shadow_filter;
+            self.context.lights.clusters.debug_heatmap = self.debug_ui.cluster_heatmap;
+            self.context.lights.upload(
+                &self.context.device,
+                &self.context.queue,
+                &self.context.light_bind_group_layout,
+                &camera::View {
+                    camera: &self.camera,
+                    projection: &self.projection,
+                    width: self.context.sc_desc.width,
+                    height: self.context.sc_desc.height,
+                },
             );
         }
 
@@ -319,10 +629,20 @@ impl State {
         self.context.lights.config.shadows_enabled = self.debug_ui.shadows_enabled;
         self.context.lights.config.upload(&self.context.queue);
 
-        for (i, light) in self.context.lights.lights.iter().enumerate() {
-            if let Some(light) = light {
-                self.shadow_pass
-                    .update_light(&self.context.queue, i, &light);
+        self.shadow_pass.reserve(
+            &self.context.device,
+            self.context.lights.shadow_layer_count(),
+        );
+        for light in self.context.lights.iter() {
+            if let Some((first_layer, projections)) = self.context.lights.shadow_layers(light.id) {
+                let settings = self.context.lights.light_shadow_settings(light);
+                self.shadow_pass.update_light(
+                    &self.context.queue,
+                    first_layer,
+                    light,
+                    projections,
+                    &settings,
+                );
             }
         }
 
@@ -333,67 +653,109 @@ impl State {
 
         // Update ui
         self.debug_ui.camera_pos = self.camera.position;
+
+        // Anchor a label to each light on screen
+        let screen = self.screen_space();
+        self.debug_ui.labels = self
+            .context
+            .lights
+            .iter()
+            .filter(|light| !light.is_directional())
+            .filter_map(|light| {
+                let position = Point3::from_vec(light.position);
+                if !screen.is_on_screen(position) {
+                    return None;
+                }
+                let p = screen.project(position)?;
+                Some(ui::HudLabel {
+                    text: format!("Light {}", light.id.index()),
+                    position: [p.x, p.y],
+                })
+            })
+            .collect();
     }
 
     fn render(&mut self) {
         let frame = self.context.frame();
         let mut encoder = self.context.create_encoder();
 
-        // clear the screen
-        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: &frame.output.view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.1,
-                        g: 0.2,
-                        b: 0.3,
-                        a: 1.0,
-                    }),
-                    store: true,
-                },
-            }],
-            depth_stencil_attachment: None,
-        });
-
-        // render shadow maps
+        // render shadow maps, skipping faces that haven't changed
+        let changes = self.scene_bvh.take_changes();
         if self.context.lights.config.shadows_enabled {
-            for (i, light) in self.context.lights.lights.iter().enumerate() {
-                if let Some(_) = light {
-                    for face_index in 0..6 {
-                        // shadow pass
-                        let mut pass = self.shadow_pass.begin(&mut encoder, face_index);
-                        for mesh in &self.obj_model.meshes {
-                            pass.render(
-                                shadow::ShadowPassRenderData::from_mesh(
-                                    &mesh,
-                                    &self.instances_bind_group,
-                                ),
-                                face_index,
-                                i,
-                            );
-                        }
-                    }
+            self.shadow_pass.bake_static = self.debug_ui.bake_static_shadows;
+            let casters: Vec<_> = self
+                .models
+                .iter()
+                .enumerate()
+                .map(|(model_index, scene_model)| shadow::ShadowCaster {
+                    model_index,
+                    model: &scene_model.model,
+                    instances: 0..scene_model.instances.len() as u32,
+                    instances_bind_group: &scene_model.instances_bind_group,
+                })
+                .collect();
+            let rendered = self.shadow_pass.render(
+                &self.context.device,
+                &mut encoder,
+                &self.context.lights,
+                &self.scene_bvh,
+                &changes,
+                &casters,
+                &self.billboards.shadow_casters(),
+            );
+            self.vsm_pass.render(
+                &self.context.device,
+                &self.context.queue,
+                &mut encoder,
+                &self.context.lights,
+                &rendered,
+            );
+        } else {
+            self.shadow_pass.invalidate();
+        }
 
-                    self.shadow_pass
-                        .copy_to_cubemap(&mut encoder, &self.context.lights.shadow_textures[i]);
+        let frustum = self.projection.frustum(&self.camera);
+        let visible: Vec<_> = (0..self.models.len())
+            .map(|model_index| self.scene_bvh.visible_ranges(model_index, &frustum))
+            .collect();
+        if self.forward_pass.runs_prepass() {
+            // depth (and normal) prepass, for the ambient occlusion
+            let settings = &self.ssao_pass.settings;
+            let normals = settings.enabled && settings.normal_prepass;
+            let mut render_pass = self.forward_pass.begin_prepass(&mut encoder, normals);
+            for (scene_model, visible) in self.models.iter().zip(&visible) {
+                for instances in visible.iter().cloned() {
+                    render_pass.draw_model_instanced(
+                        &scene_model.model,
+                        instances,
+                        &self.forward_pass.uniform_bind_group,
+                        &scene_model.instances_bind_group,
+                        &self.context.lights.bind_group,
+                    );
                 }
             }
         }
+        self.ssao_pass.render(&mut encoder, &self.forward_pass);
 
         {
             // forward pass
-            let mut render_pass = self.forward_pass.begin(&frame.output.view, &mut encoder);
+            let mut render_pass = self.forward_pass.begin(&mut encoder);
             render_pass.set_pipeline(&self.forward_pass.pipeline);
 
-            render_pass.draw_model_instanced(
-                &self.obj_model,
-                0..self.instances.len() as u32,
-                &self.forward_pass.uniform_bind_group,
-                &self.instances_bind_group,
-                &self.context.lights.bind_group,
-            );
+            for (scene_model, visible) in self.models.iter().zip(visible) {
+                for instances in visible {
+                    render_pass.draw_model_instanced(
+                        &scene_model.model,
+                        instances,
+                        &self.forward_pass.uniform_bind_group,
+                        &scene_model.instances_bind_group,
+                        &self.context.lights.bind_group,
+                    );
+                }
+            }
+
+            // the background only shows where the geometry left the far plane
+            self.background_pass.render(&mut render_pass);
 
             render_pass.set_pipeline(&self.forward_pass.billboard_pipeline);
             self.billboards.render(
@@ -403,6 +765,19 @@ impl State {
             );
         }
 
+        self.post_stack.render(
+            &self.context.device,
+            &self.context.queue,
+            &mut encoder,
+            &self.forward_pass.hdr_texture,
+            &self.forward_pass.depth_texture,
+        );
+
+        self.tonemap_pass.tonemapper = self.debug_ui.tonemapper;
+        self.tonemap_pass.exposure = self.debug_ui.exposure;
+        self.tonemap_pass
+            .render(&self.context.queue, &mut encoder, &frame.output.view);
+
         // Render debug UI
         if self.debug_ui.is_visible {
             self.debug_ui
@@ -410,5 +785,10 @@ impl State {
         }
 
         self.context.queue.submit(iter::once(encoder.finish()));
+
+        if self.pick_requested {
+            self.pick_requested = false;
+            self.pick_gpu();
+        }
     }
 }
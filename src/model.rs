@@ -1,3 +1,5 @@
+use crate::bounds::{Aabb, BoundingSphere, Ray};
+use crate::bvh::Bvh;
 use crate::geometry::Vertex;
 use crate::prelude::*;
 use crate::texture;
@@ -82,6 +84,7 @@ where
         self.set_bind_group(1, &uniforms, &[]);
         self.set_bind_group(2, &instances_bind_group, &[]);
         self.set_bind_group(3, &light, &[]);
+        self.set_bind_group(4, &mesh.morph_targets.bind_group, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
 
@@ -141,6 +144,8 @@ where
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
 }
 
 pub type MaterialId = u32;
@@ -149,10 +154,26 @@ pub struct Material {
     pub name: String,
     pub diffuse_texture: texture::Texture,
     pub normal_texture: texture::Texture,
+    /// Light the surface gives off by itself, in linear HDR color, so that it glows with bloom.
+    /// Multiplied by `emissive_texture` if there is one.
+    pub emissive: Vector3,
+    pub emissive_texture: Option<texture::Texture>,
     pub bind_group: wgpu::BindGroup,
 }
 
+/// The parameters of a material that aren't textures, as stored on the GPU
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MaterialRaw {
+    /// `w` is 1 if the material has an emissive texture
+    emissive: Vector4,
+}
+
+unsafe impl bytemuck::Pod for MaterialRaw {}
+unsafe impl bytemuck::Zeroable for MaterialRaw {}
+
 impl Material {
+    /// A material that doesn't give off light
     pub fn new(
         device: &wgpu::Device,
         name: &str,
@@ -160,6 +181,38 @@ impl Material {
         normal_texture: texture::Texture,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        Self::with_emission(
+            device,
+            name,
+            diffuse_texture,
+            normal_texture,
+            Vector3::zero(),
+            None,
+            layout,
+        )
+    }
+
+    pub fn with_emission(
+        device: &wgpu::Device,
+        name: &str,
+        diffuse_texture: texture::Texture,
+        normal_texture: texture::Texture,
+        emissive: Vector3,
+        emissive_texture: Option<texture::Texture>,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let raw = MaterialRaw {
+            emissive: emissive.extend(emissive_texture.is_some() as u32 as f32),
+        };
+        let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Material"),
+            contents: bytemuck::bytes_of(&raw),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+
+        // Without an emissive texture the shader doesn't sample it, but something has to be bound
+        let emissive_binding = emissive_texture.as_ref().unwrap_or(&diffuse_texture);
+
         // A BindGroup is a more specific declaration of the BindGroupLayout.
         // The reason why these are separate is to allow us to swap out BindGroups on the fly,
         // so long as they all share the same BindGroupLayout.
@@ -182,6 +235,22 @@ impl Material {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&emissive_binding.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&emissive_binding.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniforms_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
             ],
             label: None,
         });
@@ -189,6 +258,8 @@ impl Material {
             name: String::from(name),
             diffuse_texture,
             normal_texture,
+            emissive,
+            emissive_texture,
             bind_group,
         }
     }
@@ -200,12 +271,329 @@ pub struct Mesh {
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    pub morph_targets: MorphTargets,
+
+    /// Model space bounds, large enough to contain the mesh for any morph weights in [0, 1]
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+
+    /// CPU copy of the geometry used for spatial queries. Morph targets are not applied.
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub triangle_bvh: Bvh,
+}
+
+/// A ray hit against a triangle of a mesh
+#[derive(Copy, Clone, Debug)]
+pub struct MeshHit {
+    pub triangle: usize,
+    pub distance: f32,
+    pub barycentric: (f32, f32),
+}
+
+impl Mesh {
+    pub fn new(
+        device: &wgpu::Device,
+        morph_layout: &wgpu::BindGroupLayout,
+        name: &str,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+        morph: MorphTargetData,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        let aabb = calculate_bounds(vertices, morph.targets);
+        let triangle_aabbs: Vec<Aabb> = indices
+            .chunks_exact(3)
+            .map(|c| {
+                Aabb::from_points(
+                    c.iter()
+                        .map(|&i| Point3::from_vec(vertices[i as usize].position)),
+                )
+            })
+            .collect();
+        let triangle_bvh = Bvh::build(&triangle_aabbs);
+        let morph_targets = MorphTargets::new(device, morph_layout, vertices.len() as u32, morph);
+
+        Mesh {
+            name: String::from(name),
+            vertex_buffer,
+            index_buffer,
+            num_elements: indices.len() as u32,
+            material,
+            morph_targets,
+            aabb,
+            bounding_sphere: BoundingSphere::from_aabb(&aabb),
+            vertices: vertices.to_vec(),
+            indices: indices.to_vec(),
+            triangle_bvh,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    /// Vertices of the given triangle, in model space
+    pub fn triangle(&self, index: usize) -> [&ModelVertex; 3] {
+        let i = &self.indices[index * 3..index * 3 + 3];
+        [
+            &self.vertices[i[0] as usize],
+            &self.vertices[i[1] as usize],
+            &self.vertices[i[2] as usize],
+        ]
+    }
+
+    fn triangle_positions(&self, index: usize) -> [Point3; 3] {
+        let [a, b, c] = self.triangle(index);
+        [
+            Point3::from_vec(a.position),
+            Point3::from_vec(b.position),
+            Point3::from_vec(c.position),
+        ]
+    }
+
+    /// Finds the closest triangle hit by a model space ray
+    pub fn cast_ray(&self, ray: &Ray, max_distance: f32) -> Option<MeshHit> {
+        let mut closest_t = max_distance;
+        let mut barycentric = (0.0, 0.0);
+        self.triangle_bvh
+            .cast_ray(ray, max_distance, |triangle| {
+                let [a, b, c] = self.triangle_positions(triangle);
+                let (t, u, v) = ray.intersect_triangle(a, b, c)?;
+                if t < closest_t {
+                    closest_t = t;
+                    barycentric = (u, v);
+                }
+                Some(t)
+            })
+            .map(|(triangle, distance)| MeshHit {
+                triangle,
+                distance,
+                barycentric,
+            })
+    }
+}
+
+fn calculate_bounds(vertices: &[ModelVertex], morph_targets: &[Vec<MorphTargetVertex>]) -> Aabb {
+    let mut aabb = Aabb::empty();
+    for (i, v) in vertices.iter().enumerate() {
+        // Expand the vertex by the sum of all negative and positive displacements, which covers
+        // every combination of weights between 0 and 1
+        let mut min = v.position;
+        let mut max = v.position;
+        for target in morph_targets {
+            let d = target[i].position.truncate();
+            min += Vector3::new(d.x.min(0.0), d.y.min(0.0), d.z.min(0.0));
+            max += Vector3::new(d.x.max(0.0), d.y.max(0.0), d.z.max(0.0));
+        }
+        aabb = aabb
+            .grow(Point3::from_vec(min))
+            .grow(Point3::from_vec(max));
+    }
+    aabb
+}
+
+/// Per-vertex displacement of a single morph target (blend shape)
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MorphTargetVertex {
+    // We store these as Vector4 because vectors require 16 byte alignment
+    pub position: Vector4,
+    pub normal: Vector4,
+    pub tangent: Vector4,
+}
+
+unsafe impl bytemuck::Pod for MorphTargetVertex {}
+unsafe impl bytemuck::Zeroable for MorphTargetVertex {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct MorphInfoRaw {
+    vertex_count: u32,
+    target_count: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for MorphInfoRaw {}
+unsafe impl bytemuck::Zeroable for MorphInfoRaw {}
+
+/// The morph targets of a mesh as loaded, before they're uploaded to a `MorphTargets`
+#[derive(Copy, Clone)]
+pub struct MorphTargetData<'a> {
+    pub targets: &'a [Vec<MorphTargetVertex>],
+    pub default_weights: &'a [f32],
+    /// Number of instances to store blend weights for. Instances past it must not be drawn
+    /// unless the mesh has no morph targets.
+    pub instance_count: u32,
+}
+
+impl<'a> MorphTargetData<'a> {
+    /// For a mesh without morph targets
+    pub fn none() -> Self {
+        MorphTargetData {
+            targets: &[],
+            default_weights: &[],
+            instance_count: 0,
+        }
+    }
+}
+
+/// Morph targets of a mesh, stored on the GPU together with the blend weights of each instance.
+///
+/// The deltas of all targets are stored back to back in a single storage buffer (target major),
+/// and the vertex shader blends them using the weights of the instance being drawn.
+pub struct MorphTargets {
+    pub target_count: u32,
+    /// Number of instances there are weights for
+    pub instance_count: u32,
+    pub default_weights: Vec<f32>,
+    pub deltas_buffer: wgpu::Buffer,
+    weights_buffer: wgpu::Buffer,
+    pub info_buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl MorphTargets {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        vertex_count: u32,
+        morph: MorphTargetData,
+    ) -> Self {
+        let MorphTargetData {
+            targets,
+            default_weights,
+            instance_count,
+        } = morph;
+        let target_count = targets.len() as u32;
+
+        // Storage buffers can't be empty, so meshes without morph targets get a single zeroed
+        // delta which is never read since the target count is zero
+        let mut deltas: Vec<MorphTargetVertex> = targets.iter().flatten().copied().collect();
+        if deltas.is_empty() {
+            deltas.push(bytemuck::Zeroable::zeroed());
+        }
+        let deltas_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Morph targets"),
+            contents: bytemuck::cast_slice(&deltas),
+            usage: wgpu::BufferUsage::STORAGE,
+        });
+
+        // Every instance starts out with the default weights of the mesh
+        let mut default_weights = default_weights.to_vec();
+        default_weights.resize(target_count as usize, 0.0);
+        let mut weights: Vec<f32> = (0..instance_count)
+            .flat_map(|_| default_weights.iter().copied())
+            .collect();
+        if weights.is_empty() {
+            weights.push(0.0);
+        }
+        let weights_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Morph weights"),
+            contents: bytemuck::cast_slice(&weights),
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let info = MorphInfoRaw {
+            vertex_count,
+            target_count,
+            _padding: [0; 2],
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Morph info"),
+            contents: bytemuck::cast_slice(&[info]),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &deltas_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &weights_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &info_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+            ],
+            label: Some("morph_bind_group"),
+        });
+
+        Self {
+            target_count,
+            instance_count,
+            default_weights,
+            deltas_buffer,
+            weights_buffer,
+            info_buffer,
+            bind_group,
+        }
+    }
+
+    /// Sets the blend weights used when drawing the given instance. Weights beyond the number of
+    /// morph targets are ignored.
+    pub fn upload_weights(&self, queue: &wgpu::Queue, instance: u32, weights: &[f32]) {
+        if self.target_count == 0 || instance >= self.instance_count {
+            return;
+        }
+        let count = weights.len().min(self.target_count as usize);
+        let offset = (instance * self.target_count) as u64 * std::mem::size_of::<f32>() as u64;
+        queue.write_buffer(
+            &self.weights_buffer,
+            offset,
+            bytemuck::cast_slice(&weights[..count]),
+        );
+    }
+
+    pub fn binding_size() -> Option<wgpu::BufferSize> {
+        wgpu::BufferSize::new(std::mem::size_of::<MorphInfoRaw>() as _)
+    }
 }
 
 impl Model {
+    pub fn new(meshes: Vec<Mesh>, materials: Vec<Material>) -> Self {
+        let aabb = meshes
+            .iter()
+            .fold(Aabb::empty(), |aabb, mesh| aabb.union(&mesh.aabb));
+        Model {
+            meshes,
+            materials,
+            aabb,
+            bounding_sphere: BoundingSphere::from_aabb(&aabb),
+        }
+    }
+
     pub fn load<P: AsRef<Path>>(
         device: &wgpu::Device,
         layout: &wgpu::BindGroupLayout,
+        morph_layout: &wgpu::BindGroupLayout,
         path: P,
     ) -> Result<(Self, Vec<wgpu::CommandBuffer>), anyhow::Error> {
         let (obj_models, obj_materials) = tobj::load_obj(path.as_ref())?;
@@ -232,11 +620,28 @@ impl Model {
                 texture::Texture::load(device, containing_folder.join(normal_path?), true)?;
             command_buffers.push(cmds);
 
-            materials.push(Material::new(
+            // tobj leaves the emission to the unknown parameters
+            let emissive = match mat.unknown_param.get("Ke") {
+                Some(v) => parse_mtl_color(v)?,
+                None => Vector3::zero(),
+            };
+            let emissive_texture = match mat.unknown_param.get("map_Ke") {
+                Some(v) => {
+                    let (texture, cmds) =
+                        texture::Texture::load(device, containing_folder.join(v), false)?;
+                    command_buffers.push(cmds);
+                    Some(texture)
+                }
+                None => None,
+            };
+
+            materials.push(Material::with_emission(
                 device,
                 &mat.name,
                 diffuse_texture,
                 normal_texture,
+                emissive,
+                emissive_texture,
                 layout,
             ));
         }
@@ -264,84 +669,311 @@ impl Model {
                 });
             }
 
-            // Calculate tangents and bitangents. We're going to
-            // use triangles, so we need to loop through the
-            // indices in chunks of 3
-            let indices = &m.mesh.indices;
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.tex_coords;
-                let uv1 = v1.tex_coords;
-                let uv2 = v2.tex_coords;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent = tangent;
-                vertices[c[1] as usize].tangent = tangent;
-                vertices[c[2] as usize].tangent = tangent;
-
-                vertices[c[0] as usize].bitangent = bitangent;
-                vertices[c[1] as usize].bitangent = bitangent;
-                vertices[c[2] as usize].bitangent = bitangent;
+            calculate_tangents(&mut vertices, &m.mesh.indices);
+
+            meshes.push(Mesh::new(
+                device,
+                morph_layout,
+                &m.name,
+                &vertices,
+                &m.mesh.indices,
+                m.mesh.material_id.unwrap_or(0),
+                MorphTargetData::none(),
+            ));
+        }
+
+        Ok((Self::new(meshes, materials), command_buffers))
+    }
+
+    /// Loads a glTF 2.0 model, including any morph targets of its meshes. Each primitive becomes
+    /// its own `Mesh`. Node transforms are not applied. Morph target weights are stored for
+    /// `instance_count` instances.
+    pub fn load_gltf<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        morph_layout: &wgpu::BindGroupLayout,
+        path: P,
+        instance_count: u32,
+    ) -> Result<(Self, Vec<wgpu::CommandBuffer>), anyhow::Error> {
+        let (document, buffers, images) = gltf::import(path.as_ref())?;
+
+        let mut command_buffers = Vec::new();
+
+        let load_texture = |texture: Option<gltf::Texture>, is_normal_map: bool| {
+            let img = texture
+                .and_then(|t| gltf_image_to_dynamic(&images[t.source().index()]))
+                .unwrap_or_else(|| {
+                    // Fall back to a white diffuse texture or a flat normal map
+                    let pixel = if is_normal_map {
+                        image::Rgba([128, 128, 255, 255])
+                    } else {
+                        image::Rgba([255, 255, 255, 255])
+                    };
+                    image::DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(1, 1, pixel))
+                });
+            texture::Texture::from_image(device, &img, None, is_normal_map)
+        };
+
+        let mut materials = Vec::new();
+        for mat in document.materials() {
+            let (diffuse_texture, cmds) = load_texture(
+                mat.pbr_metallic_roughness()
+                    .base_color_texture()
+                    .map(|info| info.texture()),
+                false,
+            )?;
+            command_buffers.push(cmds);
+            let (normal_texture, cmds) =
+                load_texture(mat.normal_texture().map(|info| info.texture()), true)?;
+            command_buffers.push(cmds);
+            let emissive_texture = match mat.emissive_texture() {
+                Some(info) => {
+                    let (texture, cmds) = load_texture(Some(info.texture()), false)?;
+                    command_buffers.push(cmds);
+                    Some(texture)
+                }
+                None => None,
+            };
+
+            materials.push(Material::with_emission(
+                device,
+                mat.name().unwrap_or("gltf material"),
+                diffuse_texture,
+                normal_texture,
+                mat.emissive_factor().into(),
+                emissive_texture,
+                layout,
+            ));
+        }
+
+        // Primitives without a material use a default one placed last
+        let default_material = materials.len();
+        let (diffuse_texture, cmds) = load_texture(None, false)?;
+        command_buffers.push(cmds);
+        let (normal_texture, cmds) = load_texture(None, true)?;
+        command_buffers.push(cmds);
+        materials.push(Material::new(
+            device,
+            "default",
+            diffuse_texture,
+            normal_texture,
+            layout,
+        ));
+
+        let mut meshes = Vec::new();
+        for mesh in document.meshes() {
+            let name = mesh.name().unwrap_or("gltf mesh");
+            let default_weights = mesh.weights().unwrap_or(&[]);
+
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()].0[..]));
+
+                let positions: Vec<[f32; 3]> = match reader.read_positions() {
+                    Some(positions) => positions.collect(),
+                    None => continue,
+                };
+                let vertex_count = positions.len();
+                let normals: Vec<[f32; 3]> = match reader.read_normals() {
+                    Some(normals) => normals.collect(),
+                    None => vec![[0.0, 1.0, 0.0]; vertex_count],
+                };
+                let tex_coords: Vec<[f32; 2]> = match reader.read_tex_coords(0) {
+                    Some(tex_coords) => tex_coords.into_f32().collect(),
+                    None => vec![[0.0, 0.0]; vertex_count],
+                };
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..vertex_count as u32).collect(),
+                };
+                // Meshes are drawn as triangle lists, so points and lines are left out
+                let indices = match triangle_list(primitive.mode(), indices) {
+                    Some(indices) => indices,
+                    None => continue,
+                };
+
+                let mut vertices: Vec<ModelVertex> = (0..vertex_count)
+                    .map(|i| ModelVertex {
+                        position: positions[i].into(),
+                        tex_coords: tex_coords[i].into(),
+                        normal: normals[i].into(),
+                        tangent: [0.0; 3].into(),
+                        bitangent: [0.0; 3].into(),
+                    })
+                    .collect();
+
+                match reader.read_tangents() {
+                    Some(tangents) => {
+                        // The w component stores the handedness of the tangent space
+                        for (v, t) in vertices.iter_mut().zip(tangents) {
+                            v.tangent = [t[0], t[1], t[2]].into();
+                            v.bitangent = v.normal.cross(v.tangent) * t[3];
+                        }
+                    }
+                    None => calculate_tangents(&mut vertices, &indices),
+                }
+
+                let morph_targets: Vec<Vec<MorphTargetVertex>> = reader
+                    .read_morph_targets()
+                    .map(|(positions, normals, tangents)| {
+                        let mut deltas = vec![
+                            MorphTargetVertex {
+                                position: Vector4::zero(),
+                                normal: Vector4::zero(),
+                                tangent: Vector4::zero(),
+                            };
+                            vertex_count
+                        ];
+                        if let Some(positions) = positions {
+                            for (d, p) in deltas.iter_mut().zip(positions) {
+                                d.position = Vector3::from(p).extend(0.0);
+                            }
+                        }
+                        if let Some(normals) = normals {
+                            for (d, n) in deltas.iter_mut().zip(normals) {
+                                d.normal = Vector3::from(n).extend(0.0);
+                            }
+                        }
+                        if let Some(tangents) = tangents {
+                            for (d, t) in deltas.iter_mut().zip(tangents) {
+                                d.tangent = Vector3::from(t).extend(0.0);
+                            }
+                        }
+                        deltas
+                    })
+                    .collect();
+
+                meshes.push(Mesh::new(
+                    device,
+                    morph_layout,
+                    name,
+                    &vertices,
+                    &indices,
+                    primitive.material().index().unwrap_or(default_material),
+                    MorphTargetData {
+                        targets: &morph_targets,
+                        default_weights,
+                        instance_count,
+                    },
+                ));
             }
+        }
+
+        Ok((Self::new(meshes, materials), command_buffers))
+    }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
-
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            });
+    /// Sets the morph target weights of an instance for every mesh in the model that has morph
+    /// targets.
+    pub fn upload_morph_weights(&self, queue: &wgpu::Queue, instance: u32, weights: &[f32]) {
+        for mesh in &self.meshes {
+            mesh.morph_targets.upload_weights(queue, instance, weights);
         }
+    }
+}
+
+fn gltf_image_to_dynamic(data: &gltf::image::Data) -> Option<image::DynamicImage> {
+    let pixels = data.pixels.clone();
+    match data.format {
+        gltf::image::Format::R8G8B8A8 => {
+            image::RgbaImage::from_raw(data.width, data.height, pixels)
+                .map(image::DynamicImage::ImageRgba8)
+        }
+        gltf::image::Format::R8G8B8 => image::RgbImage::from_raw(data.width, data.height, pixels)
+            .map(image::DynamicImage::ImageRgb8),
+        _ => None,
+    }
+}
+
+/// Turns the indices of a glTF primitive into a triangle list, or `None` for points and lines
+fn triangle_list(mode: gltf::mesh::Mode, indices: Vec<u32>) -> Option<Vec<u32>> {
+    use gltf::mesh::Mode;
+    let triangle_count = indices.len().saturating_sub(2);
+    let mut triangles = Vec::with_capacity(triangle_count * 3);
+    match mode {
+        Mode::Triangles => return Some(indices),
+        Mode::TriangleStrip => {
+            // Every other triangle of a strip is flipped to keep the winding
+            for i in 0..triangle_count {
+                let (a, b) = if i % 2 == 0 { (i, i + 1) } else { (i + 1, i) };
+                triangles.extend_from_slice(&[indices[a], indices[b], indices[i + 2]]);
+            }
+        }
+        Mode::TriangleFan => {
+            for i in 0..triangle_count {
+                triangles.extend_from_slice(&[indices[0], indices[i + 1], indices[i + 2]]);
+            }
+        }
+        Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => return None,
+    }
+    Some(triangles)
+}
+
+/// Parses an MTL color like `Ke 1.0 0.5 0.0`, where a single value stands for all three
+fn parse_mtl_color(value: &str) -> Result<Vector3, anyhow::Error> {
+    let values = value
+        .split_whitespace()
+        .map(|v| v.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [v] => Ok(Vector3::new(v, v, v)),
+        [r, g, b] => Ok(Vector3::new(r, g, b)),
+        _ => Err(anyhow::anyhow!("Invalid color: {}", value)),
+    }
+}
+
+// Calculate tangents and bitangents. We're going to use triangles, so we need to loop through the
+// indices in chunks of 3
+fn calculate_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    for c in indices.chunks_exact(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        let pos0 = v0.position;
+        let pos1 = v1.position;
+        let pos2 = v2.position;
+
+        let uv0 = v0.tex_coords;
+        let uv1 = v1.tex_coords;
+        let uv2 = v2.tex_coords;
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+
+        // This will give us a direction to calculate the
+        // tangent and bitangent
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
 
-        Ok((Self { meshes, materials }, command_buffers))
+        // Solving the following system of equations will
+        // give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        // Luckily, the place I found this equation provided
+        // the solution!
+        let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+
+        // We'll use the same tangent/bitangent for each vertex in the triangle
+        vertices[c[0] as usize].tangent = tangent;
+        vertices[c[1] as usize].tangent = tangent;
+        vertices[c[2] as usize].tangent = tangent;
+
+        vertices[c[0] as usize].bitangent = bitangent;
+        vertices[c[1] as usize].bitangent = bitangent;
+        vertices[c[2] as usize].bitangent = bitangent;
     }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct ModelVertex {
-    position: Vector3,
-    tex_coords: cgmath::Vector2<f32>,
-    normal: Vector3,
-    tangent: Vector3,
-    bitangent: Vector3,
+    pub position: Vector3,
+    pub tex_coords: cgmath::Vector2<f32>,
+    pub normal: Vector3,
+    pub tangent: Vector3,
+    pub bitangent: Vector3,
 }
 
 unsafe impl bytemuck::Pod for ModelVertex {}
@@ -391,22 +1023,99 @@ impl Vertex for ModelVertex {
 pub struct Instance {
     pub position: Vector3,
     pub rotation: cgmath::Quaternion<f32>,
+    pub mobility: Mobility,
+    pub casts_shadows: bool,
+    pub receives_shadows: bool,
+}
+
+/// Whether something is expected to change from frame to frame. Shadow maps cache static lights
+/// and shadow casters, and only re-render them when they change.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mobility {
+    Static,
+    Dynamic,
 }
 
 impl Instance {
     pub fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: Matrix4::from_translation(self.position)
-                * Matrix4::from(self.rotation),
+        let mut flags = 0;
+        if self.casts_shadows {
+            flags |= INSTANCE_CASTS_SHADOWS;
+        }
+        if self.receives_shadows {
+            flags |= INSTANCE_RECEIVES_SHADOWS;
         }
+        InstanceRaw::new(
+            Matrix4::from_translation(self.position) * Matrix4::from(self.rotation),
+            flags,
+        )
     }
 }
 
+/// Bits of `InstanceRaw::flags`
+pub const INSTANCE_CASTS_SHADOWS: u32 = 1;
+pub const INSTANCE_RECEIVES_SHADOWS: u32 = 2;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct InstanceRaw {
     pub model: Matrix4,
+    pub flags: u32,
+    /// Scale of the color of unlit instances, such as billboards
+    pub emission: f32,
+    _padding: [u32; 2],
+}
+
+impl InstanceRaw {
+    pub fn new(model: Matrix4, flags: u32) -> Self {
+        Self {
+            model,
+            flags,
+            emission: 1.0,
+            _padding: [0; 2],
+        }
+    }
 }
 
 unsafe impl bytemuck::Pod for InstanceRaw {}
 unsafe impl bytemuck::Zeroable for InstanceRaw {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gltf::mesh::Mode;
+
+    #[test]
+    fn triangle_list_converts_strips_and_fans() {
+        let indices = vec![0, 1, 2, 3, 4];
+        assert_eq!(
+            triangle_list(Mode::Triangles, indices.clone()),
+            Some(indices.clone())
+        );
+        assert_eq!(
+            triangle_list(Mode::TriangleStrip, indices.clone()),
+            Some(vec![0, 1, 2, 2, 1, 3, 2, 3, 4])
+        );
+        assert_eq!(
+            triangle_list(Mode::TriangleFan, indices.clone()),
+            Some(vec![0, 1, 2, 0, 2, 3, 0, 3, 4])
+        );
+        assert_eq!(triangle_list(Mode::Lines, indices.clone()), None);
+        assert_eq!(triangle_list(Mode::Points, indices), None);
+        assert_eq!(triangle_list(Mode::TriangleStrip, vec![0, 1]), Some(vec![]));
+    }
+
+    #[test]
+    fn calculate_tangents_ignores_a_partial_triangle() {
+        let vertex = |x: f32, y: f32| ModelVertex {
+            position: Vector3::new(x, y, 0.0),
+            tex_coords: cgmath::Vector2::new(x, y),
+            normal: Vector3::unit_z(),
+            tangent: Vector3::zero(),
+            bitangent: Vector3::zero(),
+        };
+        let mut vertices = vec![vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0)];
+        calculate_tangents(&mut vertices, &[0, 1, 2, 0]);
+        assert!((vertices[1].tangent - Vector3::unit_x()).magnitude() < 1e-5);
+    }
+}
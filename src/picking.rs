@@ -0,0 +1,439 @@
+use crate::bounds::Ray;
+use crate::bvh::{InstanceRef, SceneBvh, SceneHit};
+use crate::camera::ScreenSpace;
+use crate::geometry::Vertex;
+use crate::model;
+use crate::prelude::*;
+use crate::texture;
+use crate::Context;
+use crate::{compile_frag, compile_vertex};
+use std::num::NonZeroU64;
+use std::ops::Range;
+
+/// The maximum number of draws (meshes) the id buffer can distinguish per frame
+const MAX_PICK_DRAWS: u64 = 1024;
+const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Uint;
+
+/// Result of picking what's under the cursor
+#[derive(Copy, Clone, Debug)]
+pub struct PickHit {
+    pub model: usize,
+    pub mesh: usize,
+    pub instance: usize,
+    /// World space position of the hit
+    pub position: Point3,
+    /// World space surface normal, interpolated from the vertex normals
+    pub normal: Vector3,
+    pub tex_coords: cgmath::Vector2<f32>,
+    /// Distance from the ray origin
+    pub distance: f32,
+}
+
+/// Finds the closest triangle under a world space ray. The models must be the ones the scene was
+/// built from.
+pub fn pick(scene: &SceneBvh, models: &[&model::Model], ray: &Ray) -> Option<PickHit> {
+    scene
+        .cast_ray(ray, f32::INFINITY, models)
+        .map(|hit| hit_details(scene, models, ray, &hit))
+}
+
+fn hit_details(
+    scene: &SceneBvh,
+    models: &[&model::Model],
+    ray: &Ray,
+    hit: &SceneHit,
+) -> PickHit {
+    let mesh = &models[hit.instance.model].meshes[hit.mesh];
+    let [a, b, c] = mesh.triangle(hit.triangle);
+    let (u, v) = hit.barycentric;
+    let w = 1.0 - u - v;
+
+    // Normals are transformed with the inverse transpose to stay correct under non-uniform scale
+    let transform = scene.instance_transform(hit.instance);
+    let normal_matrix = transform
+        .invert()
+        .unwrap_or_else(Matrix4::identity)
+        .transpose();
+    let normal = a.normal * w + b.normal * u + c.normal * v;
+    let normal = (normal_matrix * normal.extend(0.0)).truncate().normalize();
+
+    PickHit {
+        model: hit.instance.model,
+        mesh: hit.mesh,
+        instance: hit.instance.instance,
+        position: ray.at(hit.distance),
+        normal,
+        tex_coords: a.tex_coords * w + b.tex_coords * u + c.tex_coords * v,
+        distance: hit.distance,
+    }
+}
+
+/// Something to draw into the id buffer
+pub struct PickTarget<'a> {
+    /// Index of the model in the slice the scene was built from
+    pub model_index: usize,
+    pub model: &'a model::Model,
+    pub instances: Range<u32>,
+    pub instances_bind_group: &'a wgpu::BindGroup,
+}
+
+/// Pixel-exact picking by rendering the ids of everything visible into an offscreen texture and
+/// reading back the pixel under the cursor.
+pub struct IdBufferPicker {
+    pipeline: wgpu::RenderPipeline,
+    id_texture: wgpu::Texture,
+    id_view: wgpu::TextureView,
+    depth_texture: texture::Texture,
+    ids_buffer: wgpu::Buffer,
+    ids_bind_group: wgpu::BindGroup,
+    readback_buffer: wgpu::Buffer,
+
+    /// (model, mesh) of each draw in the last rendered id buffer, indexed by draw id - 1
+    draws: Vec<(usize, usize)>,
+    /// Meshes left out of the last id buffer, past the first `MAX_PICK_DRAWS`. They can't be
+    /// picked.
+    pub skipped_draws: usize,
+}
+
+impl IdBufferPicker {
+    pub fn new(context: &mut Context, uniform_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let device = &context.device;
+
+        let ids_binding_size = NonZeroU64::new(std::mem::size_of::<u32>() as u64);
+        let ids_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Pick ids"),
+            size: MAX_PICK_DRAWS * wgpu::BIND_BUFFER_ALIGNMENT,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let ids_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: true,
+                        min_binding_size: ids_binding_size,
+                    },
+                    count: None,
+                }],
+                label: Some("Pick ids bind group layout"),
+            });
+        let ids_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &ids_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &ids_buffer,
+                    offset: 0,
+                    size: ids_binding_size,
+                },
+            }],
+            label: Some("Pick ids bind group"),
+        });
+
+        // Room for a single pixel, padded to the required row alignment
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Pick readback"),
+            size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Pick pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[
+                uniform_bind_group_layout,
+                &context.instances_bind_group_layout,
+                &context.morph_bind_group_layout,
+                &ids_bind_group_layout,
+            ],
+        });
+
+        let vs_module =
+            compile_vertex!(&context.device, &mut context.shader_compiler, "pick.vert").unwrap();
+        let fs_module =
+            compile_frag!(&context.device, &mut context.shader_compiler, "pick.frag").unwrap();
+
+        // Integer targets can't be blended, so we can't use `pipeline::create` here
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("pick"),
+                layout: Some(&layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &vs_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &fs_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::Back,
+                    ..Default::default()
+                }),
+                color_states: &[wgpu::ColorStateDescriptor {
+                    format: ID_FORMAT,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                    format: texture::Texture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilStateDescriptor::default(),
+                }),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[model::ModelVertex::desc()],
+                },
+                sample_count: 1,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            });
+
+        let (id_texture, id_view) = create_id_texture(&context.device, &context.sc_desc);
+        let depth_texture = texture::Texture::create_depth_texture(
+            &context.device,
+            &context.sc_desc,
+            1,
+            "pick_depth",
+        );
+
+        IdBufferPicker {
+            pipeline,
+            id_texture,
+            id_view,
+            depth_texture,
+            ids_buffer,
+            ids_bind_group,
+            readback_buffer,
+            draws: Vec::new(),
+            skipped_draws: 0,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
+        let (id_texture, id_view) = create_id_texture(device, sc_desc);
+        self.id_texture = id_texture;
+        self.id_view = id_view;
+        self.depth_texture =
+            texture::Texture::create_depth_texture(device, sc_desc, 1, "pick_depth");
+    }
+
+    /// Renders the ids of the targets into the id buffer
+    pub fn render(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        uniforms: &wgpu::BindGroup,
+        targets: &[PickTarget],
+    ) {
+        self.draws.clear();
+        self.skipped_draws = 0;
+        for target in targets {
+            for mesh_index in 0..target.model.meshes.len() {
+                if self.draws.len() as u64 >= MAX_PICK_DRAWS {
+                    self.skipped_draws += 1;
+                    continue;
+                }
+                let draw_id = self.draws.len() as u32 + 1;
+                queue.write_buffer(
+                    &self.ids_buffer,
+                    self.draws.len() as u64 * wgpu::BIND_BUFFER_ALIGNMENT,
+                    bytemuck::bytes_of(&draw_id),
+                );
+                self.draws.push((target.model_index, mesh_index));
+            }
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &self.id_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, uniforms, &[]);
+
+        let mut draw_index = 0;
+        for target in targets {
+            render_pass.set_bind_group(1, target.instances_bind_group, &[]);
+            for mesh in &target.model.meshes {
+                if draw_index >= self.draws.len() {
+                    return;
+                }
+                let offset = (draw_index as u64 * wgpu::BIND_BUFFER_ALIGNMENT) as wgpu::DynamicOffset;
+                render_pass.set_bind_group(2, &mesh.morph_targets.bind_group, &[]);
+                render_pass.set_bind_group(3, &self.ids_bind_group, &[offset]);
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..));
+                render_pass.draw_indexed(0..mesh.num_elements, 0, target.instances.clone());
+                draw_index += 1;
+            }
+        }
+    }
+
+    /// Reads back what was rendered at the given pixel of the last id buffer. Returns the
+    /// (model, mesh, instance) and the depth buffer value at that pixel, or `None` if the pixel is
+    /// outside the id buffer. This blocks until the GPU has finished rendering the id buffer.
+    pub fn read(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        x: u32,
+        y: u32,
+    ) -> Option<(InstanceRef, usize, f32)> {
+        // The id buffer has the size of the depth texture
+        let size = self.depth_texture.size;
+        if x >= size.width || y >= size.height {
+            return None;
+        }
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.id_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.readback_buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT,
+                    rows_per_image: 1,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = self.readback_buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        if futures::executor::block_on(mapping).is_err() {
+            return None;
+        }
+
+        let pixel: [u32; 4] = {
+            let data = slice.get_mapped_range();
+            let mut pixel = [0u32; 4];
+            pixel.copy_from_slice(bytemuck::cast_slice(&data[..16]));
+            pixel
+        };
+        self.readback_buffer.unmap();
+
+        let draw_id = pixel[0] as usize;
+        if draw_id == 0 || draw_id > self.draws.len() {
+            return None;
+        }
+        let (model, mesh) = self.draws[draw_id - 1];
+        let instance = InstanceRef {
+            model,
+            instance: pixel[1] as usize,
+        };
+        Some((instance, mesh, f32::from_bits(pixel[2])))
+    }
+
+    /// Picks using the last rendered id buffer. The instance and mesh come straight from the id
+    /// buffer, and the remaining hit details from a ray cast against that mesh only.
+    pub fn pick(
+        &self,
+        context: &Context,
+        x: u32,
+        y: u32,
+        screen: &ScreenSpace,
+        scene: &SceneBvh,
+        models: &[&model::Model],
+    ) -> Option<PickHit> {
+        let (instance, mesh_index, depth) = self.read(&context.device, &context.queue, x, y)?;
+
+        // Reconstruct the exact position from the depth buffer
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let position = screen.unproject(px, py, depth);
+        let ray = screen.view_ray(px, py);
+        let distance = (position - ray.origin).magnitude();
+
+        // Use the triangle under the pixel for normal and texture coordinates. The ray can miss
+        // right at the silhouette, in which case we fall back to facing the camera.
+        let mesh = &models[instance.model].meshes[mesh_index];
+        let local_ray = ray.transform(
+            &scene
+                .instance_transform(instance)
+                .invert()
+                .unwrap_or_else(Matrix4::identity),
+        );
+        match mesh.cast_ray(&local_ray, f32::INFINITY) {
+            Some(hit) => Some(PickHit {
+                position,
+                distance,
+                ..hit_details(
+                    scene,
+                    models,
+                    &ray,
+                    &SceneHit {
+                        instance,
+                        mesh: mesh_index,
+                        triangle: hit.triangle,
+                        distance: hit.distance,
+                        barycentric: hit.barycentric,
+                    },
+                )
+            }),
+            None => Some(PickHit {
+                model: instance.model,
+                mesh: mesh_index,
+                instance: instance.instance,
+                position,
+                normal: -ray.direction,
+                tex_coords: cgmath::Vector2::new(0.0, 0.0),
+                distance,
+            }),
+        }
+    }
+}
+
+fn create_id_texture(
+    device: &wgpu::Device,
+    sc_desc: &wgpu::SwapChainDescriptor,
+) -> (wgpu::Texture, wgpu::TextureView) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("pick_ids"),
+        size: wgpu::Extent3d {
+            width: sc_desc.width,
+            height: sc_desc.height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ID_FORMAT,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
@@ -3,15 +3,33 @@ pub struct DepthConfig {
     bias: i32,
     bias_slope_scale: f32,
     bias_clamp: f32,
+    compare: wgpu::CompareFunction,
+    write_enabled: bool,
 }
 
 impl DepthConfig {
+    pub fn with_format(self, format: wgpu::TextureFormat) -> Self {
+        DepthConfig { format, ..self }
+    }
+
+    /// Tests against the depth buffer without writing to it, passing at equal depth. For drawing
+    /// behind the geometry at the far plane.
+    pub fn read_only(self) -> Self {
+        DepthConfig {
+            compare: wgpu::CompareFunction::LessEqual,
+            write_enabled: false,
+            ..self
+        }
+    }
+
     pub fn no_bias() -> Self {
         DepthConfig {
             format: wgpu::TextureFormat::Depth32Float,
             bias: 0,
             bias_slope_scale: 0.0,
             bias_clamp: 0.0,
+            compare: wgpu::CompareFunction::Less,
+            write_enabled: true,
         }
     }
 }
@@ -23,10 +41,15 @@ impl Default for DepthConfig {
             bias: 2, // corresponds to bilinear filtering
             bias_slope_scale: 2.0,
             bias_clamp: 0.0,
+            compare: wgpu::CompareFunction::Less,
+            write_enabled: true,
         }
     }
 }
 
+/// `sample_count` has to match the targets the pipeline renders into, 1 unless they're
+/// multisampled
+#[allow(clippy::too_many_arguments)]
 pub fn create(
     name: &str,
     device: &wgpu::Device,
@@ -35,6 +58,7 @@ pub fn create(
     fs_module: &wgpu::ShaderModule,
     color_format: Option<wgpu::TextureFormat>,
     depth_config: Option<DepthConfig>,
+    sample_count: u32,
     vertex_descs: &[wgpu::VertexBufferDescriptor],
 ) -> wgpu::RenderPipeline {
     let mut rasterization_state = wgpu::RasterizationStateDescriptor {
@@ -86,15 +110,15 @@ pub fn create(
         primitive_topology: wgpu::PrimitiveTopology::TriangleList,
         depth_stencil_state: depth_config.map(|config| wgpu::DepthStencilStateDescriptor {
             format: config.format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
+            depth_write_enabled: config.write_enabled,
+            depth_compare: config.compare,
             stencil: wgpu::StencilStateDescriptor::default(),
         }),
         vertex_state: wgpu::VertexStateDescriptor {
             index_format: wgpu::IndexFormat::Uint32,
             vertex_buffers: vertex_descs,
         },
-        sample_count: 1,
+        sample_count,
         sample_mask: !0,
         alpha_to_coverage_enabled: false,
     })
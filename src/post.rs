@@ -0,0 +1,243 @@
+use crate::compile_vertex;
+use crate::texture;
+use crate::Context;
+use std::ops::RangeInclusive;
+
+/// Draws a single triangle covering the whole target (see `fullscreen.vert`), for passes that
+/// run a fragment shader over every pixel
+pub struct Fullscreen {
+    vs_module: wgpu::ShaderModule,
+}
+
+impl Fullscreen {
+    pub fn new(device: &wgpu::Device, compiler: &mut shaderc::Compiler) -> Self {
+        let vs_module = compile_vertex!(device, compiler, "fullscreen.vert").unwrap();
+        Fullscreen { vs_module }
+    }
+
+    /// A pipeline drawing `fs_module` over the whole target. The fragment shader gets the texture
+    /// coordinates of the target in location 0.
+    pub fn create_pipeline(
+        &self,
+        name: &str,
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        fs_module: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+        blend: wgpu::BlendDescriptor,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(name),
+            layout: Some(layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &self.vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: fs_module,
+                entry_point: "main",
+            }),
+            // The triangle is clockwise
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                ..Default::default()
+            }),
+            color_states: &[wgpu::ColorStateDescriptor {
+                format,
+                color_blend: blend.clone(),
+                alpha_blend: blend,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        })
+    }
+
+    /// Runs a pipeline made by `create_pipeline` over the whole of `output`, with the bind groups
+    /// in set order
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        load: wgpu::LoadOp<wgpu::Color>,
+        pipeline: &wgpu::RenderPipeline,
+        bind_groups: &[&wgpu::BindGroup],
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        for (index, bind_group) in bind_groups.iter().enumerate() {
+            render_pass.set_bind_group(index as u32, bind_group, &[]);
+        }
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// What an effect reads and writes in a frame
+pub struct PostFrame<'a> {
+    pub device: &'a wgpu::Device,
+    pub queue: &'a wgpu::Queue,
+    pub fullscreen: &'a Fullscreen,
+    /// Color so far: the scene, or the output of the previous effect
+    pub source: &'a texture::Texture,
+    /// Depth of the scene, as rendered by the forward pass
+    pub depth: &'a texture::Texture,
+    /// Where to write the result. It's never `source` but it's undefined, so every pixel has to
+    /// be written.
+    pub output: &'a texture::Texture,
+}
+
+/// A setting of an effect, tweakable in the debug UI
+pub struct Parameter<'a> {
+    pub name: &'static str,
+    pub value: &'a mut f32,
+    /// Of sensible values
+    pub range: RangeInclusive<f32>,
+}
+
+impl<'a> Parameter<'a> {
+    pub fn new(name: &'static str, value: &'a mut f32, range: RangeInclusive<f32>) -> Self {
+        Parameter { name, value, range }
+    }
+}
+
+/// A full screen pass over the HDR color of the scene, run by `PostStack`. The source and output
+/// depend on which other effects are enabled and change on resize, so bind groups referring to
+/// them are usually created in `render`.
+pub trait Effect {
+    /// Shown in the debug UI
+    fn name(&self) -> &str;
+
+    /// The settings of the effect
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        Vec::new()
+    }
+
+    /// Called when the swap chain, and so the scene and every target, changes size
+    fn resize(&mut self, _device: &wgpu::Device, _sc_desc: &wgpu::SwapChainDescriptor) {}
+
+    fn render(&mut self, encoder: &mut wgpu::CommandEncoder, frame: &PostFrame);
+}
+
+pub struct PostEffect {
+    pub enabled: bool,
+    pub effect: Box<dyn Effect>,
+}
+
+/// Effects applied in order to the HDR color of the scene, before tonemapping. They ping-pong
+/// between two targets the size of the swap chain, and the result is left in the scene texture.
+pub struct PostStack {
+    pub effects: Vec<PostEffect>,
+    fullscreen: Fullscreen,
+    targets: [texture::Texture; 2],
+}
+
+impl PostStack {
+    pub fn new(context: &mut Context) -> Self {
+        let fullscreen = Fullscreen::new(&context.device, &mut context.shader_compiler);
+        PostStack {
+            effects: Vec::new(),
+            fullscreen,
+            targets: create_targets(&context.device, &context.sc_desc),
+        }
+    }
+
+    /// For effects to create their pipelines with
+    pub fn fullscreen(&self) -> &Fullscreen {
+        &self.fullscreen
+    }
+
+    /// Adds an enabled effect after the others
+    pub fn push(&mut self, effect: Box<dyn Effect>) {
+        self.effects.push(PostEffect {
+            enabled: true,
+            effect,
+        });
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
+        self.targets = create_targets(device, sc_desc);
+        for effect in &mut self.effects {
+            effect.effect.resize(device, sc_desc);
+        }
+    }
+
+    /// Applies the enabled effects to `scene`, which must be the size of the swap chain
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        scene: &texture::Texture,
+        depth: &texture::Texture,
+    ) {
+        let PostStack {
+            effects,
+            fullscreen,
+            targets,
+        } = self;
+        let enabled: Vec<&mut PostEffect> =
+            effects.iter_mut().filter(|effect| effect.enabled).collect();
+        let count = enabled.len();
+
+        // The first effect reads the scene and the last one writes back into it, unless it's
+        // also the first
+        for (i, effect) in enabled.into_iter().enumerate() {
+            let source = if i == 0 { scene } else { &targets[(i - 1) % 2] };
+            let output = if i > 0 && i == count - 1 {
+                scene
+            } else {
+                &targets[i % 2]
+            };
+            let frame = PostFrame {
+                device,
+                queue,
+                fullscreen,
+                source,
+                depth,
+                output,
+            };
+            effect.effect.render(encoder, &frame);
+        }
+
+        if count == 1 {
+            encoder.copy_texture_to_texture(
+                wgpu::TextureCopyView {
+                    texture: &targets[0].texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                wgpu::TextureCopyView {
+                    texture: &scene.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                scene.size,
+            );
+        }
+    }
+}
+
+fn create_targets(
+    device: &wgpu::Device,
+    sc_desc: &wgpu::SwapChainDescriptor,
+) -> [texture::Texture; 2] {
+    [
+        texture::Texture::create_hdr_texture(device, sc_desc, "post_target_0"),
+        texture::Texture::create_hdr_texture(device, sc_desc, "post_target_1"),
+    ]
+}
@@ -1,69 +1,488 @@
 use crate::camera;
+use crate::bounds::{BoundingSphere, Frustum};
+use crate::camera::Projection;
+use crate::bvh;
+use crate::geometry;
+use crate::geometry::Vertex;
 use crate::light;
 use crate::model;
 use crate::pipeline;
 use crate::prelude::*;
 use crate::{compile_frag, compile_vertex};
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::mem;
-use std::num::{NonZeroU32, NonZeroU64};
+use std::num::NonZeroU64;
 use std::ops::Range;
 
-const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-pub const SHADOW_SIZE: wgpu::Extent3d = wgpu::Extent3d {
-    width: 1024,
-    height: 1024,
-    depth: 1,
-};
+/// How shadow maps are rendered and sampled. `Lights::shadow_settings` applies to all lights, and
+/// each light can override it with `Light::shadow_settings`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    /// Largest width and height of each shadow map (or cube face) in the atlas. Lights covering
+    /// less of the view get smaller shadow maps, down to `MIN_SHADOW_RESOLUTION`, and the largest
+    /// shadow maps are scaled down further when they don't all fit the atlas. Rounded down to a
+    /// power of two.
+    pub resolution: u32,
+    /// Width and height of the shadow atlas all shadow maps are packed into. It's shared by all
+    /// lights, so only the global setting is used. Rounded down to a power of two (see
+    /// `rounded_atlas_size`).
+    pub atlas_size: u32,
+    /// Depth format of the shadow atlas. Like `atlas_size`, only the global setting is used.
+    pub format: wgpu::TextureFormat,
+    /// Near and far plane of point and spot light projections. Their shadow maps store the
+    /// distance to the light as a fraction of `far`.
+    pub near: f32,
+    pub far: f32,
+    /// Distance (in world units) a fragment has to be behind the closest occluder to be in shadow,
+    /// which keeps surfaces from shadowing themselves
+    pub bias: f32,
+}
 
-pub struct ShadowMapTarget {
-    pub texture: wgpu::Texture,
-    pub view: wgpu::TextureView,
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 1024,
+            atlas_size: 4096,
+            format: wgpu::TextureFormat::Depth32Float,
+            near: 0.1,
+            far: 100.0,
+            bias: 0.15,
+        }
+    }
 }
 
-pub struct ShadowCubemap {
+impl ShadowSettings {
+    /// `atlas_size` rounded down to a power of two, and no smaller than a single shadow map, which
+    /// is the size the atlas is allocated with
+    pub fn rounded_atlas_size(&self) -> u32 {
+        floor_power_of_two(self.atlas_size.max(MIN_SHADOW_RESOLUTION))
+    }
+}
+
+/// Smallest shadow map (or cube face) the atlas hands out. Lights that don't fit the atlas even at
+/// this resolution get no shadows.
+pub const MIN_SHADOW_RESOLUTION: u32 = 64;
+
+/// Number of cascades each directional light splits the view into. The forward shader stores the
+/// split depths in a vec4, so this can't be raised beyond 4.
+pub const CASCADE_COUNT: usize = 4;
+
+/// Distance from the camera covered by the cascades. Beyond that there are no shadows from
+/// directional lights.
+const CASCADE_MAX_DISTANCE: f32 = 50.0;
+
+/// Blend between logarithmic (1.0) and uniform (0.0) cascade splits
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+/// How far behind each cascade shadow casters are picked up, in the direction of the light
+const CASCADE_CASTER_DISTANCE: f32 = 50.0;
+
+/// Format of the depth moments the variance shadow filters sample (see `vsm::VsmPass`). It's
+/// filterable, and precise enough for the exponents of the exponential variant.
+pub const MOMENTS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// A single large texture the shadow maps of all lights are packed into (see `pack_atlas`). Point
+/// lights take six regions, one per cube face, directional lights one per cascade and spot lights
+/// a single one. Variance filters keep the moments of the shadow maps in a second atlas with the
+/// same layout.
+pub struct ShadowAtlas {
     pub texture: wgpu::Texture,
     pub texture_view: wgpu::TextureView,
+    /// Width and height
+    pub size: u32,
+    pub format: wgpu::TextureFormat,
 }
 
-impl ShadowCubemap {
-    pub fn new(device: &wgpu::Device) -> Self {
+impl ShadowAtlas {
+    pub fn new(device: &wgpu::Device, size: u32, format: wgpu::TextureFormat) -> Self {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             size: wgpu::Extent3d {
-                width: 1024,
-                height: 1024,
-                depth: 6,
+                width: size,
+                height: size,
+                depth: 1,
             },
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: SHADOW_FORMAT,
-            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
-            label: None,
-        });
-
-        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-            label: Some("Shadow"),
-            format: None,
-            dimension: Some(wgpu::TextureViewDimension::Cube),
-            aspect: wgpu::TextureAspect::All,
-            base_mip_level: 0,
-            level_count: None,
-            base_array_layer: 0,
-            array_layer_count: NonZeroU32::new(1),
+            format,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            label: Some("Shadow atlas"),
         });
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
 
         Self {
             texture,
             texture_view,
+            size,
+            format,
         }
     }
+
+    /// Placeholder for the moments atlas while no variance filter is in use
+    pub fn empty(device: &wgpu::Device) -> Self {
+        Self::new(device, 1, MOMENTS_FORMAT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 1
+    }
+}
+
+/// A square region of the shadow atlas, in texels
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+impl AtlasRect {
+    pub fn overlaps(&self, other: &AtlasRect) -> bool {
+        self.x < other.x + other.size
+            && other.x < self.x + self.size
+            && self.y < other.y + other.size
+            && other.y < self.y + self.size
+    }
+
+    /// Offset (xy) and size (zw) of the region in texture coordinates of the atlas
+    pub fn uv(&self, atlas_size: u32) -> Vector4 {
+        let scale = 1.0 / atlas_size as f32;
+        Vector4::new(
+            self.x as f32 * scale,
+            self.y as f32 * scale,
+            self.size as f32 * scale,
+            self.size as f32 * scale,
+        )
+    }
+}
+
+/// The shadow maps a light needs, see `pack_atlas`
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasRequest {
+    /// Number of shadow maps, which all share the same resolution
+    pub count: usize,
+    /// Width and height of each shadow map. Rounded down to a power of two.
+    pub resolution: u32,
+    /// How much the light's shadows matter. Less important lights are scaled down first when the
+    /// shadow maps don't fit.
+    pub importance: f32,
+}
+
+/// Packs the shadow maps of the requests into an atlas of the given size, returning the regions of
+/// each request in order. If they don't all fit, the largest shadow maps are halved (those of the
+/// least important request first), and requests that still don't fit at `MIN_SHADOW_RESOLUTION`
+/// are left without regions. The atlas size and the resolutions are rounded down to powers of two.
+pub fn pack_atlas(atlas_size: u32, requests: &[AtlasRequest]) -> Vec<Vec<AtlasRect>> {
+    let atlas_size = floor_power_of_two(atlas_size);
+    let mut order: Vec<usize> = (0..requests.len()).collect();
+    order.sort_by(|&a, &b| {
+        requests[b]
+            .importance
+            .partial_cmp(&requests[a].importance)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    // Resolutions in order of importance, so that ties are broken in favour of the more
+    // important requests
+    let mut resolutions: Vec<u32> = order
+        .iter()
+        .map(|&i| floor_power_of_two(requests[i].resolution).min(atlas_size))
+        .collect();
+    let area = |resolutions: &[u32]| -> u64 {
+        order
+            .iter()
+            .zip(resolutions)
+            .map(|(&i, &resolution)| requests[i].count as u64 * (resolution as u64).pow(2))
+            .sum()
+    };
+    while area(&resolutions) > (atlas_size as u64).pow(2) {
+        // `max_by_key` picks the last of equally large shadow maps, the least important one
+        let largest = resolutions.iter().enumerate().max_by_key(|(_, r)| **r);
+        let (largest, &resolution) = match largest {
+            Some(largest) => largest,
+            None => break,
+        };
+        if resolution > MIN_SHADOW_RESOLUTION {
+            resolutions[largest] = resolution / 2;
+        } else if let Some(last) = resolutions.iter().rposition(|&r| r > 0) {
+            resolutions[last] = 0;
+        }
+    }
+
+    // Square power of two regions placed from largest to smallest never leave a gap, so they all
+    // fit as long as their area does
+    let mut maps: Vec<(usize, u32)> = order
+        .iter()
+        .zip(&resolutions)
+        .filter(|(_, &resolution)| resolution > 0)
+        .flat_map(|(&i, &resolution)| (0..requests[i].count).map(move |_| (i, resolution)))
+        .collect();
+    maps.sort_by_key(|&(_, resolution)| Reverse(resolution));
+
+    let mut allocator = AtlasAllocator::new(atlas_size);
+    let mut rects = vec![Vec::new(); requests.len()];
+    let mut skipped = vec![false; requests.len()];
+    for (i, resolution) in maps {
+        match allocator.allocate(resolution) {
+            Some(rect) => rects[i].push(rect),
+            None => skipped[i] = true,
+        }
+    }
+
+    // A light with only some of its shadow maps can't use them
+    for (rects, skipped) in rects.iter_mut().zip(skipped) {
+        if skipped {
+            rects.clear();
+        }
+    }
+    rects
+}
+
+/// Hands out square power of two regions of the atlas by splitting free regions into quadrants
+struct AtlasAllocator {
+    free: Vec<AtlasRect>,
+}
+
+impl AtlasAllocator {
+    fn new(atlas_size: u32) -> Self {
+        Self {
+            free: vec![AtlasRect {
+                x: 0,
+                y: 0,
+                size: atlas_size,
+            }],
+        }
+    }
+
+    /// Takes the smallest free region the size fits in, splitting it down to the size
+    fn allocate(&mut self, size: u32) -> Option<AtlasRect> {
+        let index = self
+            .free
+            .iter()
+            .enumerate()
+            .filter(|(_, rect)| rect.size >= size)
+            .min_by_key(|(_, rect)| rect.size)
+            .map(|(i, _)| i)?;
+
+        let mut rect = self.free.remove(index);
+        while rect.size > size {
+            let half = rect.size / 2;
+            for &(x, y) in &[(half, 0), (0, half), (half, half)] {
+                self.free.push(AtlasRect {
+                    x: rect.x + x,
+                    y: rect.y + y,
+                    size: half,
+                });
+            }
+            rect.size = half;
+        }
+        Some(rect)
+    }
+}
+
+/// How much of the view a light's shadows can cover, from 0 to 1: the size of its sphere of
+/// influence on screen. Directional lights cover all of it.
+pub fn light_importance(
+    light: &light::Light,
+    camera: &camera::Camera,
+    projection: &camera::PerspectiveProjection,
+) -> f32 {
+    if light.is_directional() {
+        return 1.0;
+    }
+    let radius = light.influence_radius();
+    let distance = (light.position - camera.position.to_vec()).magnitude();
+    if distance <= radius {
+        return 1.0;
+    }
+    (radius / (distance * (projection.fovy() / 2.0).tan())).min(1.0)
+}
+
+/// Resolution of a light's shadow maps: its configured resolution scaled by its importance,
+/// rounded down to a power of two
+pub fn importance_resolution(resolution: u32, importance: f32) -> u32 {
+    let max = floor_power_of_two(resolution.max(MIN_SHADOW_RESOLUTION));
+    let scaled = (max as f32 * importance) as u32;
+    floor_power_of_two(scaled.max(MIN_SHADOW_RESOLUTION)).min(max)
+}
+
+fn floor_power_of_two(n: u32) -> u32 {
+    if n.is_power_of_two() {
+        n
+    } else {
+        n.next_power_of_two() / 2
+    }
+}
+
+/// A shadow map a light renders into: its layer in the shadow layers buffer and its region of the
+/// atlas
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowTarget {
+    pub layer: usize,
+    pub rect: AtlasRect,
+}
+
+/// Light space projection and atlas region of a shadow map, as stored on the GPU
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowLayerRaw {
+    pub view_proj: Matrix4,
+    /// Offset (xy) and size (zw) of the shadow map in texture coordinates of the atlas
+    pub rect: Vector4,
+    /// Change in projected depth per world unit along the light direction, used to turn the bias
+    /// into projected depth
+    pub depth_scale: f32,
+    _padding: [f32; 3],
+}
+
+impl ShadowLayerRaw {
+    pub fn new(view_proj: Matrix4, rect: Vector4) -> Self {
+        // The view part of the projection is a rotation, so the depth row only scales
+        let depth_scale = Vector3::new(view_proj.x.z, view_proj.y.z, view_proj.z.z).magnitude();
+        Self {
+            view_proj,
+            rect,
+            depth_scale,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+unsafe impl bytemuck::Pod for ShadowLayerRaw {}
+unsafe impl bytemuck::Zeroable for ShadowLayerRaw {}
+
+/// Light space projections of a directional light, each fit to a slice of the view frustum
+#[derive(Copy, Clone, Debug)]
+pub struct Cascades {
+    pub view_proj: [Matrix4; CASCADE_COUNT],
+    /// View depth where each cascade ends
+    pub splits: [f32; CASCADE_COUNT],
+}
+
+/// Splits the view frustum into cascades and fits an orthographic projection along the light
+/// direction around each of them.
+///
+/// Each cascade is fit around the bounding sphere of its frustum slice rather than the slice
+/// itself, so its size doesn't change as the camera rotates, and the projection is snapped to
+/// whole shadow map texels as the camera moves. Together this keeps the shadow edges from
+/// shimmering.
+pub fn calculate_cascades(
+    direction: Vector3,
+    camera: &camera::Camera,
+    projection: &camera::PerspectiveProjection,
+    resolution: u32,
+) -> Cascades {
+    let near = projection.znear();
+    let far = projection.zfar().min(CASCADE_MAX_DISTANCE);
+
+    let mut splits = [0.0; CASCADE_COUNT];
+    for (i, split) in splits.iter_mut().enumerate() {
+        let t = (i + 1) as f32 / CASCADE_COUNT as f32;
+        let log = near * (far / near).powf(t);
+        let uniform = near + (far - near) * t;
+        *split = CASCADE_SPLIT_LAMBDA * log + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform;
+    }
+
+    // World space directions through the corners of the screen, scaled to a view depth of 1
+    let inv_proj = projection
+        .calc_matrix()
+        .invert()
+        .unwrap_or_else(Matrix4::identity);
+    let inv_view = camera
+        .calc_matrix()
+        .invert()
+        .unwrap_or_else(Matrix4::identity);
+    let corners: Vec<Vector3> = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+        .iter()
+        .map(|&(x, y)| {
+            let p = Point3::from_homogeneous(inv_proj * Vector4::new(x, y, 1.0, 1.0));
+            p.to_vec() / -p.z
+        })
+        .collect();
+
+    let up = if direction.x.abs() < 0.001 && direction.z.abs() < 0.001 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let light_view = Matrix4::look_at_dir(Point3::origin(), direction, up);
+
+    let mut view_proj = [Matrix4::identity(); CASCADE_COUNT];
+    let mut start = near;
+    for (i, &end) in splits.iter().enumerate() {
+        let points: Vec<Point3> = corners
+            .iter()
+            .flat_map(|d| vec![*d * start, *d * end])
+            .map(|p| inv_view.transform_point(Point3::from_vec(p)))
+            .collect();
+        let sphere = BoundingSphere::from_points(&points);
+
+        // Round the radius up so that small numerical changes don't resize the cascade
+        let radius = (sphere.radius * 16.0).ceil() / 16.0;
+
+        // Snap the center to the texel grid of the shadow map in light space
+        let texel_size = 2.0 * radius / resolution as f32;
+        let center = light_view.transform_point(sphere.center);
+        let x = (center.x / texel_size).floor() * texel_size;
+        let y = (center.y / texel_size).floor() * texel_size;
+
+        // The light looks down negative z in light space
+        let proj = camera::OrthographicProjection::new(
+            x - radius,
+            x + radius,
+            y - radius,
+            y + radius,
+            -center.z - radius - CASCADE_CASTER_DISTANCE,
+            -center.z + radius,
+        )
+        .calc_matrix();
+
+        view_proj[i] = proj * light_view;
+        start = end;
+    }
+
+    Cascades { view_proj, splits }
 }
 
 pub struct ShadowPass {
     pub pipeline: wgpu::RenderPipeline,
+    /// Draws billboards, which have their own vertex layout and no morph targets
+    pub billboard_pipeline: wgpu::RenderPipeline,
     pub uniforms_buffer: wgpu::Buffer,
     pub uniforms_bind_group: wgpu::BindGroup,
-    pub targets: [ShadowMapTarget; 6],
+
+    uniforms_bind_group_layout: wgpu::BindGroupLayout,
+    /// Number of shadow layers the uniforms buffer has room for
+    layer_capacity: usize,
+
+    /// Projection and frustum of each shadow layer, as set by `update_light`
+    layer_projections: Vec<Matrix4>,
+    layer_frustums: Vec<Frustum>,
+
+    /// Renders static shadow casters of static lights into a separate atlas once, so that only
+    /// dynamic casters need to be re-rendered on top of a copy of them when they move
+    pub bake_static: bool,
+    /// The baked atlas, and a bind group for copying from it
+    baked: Option<(ShadowAtlas, wgpu::BindGroup)>,
+
+    /// What each region of the atlas was last rendered with, so that unchanged shadow maps can be
+    /// skipped
+    rendered: HashMap<AtlasRect, RenderedFace>,
+    /// Size and format of the atlas `rendered` refers to
+    atlas: Option<(u32, wgpu::TextureFormat)>,
+
+    /// Depth format of the atlas the pipelines render into
+    format: wgpu::TextureFormat,
+    fill: AtlasFill,
+
+    /// Kept around to rebuild the pipeline when the depth format changes
+    pipeline_layout: wgpu::PipelineLayout,
+    vs_module: wgpu::ShaderModule,
+    fs_module: wgpu::ShaderModule,
+    vertex_descs: Vec<wgpu::VertexBufferDescriptor<'static>>,
+    /// Bound in place of the morph targets of a mesh when drawing billboards
+    no_morph: model::MorphTargets,
 }
 
 impl ShadowPass {
@@ -71,18 +490,10 @@ impl ShadowPass {
         device: &wgpu::Device,
         shader_compiler: &mut shaderc::Compiler,
         instances_bind_group_layout: &wgpu::BindGroupLayout,
-        vertex_descs: &[wgpu::VertexBufferDescriptor],
+        morph_bind_group_layout: &wgpu::BindGroupLayout,
+        vertex_descs: &[wgpu::VertexBufferDescriptor<'static>],
+        settings: &ShadowSettings,
     ) -> Self {
-        // Make room for all 6 sides of cubemap for each light
-        let uniforms_size =
-            (light::MAX_LIGHTS as u64 * 6 * wgpu::BIND_BUFFER_ALIGNMENT) as wgpu::BufferAddress;
-        let uniforms_binding_size = NonZeroU64::new(mem::size_of::<ShadowUniforms>() as u64);
-        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Shadow uniforms"),
-            size: uniforms_size,
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-            mapped_at_creation: false,
-        });
         let uniforms_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
@@ -90,140 +501,312 @@ impl ShadowPass {
                     visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
                     ty: wgpu::BindingType::UniformBuffer {
                         dynamic: true,
-                        min_binding_size: uniforms_binding_size,
+                        min_binding_size: uniforms_binding_size(),
                     },
                     count: None,
                 }],
                 label: Some("Shadow uniforms bind group layout"),
             });
 
-        let uniforms_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniforms_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer {
-                    buffer: &uniforms_buffer,
-                    offset: 0,
-                    size: uniforms_binding_size,
-                },
-            }],
-            label: Some("Shadow uniforms bind group"),
-        });
+        let layer_capacity = 1;
+        let (uniforms_buffer, uniforms_bind_group) =
+            create_uniforms(device, &uniforms_bind_group_layout, layer_capacity);
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Shadow pipeline"),
             push_constant_ranges: &[],
-            bind_group_layouts: &[&uniforms_bind_group_layout, &instances_bind_group_layout],
+            bind_group_layouts: &[
+                &uniforms_bind_group_layout,
+                &instances_bind_group_layout,
+                &morph_bind_group_layout,
+            ],
         });
 
         let vs_module = compile_vertex!(device, shader_compiler, "shadow.vert").unwrap();
         let fs_module = compile_frag!(device, shader_compiler, "shadow.frag").unwrap();
 
-        let pipeline = pipeline::create(
-            &"shadow pass",
+        let pipeline = create_pipeline(
             device,
             &pipeline_layout,
             &vs_module,
             &fs_module,
-            None,
-            Some(pipeline::DepthConfig::default()),
-            vertex_descs.clone(),
+            vertex_descs,
+            settings.format,
         );
-
-        let create_target = || {
-            let texture = device.create_texture(&wgpu::TextureDescriptor {
-                size: SHADOW_SIZE,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: SHADOW_FORMAT,
-                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
-                    | wgpu::TextureUsage::SAMPLED
-                    | wgpu::TextureUsage::COPY_SRC,
-                label: None,
-            });
-
-            let view = texture.create_view(&wgpu::TextureViewDescriptor {
-                label: Some("Shadow"),
-                format: None,
-                dimension: Some(wgpu::TextureViewDimension::D2),
-                aspect: wgpu::TextureAspect::All,
-                base_mip_level: 0,
-                level_count: None,
-                base_array_layer: 0,
-                array_layer_count: NonZeroU32::new(1),
-            });
-
-            ShadowMapTarget {
-                texture,
-                view,
-            }
-        };
-        let targets = [
-            create_target(),
-            create_target(),
-            create_target(),
-            create_target(),
-            create_target(),
-            create_target(),
-        ];
+        let billboard_pipeline = create_pipeline(
+            device,
+            &pipeline_layout,
+            &vs_module,
+            &fs_module,
+            &[geometry::SimpleVertex::desc()],
+            settings.format,
+        );
+        let no_morph = model::MorphTargets::new(
+            device,
+            morph_bind_group_layout,
+            0,
+            model::MorphTargetData::none(),
+        );
+        let fill = AtlasFill::new(device, shader_compiler, settings.format);
 
         Self {
             pipeline,
+            billboard_pipeline,
             uniforms_buffer,
             uniforms_bind_group,
-            targets,
+            uniforms_bind_group_layout,
+            layer_capacity,
+            layer_projections: Vec::new(),
+            layer_frustums: Vec::new(),
+            bake_static: false,
+            baked: None,
+            rendered: HashMap::new(),
+            atlas: None,
+            format: settings.format,
+            fill,
+            pipeline_layout,
+            vs_module,
+            fs_module,
+            vertex_descs: vertex_descs.to_vec(),
+            no_morph,
         }
     }
 
-    pub fn copy_to_cubemap(&self, encoder: &mut wgpu::CommandEncoder, cubemap: &wgpu::Texture) {
-        for (i, target) in self.targets.iter().enumerate() {
-            encoder.copy_texture_to_texture(
-                wgpu::TextureCopyView {
-                    texture: &target.texture,
-                    mip_level: 0,
-                    origin: wgpu::Origin3d::ZERO,
-                },
-                wgpu::TextureCopyView {
-                    texture: &cubemap,
-                    mip_level: 0,
-                    origin: wgpu::Origin3d {
-                        x: 0,
-                        y: 0,
-                        z: i as u32,
-                    },
-                },
-                wgpu::Extent3d {
-                    width: 1024,
-                    height: 1024,
-                    depth: 1,
-                },
+    /// Rebuilds the pipelines if the depth format of the atlas changed
+    pub fn configure(&mut self, device: &wgpu::Device, format: wgpu::TextureFormat) {
+        if format != self.format {
+            self.pipeline = create_pipeline(
+                device,
+                &self.pipeline_layout,
+                &self.vs_module,
+                &self.fs_module,
+                &self.vertex_descs,
+                format,
+            );
+            self.billboard_pipeline = create_pipeline(
+                device,
+                &self.pipeline_layout,
+                &self.vs_module,
+                &self.fs_module,
+                &[geometry::SimpleVertex::desc()],
+                format,
             );
+            self.fill.configure(device, format);
+            self.format = format;
+        }
+    }
+
+    /// Makes sure there's room for the uniforms of the given number of shadow layers (see
+    /// `Lights::shadow_layer_count`). Growing discards the uniforms written so far, so this should
+    /// be called before updating the lights.
+    pub fn reserve(&mut self, device: &wgpu::Device, layer_count: usize) {
+        if layer_count > self.layer_capacity {
+            self.layer_capacity = layer_count.next_power_of_two();
+            let (buffer, bind_group) = create_uniforms(
+                device,
+                &self.uniforms_bind_group_layout,
+                self.layer_capacity,
+            );
+            self.uniforms_buffer = buffer;
+            self.uniforms_bind_group = bind_group;
+        }
+
+        if self.layer_frustums.len() < layer_count {
+            let identity = Frustum::from_matrix(&Matrix4::identity());
+            self.layer_projections
+                .resize(layer_count, Matrix4::identity());
+            self.layer_frustums.resize(layer_count, identity);
+        }
+    }
+
+    /// Renders the shadow maps of the lights into their regions of the atlas, skipping those whose
+    /// light, region and shadow casters haven't changed since they were last rendered. `changes`
+    /// are the instances that moved since the last call. Only the instances of the casters that
+    /// cast shadows are drawn. Billboards face the camera, so shadow maps they're in are rendered
+    /// every time. Returns the shadow maps that were rendered.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        lights: &light::Lights,
+        scene: &bvh::SceneBvh,
+        changes: &[bvh::InstanceChange],
+        casters: &[ShadowCaster],
+        billboards: &[BillboardCaster],
+    ) -> Vec<ShadowTarget> {
+        self.configure(device, lights.shadow_atlas.format);
+        self.sync_atlas(device, lights);
+
+        let mut rendered = Vec::new();
+        for light in lights.iter() {
+            let bake = self.bake_static && light.mobility == model::Mobility::Static;
+
+            for target in lights.shadow_targets(light.id) {
+                let view_proj = self.layer_projections[target.layer];
+                let frustum = &self.layer_frustums[target.layer];
+                let previous = self.rendered.get(&target.rect);
+                let face_changes =
+                    FaceChanges::new(light, view_proj, frustum, previous, changes, billboards);
+                if !face_changes.statics && !face_changes.dynamics {
+                    continue;
+                }
+
+                let atlas = &lights.shadow_atlas.texture_view;
+                match &self.baked {
+                    Some((baked, baked_source)) if bake => {
+                        if face_changes.statics || !previous.map_or(false, |face| face.baked) {
+                            self.draw_face(
+                                encoder,
+                                &baked.texture_view,
+                                Fill::Clear,
+                                target,
+                                scene,
+                                casters,
+                                billboards,
+                                Some(model::Mobility::Static),
+                            );
+                        }
+                        self.draw_face(
+                            encoder,
+                            atlas,
+                            Fill::Copy(baked_source),
+                            target,
+                            scene,
+                            casters,
+                            billboards,
+                            Some(model::Mobility::Dynamic),
+                        );
+                    }
+                    _ => self.draw_face(
+                        encoder,
+                        atlas,
+                        Fill::Clear,
+                        target,
+                        scene,
+                        casters,
+                        billboards,
+                        None,
+                    ),
+                }
+
+                // Regions of the previous packing that were drawn over are gone
+                self.rendered
+                    .retain(|rect, _| *rect == target.rect || !rect.overlaps(&target.rect));
+                self.rendered.insert(
+                    target.rect,
+                    RenderedFace {
+                        light: light.id,
+                        view_proj,
+                        baked: bake,
+                    },
+                );
+                rendered.push(target);
+            }
+        }
+        rendered
+    }
+
+    /// Forgets what was rendered, so that every shadow map is rendered again by the next `render`,
+    /// e.g. after instances moved while shadows weren't being rendered
+    pub fn invalidate(&mut self) {
+        self.rendered.clear();
+    }
+
+    /// Forgets what was rendered if the atlas was reallocated, and keeps the baked atlas in line
+    /// with it
+    fn sync_atlas(&mut self, device: &wgpu::Device, lights: &light::Lights) {
+        let atlas = (lights.shadow_atlas.size, lights.shadow_atlas.format);
+        if self.atlas == Some(atlas) && self.bake_static == self.baked.is_some() {
+            return;
+        }
+
+        let (size, format) = atlas;
+        self.baked = if self.bake_static {
+            let baked = ShadowAtlas::new(device, size, format);
+            let source = self.fill.create_source_bind_group(device, &baked);
+            Some((baked, source))
+        } else {
+            None
+        };
+        self.rendered.clear();
+        self.atlas = Some(atlas);
+    }
+
+    /// Draws the shadow casters of a shadow map, optionally only those with the given mobility.
+    /// Billboards count as dynamic.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_face(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        atlas: &wgpu::TextureView,
+        fill: Fill,
+        target: ShadowTarget,
+        scene: &bvh::SceneBvh,
+        casters: &[ShadowCaster],
+        billboards: &[BillboardCaster],
+        mobility: Option<model::Mobility>,
+    ) {
+        let frustum = &self.layer_frustums[target.layer];
+        let mut pass = self.begin(encoder, atlas, target.rect, fill);
+        for caster in casters {
+            let visible = scene.visible_ranges_where(caster.model_index, frustum, |instance| {
+                caster.instances.contains(&(instance.instance as u32))
+                    && scene.instance_casts_shadows(instance)
+                    && mobility.map_or(true, |m| scene.instance_mobility(instance) == m)
+            });
+            for mesh in &caster.model.meshes {
+                for instances in visible.iter().cloned() {
+                    pass.render(
+                        ShadowPassRenderData::from_mesh(
+                            mesh,
+                            caster.instances_bind_group,
+                            instances,
+                        ),
+                        target.layer,
+                    );
+                }
+            }
+        }
+
+        if mobility == Some(model::Mobility::Static) {
+            return;
+        }
+        pass.set_pipeline(&self.billboard_pipeline);
+        for billboards in billboards {
+            let visible = billboards
+                .bounds
+                .iter()
+                .any(|bounds| frustum.intersects_sphere(bounds));
+            if visible {
+                pass.render(
+                    ShadowPassRenderData {
+                        vertex_buffer: billboards.vertex_buffer,
+                        index_buffer: billboards.index_buffer,
+                        indices: billboards.indices.clone(),
+                        instances_bind_group: billboards.instances_bind_group,
+                        instances: billboards.instances.clone(),
+                        morph_bind_group: &self.no_morph.bind_group,
+                    },
+                    target.layer,
+                );
+            }
         }
     }
 
-    pub fn begin<'a>(
+    /// Starts rendering into a region of an atlas, filling it first. Render passes only clear
+    /// whole textures, so the region is cleared (or copied into) by drawing over it.
+    fn begin<'a>(
         &'a self,
         encoder: &'a mut wgpu::CommandEncoder,
-        face_index: usize,
+        atlas: &'a wgpu::TextureView,
+        rect: AtlasRect,
+        fill: Fill<'a>,
     ) -> ShadowPassRunner<'a> {
-        // Clear depth buffer
-        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            color_attachments: &[],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                attachment: &self.targets[face_index].view,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: true,
-                }),
-                stencil_ops: None,
-            }),
-        });
-
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             color_attachments: &[],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                attachment: &self.targets[face_index].view,
+                attachment: atlas,
                 depth_ops: Some(wgpu::Operations {
                     load: wgpu::LoadOp::Load,
                     store: true,
@@ -232,22 +815,59 @@ impl ShadowPass {
             }),
         });
 
-        render_pass.set_pipeline(&self.pipeline);
+        let size = rect.size as f32;
+        render_pass.set_viewport(rect.x as f32, rect.y as f32, size, size, 0.0, 1.0);
+        render_pass.set_scissor_rect(rect.x, rect.y, rect.size, rect.size);
 
+        match fill {
+            Fill::Clear => {
+                render_pass.set_pipeline(&self.fill.clear_pipeline);
+                render_pass.draw(0..3, 0..1);
+            }
+            Fill::Copy(source) => {
+                render_pass.set_pipeline(&self.fill.copy_pipeline);
+                render_pass.set_bind_group(0, source, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
         ShadowPassRunner {
             render_pass,
             uniforms_bind_group: &self.uniforms_bind_group,
         }
     }
 
-    pub fn update_light(&self, queue: &wgpu::Queue, light_index: usize, light: &light::Light) {
-        let projections = create_light_proj_cube(cgmath::EuclideanSpace::from_vec(light.position));
-        for (i, proj) in projections.iter().enumerate() {
+    pub fn layer_frustum(&self, layer: usize) -> &Frustum {
+        &self.layer_frustums[layer]
+    }
+
+    /// Updates the projections of a light's shadow layers, starting at `first_layer` (see
+    /// `Lights::shadow_layers`). `reserve` must have been called with room for the layers
+    /// beforehand.
+    pub fn update_light(
+        &mut self,
+        queue: &wgpu::Queue,
+        first_layer: usize,
+        light: &light::Light,
+        layer_projections: &[Matrix4],
+        settings: &ShadowSettings,
+    ) {
+        // Point and spot lights store the distance to the light, directional lights the depth
+        let linear_depth = !light.is_directional();
+
+        for (i, proj) in layer_projections.iter().enumerate() {
+            let layer = first_layer + i;
+            self.layer_projections[layer] = *proj;
+            self.layer_frustums[layer] = Frustum::from_matrix(proj);
             let uniforms = ShadowUniforms {
                 light_proj: *proj,
                 light_position: light.position,
+                linear_depth: linear_depth as u32,
+                far: settings.far,
+                _padding: [0; 3],
             };
-            let buffer_offset = light_buffer_offset(light_index, i) as wgpu::BufferAddress;
+            let buffer_offset = layer_buffer_offset(layer) as wgpu::BufferAddress;
             queue.write_buffer(
                 &self.uniforms_buffer,
                 buffer_offset,
@@ -257,21 +877,300 @@ impl ShadowPass {
     }
 }
 
+/// Something to draw into the shadow maps
+pub struct ShadowCaster<'a> {
+    /// Index of the model in the slice the scene was built from
+    pub model_index: usize,
+    pub model: &'a model::Model,
+    pub instances: Range<u32>,
+    pub instances_bind_group: &'a wgpu::BindGroup,
+}
+
+/// Billboards to draw into the shadow maps, see `billboard::Billboards::shadow_casters`. Instances
+/// whose flags don't include `model::INSTANCE_CASTS_SHADOWS` are dropped by the vertex shader.
+pub struct BillboardCaster<'a> {
+    pub vertex_buffer: &'a wgpu::Buffer,
+    pub index_buffer: &'a wgpu::Buffer,
+    pub indices: Range<u32>,
+    pub instances_bind_group: &'a wgpu::BindGroup,
+    pub instances: Range<u32>,
+    /// Bounds of the billboards that cast shadows, to tell which shadow maps they're in
+    pub bounds: Vec<BoundingSphere>,
+}
+
+/// What a region of the atlas was last rendered with
+struct RenderedFace {
+    light: light::LightId,
+    view_proj: Matrix4,
+    /// Whether the static casters were baked for the same light and projection
+    baked: bool,
+}
+
+/// Which casters of a shadow map have to be drawn again
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct FaceChanges {
+    /// The light or its projection changed, or a static caster moved in or out of view
+    statics: bool,
+    /// A dynamic caster moved in or out of view, or a billboard is in view
+    dynamics: bool,
+}
+
+impl FaceChanges {
+    /// Compares a shadow map of `light` with how it was `previous`ly rendered
+    fn new(
+        light: &light::Light,
+        view_proj: Matrix4,
+        frustum: &Frustum,
+        previous: Option<&RenderedFace>,
+        changes: &[bvh::InstanceChange],
+        billboards: &[BillboardCaster],
+    ) -> Self {
+        let moved = |mobility| {
+            changes.iter().any(|change| {
+                change.mobility == mobility
+                    && (frustum.intersects_aabb(&change.old_aabb)
+                        || frustum.intersects_aabb(&change.new_aabb))
+            })
+        };
+
+        let light_changed = light.mobility == model::Mobility::Dynamic
+            || previous.map_or(true, |face| {
+                face.light != light.id || face.view_proj != view_proj
+            });
+        Self {
+            statics: light_changed || moved(model::Mobility::Static),
+            dynamics: moved(model::Mobility::Dynamic)
+                || billboards
+                    .iter()
+                    .flat_map(|billboards| &billboards.bounds)
+                    .any(|bounds| frustum.intersects_sphere(bounds)),
+        }
+    }
+}
+
+/// How a region of the atlas is filled before the casters are drawn into it
+enum Fill<'a> {
+    /// With the far plane
+    Clear,
+    /// With the same region of another atlas of the same size, bound by
+    /// `AtlasFill::create_source_bind_group`
+    Copy(&'a wgpu::BindGroup),
+}
+
+/// Pipelines that fill a region of an atlas with a fullscreen triangle (see `Fill`). Depth
+/// textures can only be copied as a whole, so copying a region is a draw as well.
+struct AtlasFill {
+    clear_pipeline: wgpu::RenderPipeline,
+    copy_pipeline: wgpu::RenderPipeline,
+    source_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+
+    /// Kept around to rebuild the pipelines when the depth format changes
+    clear_layout: wgpu::PipelineLayout,
+    copy_layout: wgpu::PipelineLayout,
+    vs_module: wgpu::ShaderModule,
+    clear_module: wgpu::ShaderModule,
+    copy_module: wgpu::ShaderModule,
+}
+
+impl AtlasFill {
+    fn new(
+        device: &wgpu::Device,
+        shader_compiler: &mut shaderc::Compiler,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let source_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                        count: None,
+                    },
+                ],
+                label: Some("Shadow atlas copy bind group layout"),
+            });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow atlas copy"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: None,
+            ..Default::default()
+        });
+
+        let clear_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow atlas clear"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[],
+        });
+        let copy_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow atlas copy"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&source_bind_group_layout],
+        });
+
+        let vs_module = compile_vertex!(device, shader_compiler, "fullscreen.vert").unwrap();
+        let clear_module = compile_frag!(device, shader_compiler, "shadow_clear.frag").unwrap();
+        let copy_module = compile_frag!(device, shader_compiler, "shadow_copy.frag").unwrap();
+
+        Self {
+            clear_pipeline: create_fill_pipeline(
+                device,
+                &clear_layout,
+                &vs_module,
+                &clear_module,
+                format,
+            ),
+            copy_pipeline: create_fill_pipeline(
+                device,
+                &copy_layout,
+                &vs_module,
+                &copy_module,
+                format,
+            ),
+            source_bind_group_layout,
+            sampler,
+            clear_layout,
+            copy_layout,
+            vs_module,
+            clear_module,
+            copy_module,
+        }
+    }
+
+    fn configure(&mut self, device: &wgpu::Device, format: wgpu::TextureFormat) {
+        self.clear_pipeline = create_fill_pipeline(
+            device,
+            &self.clear_layout,
+            &self.vs_module,
+            &self.clear_module,
+            format,
+        );
+        self.copy_pipeline = create_fill_pipeline(
+            device,
+            &self.copy_layout,
+            &self.vs_module,
+            &self.copy_module,
+            format,
+        );
+    }
+
+    fn create_source_bind_group(
+        &self,
+        device: &wgpu::Device,
+        source: &ShadowAtlas,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.source_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("Shadow atlas copy"),
+        })
+    }
+}
+
+/// Writes the depth of every fragment, whatever was there before
+fn create_fill_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Shadow atlas fill"),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::None,
+            ..Default::default()
+        }),
+        color_states: &[],
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+            format,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilStateDescriptor::default(),
+        }),
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: wgpu::IndexFormat::Uint32,
+            vertex_buffers: &[],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    vertex_descs: &[wgpu::VertexBufferDescriptor],
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    pipeline::create(
+        &"shadow pass",
+        device,
+        layout,
+        vs_module,
+        fs_module,
+        None,
+        Some(pipeline::DepthConfig::default().with_format(format)),
+        1,
+        vertex_descs,
+    )
+}
+
 pub struct ShadowPassRunner<'a> {
     render_pass: wgpu::RenderPass<'a>,
     uniforms_bind_group: &'a wgpu::BindGroup,
 }
 
 impl<'a> ShadowPassRunner<'a> {
-    pub fn render<'b>(
-        &mut self,
-        data: ShadowPassRenderData<'b>,
-        face_index: usize,
-        light_index: usize,
-    ) where
+    pub fn set_pipeline(&mut self, pipeline: &'a wgpu::RenderPipeline) {
+        self.render_pass.set_pipeline(pipeline);
+    }
+
+    pub fn render<'b>(&mut self, data: ShadowPassRenderData<'b>, layer: usize)
+    where
         'b: 'a,
     {
-        let buffer_offset = light_buffer_offset(light_index, face_index) as wgpu::DynamicOffset;
+        let buffer_offset = layer_buffer_offset(layer) as wgpu::DynamicOffset;
 
         self.render_pass
             .set_vertex_buffer(0, data.vertex_buffer.slice(..));
@@ -281,15 +1180,48 @@ impl<'a> ShadowPassRunner<'a> {
             .set_bind_group(0, &self.uniforms_bind_group, &[buffer_offset]);
         self.render_pass
             .set_bind_group(1, &data.instances_bind_group, &[]);
+        self.render_pass
+            .set_bind_group(2, &data.morph_bind_group, &[]);
         self.render_pass
             .draw_indexed(data.indices, 0, data.instances);
     }
 }
 
-fn light_buffer_offset(light_index: usize, face_index: usize) -> usize {
-    let light_offset = light_index * 6 * wgpu::BIND_BUFFER_ALIGNMENT as usize;
-    let face_offset = face_index * wgpu::BIND_BUFFER_ALIGNMENT as usize;
-    light_offset + face_offset
+fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+    NonZeroU64::new(mem::size_of::<ShadowUniforms>() as u64)
+}
+
+/// Creates the uniforms buffer with room for the projection of each shadow layer
+fn create_uniforms(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    layer_capacity: usize,
+) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Shadow uniforms"),
+        size: (layer_capacity as u64 * wgpu::BIND_BUFFER_ALIGNMENT) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer {
+                buffer: &buffer,
+                offset: 0,
+                size: uniforms_binding_size(),
+            },
+        }],
+        label: Some("Shadow uniforms bind group"),
+    });
+
+    (buffer, bind_group)
+}
+
+fn layer_buffer_offset(layer: usize) -> usize {
+    layer * wgpu::BIND_BUFFER_ALIGNMENT as usize
 }
 
 pub struct ShadowPassRenderData<'a> {
@@ -298,22 +1230,28 @@ pub struct ShadowPassRenderData<'a> {
     pub indices: Range<u32>,
     pub instances_bind_group: &'a wgpu::BindGroup,
     pub instances: Range<u32>,
+    pub morph_bind_group: &'a wgpu::BindGroup,
 }
 
 impl<'a> ShadowPassRenderData<'a> {
-    pub fn from_mesh(mesh: &'a model::Mesh, instances_bind_group: &'a wgpu::BindGroup) -> Self {
+    pub fn from_mesh(
+        mesh: &'a model::Mesh,
+        instances_bind_group: &'a wgpu::BindGroup,
+        instances: Range<u32>,
+    ) -> Self {
         Self {
             vertex_buffer: &mesh.vertex_buffer,
             index_buffer: &mesh.index_buffer,
             indices: 0..mesh.num_elements,
             instances_bind_group,
-            instances: 0..1,
+            instances,
+            morph_bind_group: &mesh.morph_targets.bind_group,
         }
     }
 }
 
-pub fn create_light_proj_cube(light_pos: cgmath::Point3<f32>) -> Vec<Matrix4> {
-    let light_proj = create_proj_mat(&light::LightType::Point);
+pub fn create_light_proj_cube(light_pos: Point3, settings: &ShadowSettings) -> Vec<Matrix4> {
+    let light_proj = create_point_proj_mat(settings);
     let transforms = vec![
         light_proj
             * Matrix4::look_at(
@@ -355,16 +1293,34 @@ pub fn create_light_proj_cube(light_pos: cgmath::Point3<f32>) -> Vec<Matrix4> {
     transforms
 }
 
-fn create_proj_mat(light_type: &light::LightType) -> Matrix4 {
-    match light_type {
-        light::LightType::Directional => {
-            camera::OrthographicProjection::new(-10.0, 10.0, -10.0, 10.0, 0.1, 100.0).calc_matrix()
-        }
-        light::LightType::Point => {
-            camera::PerspectiveProjection::new(1024, 1024, cgmath::Deg(90.0), 0.1, 100.0)
-                .calc_matrix()
-        }
-    }
+fn create_point_proj_mat(settings: &ShadowSettings) -> Matrix4 {
+    camera::PerspectiveProjection::new(1, 1, cgmath::Deg(90.0), settings.near, settings.far)
+        .calc_matrix()
+}
+
+/// Perspective projection covering the cone of a spot light
+pub fn create_spot_view_proj(
+    position: Vector3,
+    direction: Vector3,
+    outer_angle: Rad<f32>,
+    settings: &ShadowSettings,
+) -> Matrix4 {
+    let position = Point3::from_vec(position);
+    let up = if direction.x.abs() < 0.001 && direction.z.abs() < 0.001 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let view = Matrix4::look_at_dir(position, direction, up);
+
+    let proj = camera::PerspectiveProjection::new(
+        1,
+        1,
+        outer_angle * 2.0,
+        settings.near,
+        settings.far,
+    );
+    proj.calc_matrix() * view
 }
 
 #[repr(C)]
@@ -372,7 +1328,168 @@ fn create_proj_mat(light_type: &light::LightType) -> Matrix4 {
 pub struct ShadowUniforms {
     pub light_proj: Matrix4,
     pub light_position: Vector3,
+    /// Whether to store the linear distance to the light (point and spot lights) rather than the
+    /// projected depth (directional lights)
+    pub linear_depth: u32,
+    /// Distance the linear depth is normalized by
+    pub far: f32,
+    _padding: [u32; 3],
 }
 
 unsafe impl bytemuck::Pod for ShadowUniforms {}
 unsafe impl bytemuck::Zeroable for ShadowUniforms {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounds::Aabb;
+
+    fn request(count: usize, resolution: u32, importance: f32) -> AtlasRequest {
+        AtlasRequest {
+            count,
+            resolution,
+            importance,
+        }
+    }
+
+    /// A face looking down -z at x in [x - 1, x + 1], rendered last frame for a static light
+    fn face_at(light: &light::Light, x: f32) -> (Matrix4, Frustum, RenderedFace) {
+        let view_proj =
+            camera::OPENGL_TO_WGPU_MATRIX * cgmath::ortho(x - 1.0, x + 1.0, -1.0, 2.0, -1.0, 1.0);
+        let face = RenderedFace {
+            light: light.id,
+            view_proj,
+            baked: false,
+        };
+        (view_proj, Frustum::from_matrix(&view_proj), face)
+    }
+
+    fn unit_box(x: f32) -> Aabb {
+        Aabb::new(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn moved_casters_invalidate_the_faces_they_were_and_are_in() {
+        let mut light = light::Light::point([0.0, 0.0, 0.0]);
+        light.mobility = model::Mobility::Static;
+        let change = bvh::InstanceChange {
+            instance: bvh::InstanceRef {
+                model: 0,
+                instance: 0,
+            },
+            mobility: model::Mobility::Dynamic,
+            old_aabb: unit_box(-0.5),
+            new_aabb: unit_box(9.5),
+        };
+
+        let changes_at = |x, changes: &[bvh::InstanceChange]| {
+            let (view_proj, frustum, face) = face_at(&light, x);
+            FaceChanges::new(&light, view_proj, &frustum, Some(&face), changes, &[])
+        };
+        let dynamics = FaceChanges {
+            statics: false,
+            dynamics: true,
+        };
+        let unchanged = FaceChanges {
+            statics: false,
+            dynamics: false,
+        };
+        // Before the move, after the move, and a face it never was in
+        assert_eq!(changes_at(0.0, &[change]), dynamics);
+        assert_eq!(changes_at(10.0, &[change]), dynamics);
+        assert_eq!(changes_at(20.0, &[change]), unchanged);
+        assert_eq!(changes_at(0.0, &[]), unchanged);
+
+        // Static casters take the static part of the shadow map with them
+        let static_change = bvh::InstanceChange {
+            mobility: model::Mobility::Static,
+            ..change
+        };
+        let statics = FaceChanges {
+            statics: true,
+            dynamics: false,
+        };
+        assert_eq!(changes_at(10.0, &[static_change]), statics);
+        assert_eq!(changes_at(20.0, &[static_change]), unchanged);
+    }
+
+    #[test]
+    fn faces_are_rendered_again_when_the_light_changes() {
+        let mut light = light::Light::point([0.0, 0.0, 0.0]);
+        light.mobility = model::Mobility::Static;
+        let (view_proj, frustum, face) = face_at(&light, 0.0);
+        let changes = |light: &light::Light, view_proj, previous| {
+            FaceChanges::new(light, view_proj, &frustum, previous, &[], &[]).statics
+        };
+
+        assert!(!changes(&light, view_proj, Some(&face)));
+        assert!(changes(&light, view_proj, None));
+        let (moved_view_proj, _, _) = face_at(&light, 1.0);
+        assert!(changes(&light, moved_view_proj, Some(&face)));
+        light.mobility = model::Mobility::Dynamic;
+        assert!(changes(&light, view_proj, Some(&face)));
+    }
+
+    /// Checks that the regions are inside the atlas, don't overlap and are powers of two
+    fn assert_packed(atlas_size: u32, rects: &[Vec<AtlasRect>]) {
+        let all: Vec<&AtlasRect> = rects.iter().flatten().collect();
+        for (i, rect) in all.iter().enumerate() {
+            assert!(rect.size.is_power_of_two(), "{:?}", rect);
+            assert!(rect.x + rect.size <= atlas_size && rect.y + rect.size <= atlas_size);
+            for other in &all[i + 1..] {
+                assert!(!rect.overlaps(other), "{:?} overlaps {:?}", rect, other);
+            }
+        }
+    }
+
+    #[test]
+    fn pack_atlas_leaves_out_what_does_not_fit() {
+        // Room for four of the smallest shadow maps, so the least important request misses out
+        let size = MIN_SHADOW_RESOLUTION * 2;
+        let requests: Vec<_> = (0..5)
+            .map(|i| request(1, MIN_SHADOW_RESOLUTION, 1.0 - i as f32 * 0.1))
+            .collect();
+        let rects = pack_atlas(size, &requests);
+        assert_packed(size, &rects);
+        for rects in &rects[..4] {
+            assert_eq!(rects.len(), 1);
+        }
+        assert!(rects[4].is_empty());
+
+        // A point light needs all six faces or none
+        let rects = pack_atlas(size, &[request(6, MIN_SHADOW_RESOLUTION, 1.0)]);
+        assert!(rects[0].is_empty());
+    }
+
+    #[test]
+    fn pack_atlas_rounds_down_to_powers_of_two() {
+        let requests = [request(1, 300, 1.0), request(6, 200, 0.5)];
+        let rects = pack_atlas(1000, &requests);
+        assert_packed(512, &rects);
+        assert_eq!(rects[0].len(), 1);
+        assert_eq!(rects[0][0].size, 256);
+        assert_eq!(rects[1].len(), 6);
+        assert!(rects[1].iter().all(|rect| rect.size == 128));
+
+        let settings = ShadowSettings {
+            atlas_size: 3000,
+            ..Default::default()
+        };
+        assert_eq!(settings.rounded_atlas_size(), 2048);
+    }
+
+    #[test]
+    fn pack_atlas_scales_down_ties_in_favour_of_the_first() {
+        // Only one of them fits at full resolution
+        let size = 256;
+        let rects = pack_atlas(size, &[request(3, 128, 0.5), request(3, 128, 0.5)]);
+        assert_packed(size, &rects);
+        assert!(rects[0].iter().all(|rect| rect.size == 128));
+        assert!(rects[1].iter().all(|rect| rect.size == 64));
+
+        // Importance comes before the order
+        let rects = pack_atlas(size, &[request(3, 128, 0.5), request(3, 128, 1.0)]);
+        assert!(rects[0].iter().all(|rect| rect.size == 64));
+        assert!(rects[1].iter().all(|rect| rect.size == 128));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::light;
+use crate::prelude::*;
+use std::f32::consts::{FRAC_PI_2, PI};
+
+/// A clear sky lit by the sun, after the analytic model of Preetham et al. ("A Practical Analytic
+/// Model for Daylight", 1999). It's drawn by `background::Background::PhysicalSky`, and `apply`
+/// gives the sun light the color and intensity of the sunlight making it through the atmosphere.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sky {
+    /// Towards the sun, see `sun_direction`
+    pub sun_direction: Vector3,
+    /// Haziness of the atmosphere, from about 2 (very clear) to 10 (hazy)
+    pub turbidity: f32,
+    /// Radiance drawn per unit of sky luminance (in kcd/m²)
+    pub sky_intensity: f32,
+    /// Intensity of the sun light, before the atmosphere attenuates it
+    pub sun_intensity: f32,
+    /// Fraction of the light from the sky added to `Lights::ambient` by `apply`. Zero leaves the
+    /// ambient light to the lights themselves.
+    pub ambient: f32,
+}
+
+impl Sky {
+    pub const DEFAULT: Sky = Sky {
+        sun_direction: Vector3 {
+            x: 0.4,
+            y: 1.0,
+            z: 0.3,
+        },
+        turbidity: 3.0,
+        sky_intensity: 0.05,
+        sun_intensity: 1.0,
+        ambient: 0.3,
+    };
+
+    /// Points the sun light in the direction of the sunlight, gives it the color and intensity of
+    /// the sunlight reaching the ground, and sets the ambient light from the sky
+    pub fn apply(&self, lights: &mut light::Lights, sun: light::LightId) {
+        if let Some(light) = lights.get_mut(sun) {
+            light.direction = -self.sun_direction.normalize();
+            light.color = self.sun_color();
+            light.intensity = self.sun_intensity * self.elevation_fade(-0.02, 0.02);
+        }
+        lights.ambient = self.ambient_color() * self.ambient;
+    }
+
+    /// Fraction of each color of the sunlight that makes it through the atmosphere, scattered
+    /// away by air molecules (Rayleigh) and aerosols (Mie)
+    pub fn sun_color(&self) -> Vector3 {
+        let theta = self.sun_theta().min(FRAC_PI_2);
+        let mass = relative_optical_mass(theta);
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength: f32| {
+            // Wavelength in micrometers
+            let rayleigh = (-mass * 0.008735 * wavelength.powf(-4.08)).exp();
+            let aerosol = (-mass * beta * wavelength.powf(-1.3)).exp();
+            rayleigh * aerosol
+        };
+        Vector3::new(
+            transmittance(0.68),
+            transmittance(0.55),
+            transmittance(0.44),
+        )
+    }
+
+    /// Average radiance of the sky over the upper hemisphere, as drawn in the background
+    pub fn ambient_color(&self) -> Vector3 {
+        // Straight up and two rings below it, weighted by the solid angle of their band
+        let mut sum = self.radiance(Vector3::unit_y()) * 0.15;
+        for &(elevation, weight) in &[(Deg(50.0), 0.4), (Deg(15.0), 0.45)] {
+            let elevation: Rad<f32> = elevation.into();
+            for i in 0..8 {
+                let azimuth = i as f32 / 8.0 * 2.0 * PI;
+                let direction = Vector3::new(
+                    elevation.0.cos() * azimuth.cos(),
+                    elevation.0.sin(),
+                    elevation.0.cos() * azimuth.sin(),
+                );
+                sum += self.radiance(direction) * weight / 8.0;
+            }
+        }
+        sum
+    }
+
+    /// Radiance of the sky in the given direction, as drawn in the background (without the sun)
+    pub fn radiance(&self, direction: Vector3) -> Vector3 {
+        let raw = self.to_raw();
+        let direction = direction.normalize();
+        let sun = self.sun_direction.normalize();
+        let cos_theta = direction.y.max(0.001);
+        let gamma = direction.dot(sun).clamp(-1.0, 1.0).acos();
+
+        let perez = |i: usize| {
+            let c = |j: usize| raw.perez[j][i];
+            (1.0 + c(0) * (c(1) / cos_theta).exp())
+                * (1.0 + c(2) * (c(3) * gamma).exp() + c(4) * gamma.cos() * gamma.cos())
+        };
+        let luminance = raw.zenith.x * perez(0);
+        let x = raw.zenith.y * perez(1);
+        let y = raw.zenith.z * perez(2);
+        xyy_to_rgb(x, y, luminance) * raw.zenith.w
+    }
+
+    /// Coefficients of the Preetham model for the sun's position, which `background.frag`
+    /// evaluates for every direction
+    pub fn to_raw(&self) -> SkyRaw {
+        let t = self.turbidity;
+        let theta_s = self.sun_theta().min(FRAC_PI_2);
+
+        // Perez distribution coefficients A to E, for the luminance and both chromaticities
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ],
+            [
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ],
+            [
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ],
+            [
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ],
+            [
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        // Luminance and chromaticity at the zenith
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |coefficients: [[f32; 4]; 3]| {
+            let theta = Vector4::new(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0);
+            let row = |i: usize| Vector4::from(coefficients[i]).dot(theta);
+            t * t * row(0) + t * row(1) + row(2)
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        // The distribution is relative to the zenith, so it's divided by its value there
+        let at_zenith = |i: usize| {
+            let c = |j: usize| perez[j][i];
+            (1.0 + c(0) * c(1).exp())
+                * (1.0 + c(2) * (c(3) * theta_s).exp() + c(4) * theta_s.cos() * theta_s.cos())
+        };
+
+        SkyRaw {
+            perez: [
+                Vector3::from(perez[0]).extend(0.0),
+                Vector3::from(perez[1]).extend(0.0),
+                Vector3::from(perez[2]).extend(0.0),
+                Vector3::from(perez[3]).extend(0.0),
+                Vector3::from(perez[4]).extend(0.0),
+            ],
+            zenith: Vector4::new(
+                zenith_luminance.max(0.0) / at_zenith(0),
+                zenith_x / at_zenith(1),
+                zenith_y / at_zenith(2),
+                // The model stops at sunset, so the sky just fades to black after it
+                self.sky_intensity * self.elevation_fade(-0.2, 0.0),
+            ),
+        }
+    }
+
+    /// Angle between the zenith and the sun
+    fn sun_theta(&self) -> f32 {
+        self.sun_direction.normalize().y.clamp(-1.0, 1.0).acos()
+    }
+
+    /// Goes smoothly from 0 to 1 as the sine of the sun's elevation goes from `start` to `end`
+    fn elevation_fade(&self, start: f32, end: f32) -> f32 {
+        let elevation = self.sun_direction.normalize().y;
+        let t = ((elevation - start) / (end - start)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky::DEFAULT
+    }
+}
+
+/// The Preetham model for one sun position, see `background.frag`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SkyRaw {
+    /// Coefficients A to E of the Perez distribution, for the luminance (x) and the x (y) and y
+    /// (z) chromaticity
+    pub perez: [Vector4; 5],
+    /// Luminance and chromaticity at the zenith, divided by the Perez distribution there, and the
+    /// radiance per unit of luminance in w
+    pub zenith: Vector4,
+}
+
+unsafe impl bytemuck::Pod for SkyRaw {}
+unsafe impl bytemuck::Zeroable for SkyRaw {}
+
+/// Direction towards the sun at an hour of the day (in solar time, from 0 to 24) at a latitude,
+/// around an equinox. The sun rises in +x and sets in -x, and south is +z.
+pub fn sun_direction(hours: f32, latitude: Deg<f32>) -> Vector3 {
+    let hour_angle: Rad<f32> = Deg((hours - 12.0) * 15.0).into();
+    let latitude: Rad<f32> = latitude.into();
+    Vector3::new(
+        -hour_angle.0.sin(),
+        hour_angle.0.cos() * latitude.0.cos(),
+        hour_angle.0.cos() * latitude.0.sin(),
+    )
+}
+
+/// Relative length of the path of sunlight through the atmosphere, compared to the path when
+/// the sun is at the zenith (Kasten and Young)
+fn relative_optical_mass(theta: f32) -> f32 {
+    let degrees = theta.to_degrees();
+    1.0 / (theta.cos() + 0.50572 * (96.07995 - degrees).powf(-1.6364))
+}
+
+/// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vector3 {
+    if y <= 0.0 {
+        return Vector3::zero();
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Vector3::new(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    )
+    .map(|c| c.max(0.0))
+}
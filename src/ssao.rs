@@ -0,0 +1,470 @@
+use crate::camera;
+use crate::camera::Projection;
+use crate::compile_frag;
+use crate::forward;
+use crate::post;
+use crate::prelude::*;
+use crate::texture;
+use crate::Context;
+use std::f32::consts::PI;
+use std::mem;
+use wgpu::util::DeviceExt;
+
+/// Format of the ambient occlusion, 1 where nothing occludes the ambient light
+pub const OCCLUSION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+/// Most samples taken around each pixel
+pub const MAX_SAMPLES: usize = 32;
+
+/// Draws in the uniforms buffer, at `BIND_BUFFER_ALIGNMENT` apart
+const DRAW_OCCLUSION: usize = 0;
+const DRAW_BLUR_HORIZONTAL: usize = 1;
+const DRAW_BLUR_VERTICAL: usize = 2;
+const DRAW_COUNT: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// Whether the depth prepass also writes the normals of the surfaces (with their normal maps)
+    /// rather than having them reconstructed from the depth, which loses detail and breaks up at
+    /// edges
+    pub normal_prepass: bool,
+    /// Of the hemisphere sampled around each pixel, in world units
+    pub radius: f32,
+    /// Depth difference ignored when comparing against the samples, to avoid self occlusion
+    pub bias: f32,
+    /// Exponent of the unoccluded fraction, darkening the occlusion
+    pub intensity: f32,
+    /// Up to `MAX_SAMPLES`
+    pub sample_count: u32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        SsaoSettings {
+            enabled: true,
+            normal_prepass: true,
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.5,
+            sample_count: 16,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SsaoUniforms {
+    proj: Matrix4,
+    inv_proj: Matrix4,
+    /// World to view space, for the normals of the prepass
+    view: Matrix4,
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+    sample_count: u32,
+    normals_from_depth: u32,
+    _padding: u32,
+    /// Of the blur, in texels
+    blur_direction: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for SsaoUniforms {}
+unsafe impl bytemuck::Zeroable for SsaoUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SsaoKernel {
+    /// Offsets in tangent space around the z axis, within the unit hemisphere
+    samples: [Vector4; MAX_SAMPLES],
+}
+
+unsafe impl bytemuck::Pod for SsaoKernel {}
+unsafe impl bytemuck::Zeroable for SsaoKernel {}
+
+/// Screen space ambient occlusion, computed from the depth prepass of the forward pass (see
+/// `ForwardPass::begin_prepass`) into `ForwardPass::occlusion_texture`, which `shader.frag`
+/// multiplies the ambient light by. The occlusion is blurred with a depth aware (bilateral) blur
+/// so that it doesn't bleed across edges.
+pub struct SsaoPass {
+    pub settings: SsaoSettings,
+    occlusion_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    uniforms_buffer: wgpu::Buffer,
+    uniforms_bind_group: wgpu::BindGroup,
+    source_bind_group_layout: wgpu::BindGroupLayout,
+    /// The occlusion blurred horizontally
+    scratch: texture::Texture,
+    /// Reading from `scratch` and from `ForwardPass::occlusion_texture`
+    reads_scratch: wgpu::BindGroup,
+    reads_occlusion: wgpu::BindGroup,
+}
+
+impl SsaoPass {
+    pub fn new(
+        context: &mut Context,
+        fullscreen: &post::Fullscreen,
+        forward: &forward::ForwardPass,
+    ) -> Self {
+        let device = &context.device;
+        let uniforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: true,
+                            min_binding_size: uniforms_binding_size(),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                mem::size_of::<SsaoKernel>() as _
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("SSAO uniforms bind group layout"),
+            });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+            count: None,
+        };
+        let source_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    texture_entry(0),
+                    texture_entry(1),
+                    texture_entry(2),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                        count: None,
+                    },
+                ],
+                label: Some("SSAO source bind group layout"),
+            });
+
+        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO uniforms"),
+            size: DRAW_COUNT as u64 * wgpu::BIND_BUFFER_ALIGNMENT,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let kernel_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO kernel"),
+            contents: bytemuck::bytes_of(&create_kernel()),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        let uniforms_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniforms_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniforms_buffer,
+                        offset: 0,
+                        size: uniforms_binding_size(),
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &kernel_buffer,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+            ],
+            label: Some("SSAO uniforms bind group"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout, &source_bind_group_layout],
+        });
+        let occlusion_module =
+            compile_frag!(&context.device, &mut context.shader_compiler, "ssao.frag").unwrap();
+        let blur_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "ssao_blur.frag"
+        )
+        .unwrap();
+        let occlusion_pipeline = fullscreen.create_pipeline(
+            "SSAO",
+            &context.device,
+            &layout,
+            &occlusion_module,
+            OCCLUSION_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let blur_pipeline = fullscreen.create_pipeline(
+            "SSAO blur",
+            &context.device,
+            &layout,
+            &blur_module,
+            OCCLUSION_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        let scratch = create_occlusion_texture(&context.device, &context.sc_desc, "ssao_scratch");
+        let (reads_scratch, reads_occlusion) = create_source_bind_groups(
+            &context.device,
+            &source_bind_group_layout,
+            forward,
+            &scratch,
+        );
+
+        SsaoPass {
+            settings: SsaoSettings::default(),
+            occlusion_pipeline,
+            blur_pipeline,
+            uniforms_buffer,
+            uniforms_bind_group,
+            source_bind_group_layout,
+            scratch,
+            reads_scratch,
+            reads_occlusion,
+        }
+    }
+
+    /// Follows the textures of the forward pass after it was resized
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        forward: &forward::ForwardPass,
+    ) {
+        self.scratch = create_occlusion_texture(device, sc_desc, "ssao_scratch");
+        let (reads_scratch, reads_occlusion) = create_source_bind_groups(
+            device,
+            &self.source_bind_group_layout,
+            forward,
+            &self.scratch,
+        );
+        self.reads_scratch = reads_scratch;
+        self.reads_occlusion = reads_occlusion;
+    }
+
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        camera: &camera::Camera,
+        projection: &impl Projection,
+    ) {
+        let proj = projection.calc_matrix();
+        let settings = &self.settings;
+        let uniforms = SsaoUniforms {
+            proj,
+            inv_proj: proj.invert().unwrap_or_else(Matrix4::identity),
+            view: camera.calc_matrix(),
+            radius: settings.radius,
+            bias: settings.bias,
+            intensity: settings.intensity,
+            sample_count: settings.sample_count.min(MAX_SAMPLES as u32),
+            normals_from_depth: !settings.normal_prepass as u32,
+            _padding: 0,
+            blur_direction: [0.0, 0.0],
+        };
+        for &(draw_index, blur_direction) in &[
+            (DRAW_OCCLUSION, [0.0, 0.0]),
+            (DRAW_BLUR_HORIZONTAL, [1.0, 0.0]),
+            (DRAW_BLUR_VERTICAL, [0.0, 1.0]),
+        ] {
+            queue.write_buffer(
+                &self.uniforms_buffer,
+                draw_offset(draw_index) as wgpu::BufferAddress,
+                bytemuck::bytes_of(&SsaoUniforms {
+                    blur_direction,
+                    ..uniforms
+                }),
+            );
+        }
+    }
+
+    /// Computes the occlusion into `forward.occlusion_texture` from the depth (and normals) of the
+    /// prepass, or clears it to unoccluded when disabled
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, forward: &forward::ForwardPass) {
+        let occlusion = &forward.occlusion_texture.view;
+        if !self.settings.enabled {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: occlusion,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            return;
+        }
+
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::WHITE);
+        let passes = [
+            (
+                occlusion,
+                &self.occlusion_pipeline,
+                DRAW_OCCLUSION,
+                &self.reads_scratch,
+            ),
+            (
+                &self.scratch.view,
+                &self.blur_pipeline,
+                DRAW_BLUR_HORIZONTAL,
+                &self.reads_occlusion,
+            ),
+            (
+                occlusion,
+                &self.blur_pipeline,
+                DRAW_BLUR_VERTICAL,
+                &self.reads_scratch,
+            ),
+        ];
+        for &(output, pipeline, draw_index, sources) in &passes {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: clear,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(
+                0,
+                &self.uniforms_bind_group,
+                &[draw_offset(draw_index) as u32],
+            );
+            render_pass.set_bind_group(1, sources, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+/// A target for the ambient occlusion the size of the swap chain
+pub fn create_occlusion_texture(
+    device: &wgpu::Device,
+    sc_desc: &wgpu::SwapChainDescriptor,
+    label: &str,
+) -> texture::Texture {
+    let size = wgpu::Extent3d {
+        width: sc_desc.width,
+        height: sc_desc.height,
+        depth: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: OCCLUSION_FORMAT,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some(label),
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Nearest,
+        min_filter: wgpu::FilterMode::Nearest,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    texture::Texture {
+        size,
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Bind groups with the depth and normals of the prepass, and either the scratch texture or the
+/// occlusion. They're all read with the (nearest) sampler of the scratch texture.
+fn create_source_bind_groups(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    forward: &forward::ForwardPass,
+    scratch: &texture::Texture,
+) -> (wgpu::BindGroup, wgpu::BindGroup) {
+    let create = |source: &wgpu::TextureView| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&forward.depth_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&forward.normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&scratch.sampler),
+                },
+            ],
+            label: Some("SSAO source bind group"),
+        })
+    };
+    (
+        create(&scratch.view),
+        create(&forward.occlusion_texture.view),
+    )
+}
+
+/// Points spread over the unit hemisphere around +z, more of them close to the center where they
+/// matter most
+fn create_kernel() -> SsaoKernel {
+    let golden_angle = PI * (3.0 - 5.0f32.sqrt());
+    let mut kernel = SsaoKernel {
+        samples: [Vector4::zero(); MAX_SAMPLES],
+    };
+    for (i, sample) in kernel.samples.iter_mut().enumerate() {
+        let t = (i as f32 + 0.5) / MAX_SAMPLES as f32;
+        // Uniform over the hemisphere, keeping away from the tangent plane
+        let z = 1.0 - t * 0.9;
+        let r = (1.0 - z * z).sqrt();
+        let phi = i as f32 * golden_angle;
+        // Scattered along the radius, the order already being well spread
+        let scale = (i as f32 * 0.618_034).fract();
+        let scale = 0.1 + 0.9 * scale * scale;
+        *sample = Vector4::new(r * phi.cos(), r * phi.sin(), z, 0.0) * scale;
+    }
+    kernel
+}
+
+fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+    wgpu::BufferSize::new(mem::size_of::<SsaoUniforms>() as _)
+}
+
+fn draw_offset(draw_index: usize) -> usize {
+    draw_index * wgpu::BIND_BUFFER_ALIGNMENT as usize
+}
@@ -0,0 +1,228 @@
+use crate::compile_frag;
+use crate::post;
+use crate::texture;
+use crate::Context;
+use std::mem;
+use wgpu::util::DeviceExt;
+
+/// Curve mapping the linear color of the scene to the displayable range
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tonemapper {
+    /// Clamps at 1.0, as if there was no HDR target
+    Clamp,
+    /// `c / (1 + c)`, extended so that `white` maps to 1.0
+    Reinhard { white: f32 },
+    /// Krzysztof Narkowicz's fit of the ACES reference rendering and output transforms
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2, normalized so that `white` maps to 1.0
+    Filmic { white: f32 },
+}
+
+impl Tonemapper {
+    /// Each tonemapper with reasonable parameters
+    pub const ALL: [Tonemapper; 4] = [
+        Tonemapper::Clamp,
+        Tonemapper::Reinhard { white: 4.0 },
+        Tonemapper::Aces,
+        Tonemapper::Filmic { white: 11.2 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tonemapper::Clamp => "Clamp",
+            Tonemapper::Reinhard { .. } => "Reinhard",
+            Tonemapper::Aces => "ACES",
+            Tonemapper::Filmic { .. } => "Filmic",
+        }
+    }
+}
+
+impl Default for Tonemapper {
+    fn default() -> Self {
+        Tonemapper::Aces
+    }
+}
+
+// Matches tonemap.frag
+const TONEMAPPER_CLAMP: u32 = 0;
+const TONEMAPPER_REINHARD: u32 = 1;
+const TONEMAPPER_ACES: u32 = 2;
+const TONEMAPPER_FILMIC: u32 = 3;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct TonemapUniforms {
+    exposure: f32,
+    /// Input that maps to 1.0, for the tonemappers that take one
+    white: f32,
+    tonemapper: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for TonemapUniforms {}
+unsafe impl bytemuck::Zeroable for TonemapUniforms {}
+
+/// Maps the HDR color the scene is rendered into (see `ForwardPass::hdr_texture`) to the swap
+/// chain, after scaling it by the exposure
+pub struct TonemapPass {
+    pub tonemapper: Tonemapper,
+    /// Linear scale applied before tonemapping, as a power of two (in stops)
+    pub exposure: f32,
+    fullscreen: post::Fullscreen,
+    pipeline: wgpu::RenderPipeline,
+    uniforms_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+}
+
+impl TonemapPass {
+    pub fn new(context: &mut Context, source: &texture::Texture) -> Self {
+        let device = &context.device;
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: wgpu::BufferSize::new(
+                            mem::size_of::<TonemapUniforms>() as _
+                        ),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+            label: Some("Tonemap bind group layout"),
+        });
+
+        let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tonemap uniforms"),
+            contents: bytemuck::cast_slice(&[TonemapUniforms {
+                exposure: 1.0,
+                white: 1.0,
+                tonemapper: TONEMAPPER_CLAMP,
+                _padding: 0,
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let bind_group = create_bind_group(device, &bind_group_layout, &uniforms_buffer, source);
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Tonemap pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&bind_group_layout],
+        });
+        let fs_module = compile_frag!(
+            &context.device,
+            &mut context.shader_compiler,
+            "tonemap.frag"
+        )
+        .unwrap();
+        let fullscreen = post::Fullscreen::new(&context.device, &mut context.shader_compiler);
+        let pipeline = fullscreen.create_pipeline(
+            "Tonemap",
+            &context.device,
+            &layout,
+            &fs_module,
+            context.sc_desc.format,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        TonemapPass {
+            tonemapper: Tonemapper::default(),
+            exposure: 0.0,
+            fullscreen,
+            pipeline,
+            uniforms_buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    /// Points the pass at a new HDR texture, such as after the forward pass was resized
+    pub fn set_source(&mut self, device: &wgpu::Device, source: &texture::Texture) {
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniforms_buffer,
+            source,
+        );
+    }
+
+    /// Tonemaps the whole source into `output`, which must be the size of the source
+    pub fn render(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        let (tonemapper, white) = match self.tonemapper {
+            Tonemapper::Clamp => (TONEMAPPER_CLAMP, 1.0),
+            Tonemapper::Reinhard { white } => (TONEMAPPER_REINHARD, white),
+            Tonemapper::Aces => (TONEMAPPER_ACES, 1.0),
+            Tonemapper::Filmic { white } => (TONEMAPPER_FILMIC, white),
+        };
+        let uniforms = TonemapUniforms {
+            exposure: self.exposure.exp2(),
+            white,
+            tonemapper,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.uniforms_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+
+        // Every pixel is covered
+        self.fullscreen.draw(
+            encoder,
+            output,
+            wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+            &self.pipeline,
+            &[&self.bind_group],
+        );
+    }
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniforms_buffer: &wgpu::Buffer,
+    source: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: uniforms_buffer,
+                    offset: 0,
+                    size: None,
+                },
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&source.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(&source.sampler),
+            },
+        ],
+        label: Some("Tonemap bind group"),
+    })
+}
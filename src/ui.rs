@@ -1,19 +1,66 @@
+use crate::background;
 use crate::debug;
+use crate::forward;
 use crate::light;
+use crate::picking;
+use crate::ssao;
+use crate::tonemap;
 use crate::Context;
+use std::ops::RangeInclusive;
+
+/// A post-processing effect as shown in the debug UI, see `post::PostStack`
+#[derive(Clone)]
+pub struct PostEffectSettings {
+    pub name: String,
+    pub enabled: bool,
+    /// Name, value and range of each parameter
+    pub parameters: Vec<(&'static str, f32, RangeInclusive<f32>)>,
+}
+
+/// Text drawn at a fixed window position, e.g. projected from a point in the world
+pub struct HudLabel {
+    pub text: String,
+    /// In physical pixels, like `ScreenSpace`
+    pub position: [f32; 2],
+}
 
 pub struct DebugUi {
     pub is_visible: bool,
     pub shadows_enabled: bool,
+    pub bake_static_shadows: bool,
+    pub shadow_filter: light::ShadowFilter,
+    pub cluster_heatmap: bool,
+    pub background: background::Background,
+    /// Of the forward pass, seen where the background doesn't cover it
+    pub clear_color: [f32; 3],
+    /// Hour of the day (in solar time) the sun of the physical sky is placed at
+    pub time_of_day: f32,
+    pub tonemapper: tonemap::Tonemapper,
+    /// In stops
+    pub exposure: f32,
+    /// Samples per pixel of the forward pass, one of `ForwardPass::SAMPLE_COUNTS`
+    pub sample_count: u32,
+    pub ssao: ssao::SsaoSettings,
+    /// In order
+    pub post_effects: Vec<PostEffectSettings>,
     pub camera_pos: cgmath::Point3<f32>,
+    pub gpu_picking: bool,
+    pub picked: Option<picking::PickHit>,
+    pub labels: Vec<HudLabel>,
     pub context: imgui::Context,
     renderer: imgui_wgpu::Renderer,
     platform: imgui_winit_support::WinitPlatform,
     last_cursor: Option<imgui::MouseCursor>,
-    shadow_map_ids: Vec<imgui::TextureId>,
-    shadow_bind_groups: Vec<wgpu::BindGroup>,
+    shadow_atlas_id: imgui::TextureId,
+    shadow_atlas_bind_group: wgpu::BindGroup,
+    shadow_sampler: wgpu::Sampler,
+    /// Size and format of the shadow atlas the bind group was created for
+    shadow_atlas: (u32, wgpu::TextureFormat),
 }
 
+/// Width and height of the imgui texture the shadow atlas is drawn into
+const SHADOW_ATLAS_PREVIEW_SIZE: u32 = 1024;
+
 impl DebugUi {
     pub fn new(context: &Context, lights: &light::Lights) -> Self {
         let hidpi_factor = 1.0;
@@ -65,63 +112,39 @@ impl DebugUi {
             ..Default::default()
         });
 
-        let shadow_map_ids: Vec<_> = (0..6 * light::MAX_LIGHTS)
-            .map(|_| create_texture(&context.device, &mut renderer))
-            .collect();
-
-        // Create a texture views for each face of each shadow cubemap texture
-        let shadow_texture_views: Vec<wgpu::TextureView> = lights.shadow_textures
-            .iter()
-            .flat_map(|tex| {
-                (0..6)
-                    .map(|i| {
-                        tex.create_view(&wgpu::TextureViewDescriptor {
-                            label: Some("Shadow"),
-                            format: None,
-                            dimension: Some(wgpu::TextureViewDimension::D2),
-                            aspect: wgpu::TextureAspect::All,
-                            base_mip_level: 0,
-                            level_count: None,
-                            base_array_layer: i,
-                            array_layer_count: None,
-                        })
-                    })
-                    .collect::<Vec<wgpu::TextureView>>()
-            })
-            .collect();
-
-        let shadow_bind_groups: Vec<_> = shadow_texture_views
-            .iter()
-            .map(|view| {
-                context
-                    .device
-                    .create_bind_group(&wgpu::BindGroupDescriptor {
-                        layout: &context.texture_bind_group_layout,
-                        entries: &[
-                            wgpu::BindGroupEntry {
-                                binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&view),
-                            },
-                            wgpu::BindGroupEntry {
-                                binding: 1,
-                                resource: wgpu::BindingResource::Sampler(&shadow_sampler),
-                            },
-                        ],
-                        label: None,
-                    })
-            })
-            .collect();
+        let shadow_atlas_id = renderer.textures.insert(create_texture(
+            &context.device,
+            &renderer,
+            SHADOW_ATLAS_PREVIEW_SIZE,
+        ));
+        let shadow_atlas_bind_group = create_shadow_bind_group(context, lights, &shadow_sampler);
 
         DebugUi {
             is_visible: false,
             shadows_enabled: true,
+            bake_static_shadows: true,
+            shadow_filter: light::ShadowFilter::default(),
+            cluster_heatmap: false,
+            background: background::Background::default(),
+            clear_color: [0.0; 3],
+            time_of_day: 15.0,
+            tonemapper: tonemap::Tonemapper::default(),
+            exposure: 0.0,
+            sample_count: 1,
+            ssao: ssao::SsaoSettings::default(),
+            post_effects: Vec::new(),
             camera_pos: cgmath::Point3::new(0.0, 0.0, 0.0),
+            gpu_picking: false,
+            picked: None,
+            labels: Vec::new(),
             context: imgui_context,
             renderer,
             platform,
             last_cursor,
-            shadow_map_ids,
-            shadow_bind_groups,
+            shadow_atlas_id,
+            shadow_atlas_bind_group,
+            shadow_sampler,
+            shadow_atlas: (lights.shadow_atlas.size, lights.shadow_atlas.format),
         }
     }
 
@@ -141,25 +164,30 @@ impl DebugUi {
         encoder: &mut wgpu::CommandEncoder,
         debug_pass: &debug::DebugPass,
     ) {
-        // Render each shadow texture into the imgui textures
-        {
-            let imgui_shadow_textures = self
-                .shadow_map_ids
-                .iter()
-                .map(|id| self.renderer.textures.get(*id).unwrap());
-
-            for (i, tex) in imgui_shadow_textures.enumerate() {
-                let shadow_bind_group = &self.shadow_bind_groups[i];
-                debug_pass.render(encoder, &tex.view, &shadow_bind_group);
-            }
+        // The shadow atlas is reallocated when its size or format changes
+        let atlas = &context.lights.shadow_atlas;
+        if (atlas.size, atlas.format) != self.shadow_atlas {
+            self.shadow_atlas_bind_group =
+                create_shadow_bind_group(context, &context.lights, &self.shadow_sampler);
+            self.shadow_atlas = (atlas.size, atlas.format);
         }
 
-        // Create an imgui Image widget for each shadow texture
-        let images: Vec<_> = self
-            .shadow_map_ids
+        // Render the shadow atlas into the imgui texture
+        let preview = self.renderer.textures.get(self.shadow_atlas_id).unwrap();
+        debug_pass.render(encoder, &preview.view, &self.shadow_atlas_bind_group);
+        let atlas_image = imgui::Image::new(self.shadow_atlas_id, [368.0, 368.0]);
+
+        // Number and resolution of the shadow maps of each light
+        let shadow_maps: Vec<(usize, usize, u32)> = context
+            .lights
             .iter()
-            .map(|id| imgui::Image::new(*id, [128.0, 128.0]))
+            .map(|light| {
+                let targets = context.lights.shadow_targets(light.id);
+                let resolution = targets.first().map_or(0, |target| target.rect.size);
+                (light.id.index(), targets.len(), resolution)
+            })
             .collect();
+        let atlas_size = atlas.size;
 
         self.platform
             .prepare_frame(self.context.io_mut(), &context.window)
@@ -167,9 +195,41 @@ impl DebugUi {
 
         let ui = self.context.frame();
 
+        // Render world anchored labels. Their positions are in physical pixels, and imgui's in
+        // logical ones.
+        let hidpi_factor = self.platform.hidpi_factor() as f32;
+        for (i, label) in self.labels.iter().enumerate() {
+            let title = imgui::im_str!("##label{}", i);
+            let position = [
+                label.position[0] / hidpi_factor,
+                label.position[1] / hidpi_factor,
+            ];
+            imgui::Window::new(&title)
+                .position(position, imgui::Condition::Always)
+                .title_bar(false)
+                .resizable(false)
+                .movable(false)
+                .always_auto_resize(true)
+                .bg_alpha(0.5)
+                .build(&ui, || {
+                    ui.text(&label.text);
+                });
+        }
+
         // Render camera window
         {
             let camera_pos = self.camera_pos;
+            let picked = self.picked;
+            let mut gpu_picking = self.gpu_picking;
+            let mut cluster_heatmap = self.cluster_heatmap;
+            let mut background = self.background;
+            let mut time_of_day = self.time_of_day;
+            let mut tonemapper = self.tonemapper;
+            let mut exposure = self.exposure;
+            let mut sample_count = self.sample_count;
+            let mut ssao = self.ssao;
+            let mut clear_color = self.clear_color;
+            let mut post_effects = self.post_effects.clone();
             let window = imgui::Window::new(imgui::im_str!("Game world"));
             window
                 .position([64.0, 64.0], imgui::Condition::FirstUseEver)
@@ -179,13 +239,158 @@ impl DebugUi {
                     ui.text(format!("- x: {:.2}", camera_pos.x));
                     ui.text(format!("- y: {:.2}", camera_pos.y));
                     ui.text(format!("- z: {:.2}", camera_pos.z));
+                    ui.separator();
+
+                    ui.checkbox(imgui::im_str!("Light cluster heatmap"), &mut cluster_heatmap);
+                    ui.separator();
+
+                    let background_names: Vec<imgui::ImString> = background::Background::ALL
+                        .iter()
+                        .map(|background| imgui::ImString::new(background.name()))
+                        .collect();
+                    let background_items: Vec<&imgui::ImStr> =
+                        background_names.iter().map(|name| name.as_ref()).collect();
+                    let mut background_index = background::Background::ALL
+                        .iter()
+                        .position(|item| item.name() == background.name())
+                        .unwrap_or(0);
+                    if imgui::ComboBox::new(imgui::im_str!("Background")).build_simple_string(
+                        &ui,
+                        &mut background_index,
+                        &background_items,
+                    ) {
+                        background = background::Background::ALL[background_index];
+                    }
+                    if background == background::Background::ClearColor {
+                        imgui::ColorEdit::new(imgui::im_str!("Clear color"), &mut clear_color)
+                            .build(&ui);
+                    }
+                    if let background::Background::PhysicalSky(sky) = &mut background {
+                        imgui::Slider::new(imgui::im_str!("Time of day"), 4.0..=20.0)
+                            .build(&ui, &mut time_of_day);
+                        imgui::Slider::new(imgui::im_str!("Turbidity"), 2.0..=10.0)
+                            .build(&ui, &mut sky.turbidity);
+                        imgui::Slider::new(imgui::im_str!("Sky ambient"), 0.0..=1.0)
+                            .build(&ui, &mut sky.ambient);
+                    }
+                    ui.separator();
+
+                    let tonemapper_names: Vec<imgui::ImString> = tonemap::Tonemapper::ALL
+                        .iter()
+                        .map(|tonemapper| imgui::ImString::new(tonemapper.name()))
+                        .collect();
+                    let tonemapper_items: Vec<&imgui::ImStr> =
+                        tonemapper_names.iter().map(|name| name.as_ref()).collect();
+                    let mut tonemapper_index = tonemap::Tonemapper::ALL
+                        .iter()
+                        .position(|item| item.name() == tonemapper.name())
+                        .unwrap_or(0);
+                    if imgui::ComboBox::new(imgui::im_str!("Tonemapper")).build_simple_string(
+                        &ui,
+                        &mut tonemapper_index,
+                        &tonemapper_items,
+                    ) {
+                        tonemapper = tonemap::Tonemapper::ALL[tonemapper_index];
+                    }
+                    imgui::Slider::new(imgui::im_str!("Exposure"), -4.0..=4.0)
+                        .build(&ui, &mut exposure);
+                    let sample_count_names: Vec<imgui::ImString> =
+                        forward::ForwardPass::SAMPLE_COUNTS
+                            .iter()
+                            .map(|&count| match count {
+                                1 => imgui::ImString::new("Off"),
+                                count => imgui::ImString::new(format!("{}x", count)),
+                            })
+                            .collect();
+                    let sample_count_items: Vec<&imgui::ImStr> = sample_count_names
+                        .iter()
+                        .map(|name| name.as_ref())
+                        .collect();
+                    let mut sample_count_index = forward::ForwardPass::SAMPLE_COUNTS
+                        .iter()
+                        .position(|&count| count == sample_count)
+                        .unwrap_or(0);
+                    if imgui::ComboBox::new(imgui::im_str!("MSAA")).build_simple_string(
+                        &ui,
+                        &mut sample_count_index,
+                        &sample_count_items,
+                    ) {
+                        sample_count = forward::ForwardPass::SAMPLE_COUNTS[sample_count_index];
+                    }
+                    ui.checkbox(imgui::im_str!("Ambient occlusion"), &mut ssao.enabled);
+                    if ssao.enabled {
+                        ui.checkbox(imgui::im_str!("Normal prepass"), &mut ssao.normal_prepass);
+                        imgui::Slider::new(imgui::im_str!("AO radius"), 0.1..=3.0)
+                            .build(&ui, &mut ssao.radius);
+                        imgui::Slider::new(imgui::im_str!("AO intensity"), 0.5..=4.0)
+                            .build(&ui, &mut ssao.intensity);
+                        imgui::Slider::new(imgui::im_str!("AO bias"), 0.0..=0.2)
+                            .build(&ui, &mut ssao.bias);
+                        imgui::Slider::new(
+                            imgui::im_str!("AO samples"),
+                            1..=ssao::MAX_SAMPLES as u32,
+                        )
+                        .build(&ui, &mut ssao.sample_count);
+                    }
+                    if !post_effects.is_empty() {
+                        ui.text("Post-processing:");
+                        for effect in &mut post_effects {
+                            ui.checkbox(
+                                &imgui::ImString::new(effect.name.as_str()),
+                                &mut effect.enabled,
+                            );
+                            if !effect.enabled {
+                                continue;
+                            }
+                            for (name, value, range) in &mut effect.parameters {
+                                let label = imgui::ImString::new(format!("{}##{}", name, effect.name));
+                                imgui::Slider::new(&label, range.clone()).build(&ui, value);
+                            }
+                        }
+                    }
+                    ui.separator();
+
+                    ui.checkbox(imgui::im_str!("GPU picking"), &mut gpu_picking);
+                    match picked {
+                        Some(hit) => {
+                            ui.text(format!(
+                                "Picked model {} mesh {} instance {}",
+                                hit.model, hit.mesh, hit.instance
+                            ));
+                            ui.text(format!(
+                                "- position: ({:.2}, {:.2}, {:.2})",
+                                hit.position.x, hit.position.y, hit.position.z
+                            ));
+                            ui.text(format!(
+                                "- normal: ({:.2}, {:.2}, {:.2})",
+                                hit.normal.x, hit.normal.y, hit.normal.z
+                            ));
+                            ui.text(format!(
+                                "- uv: ({:.2}, {:.2})",
+                                hit.tex_coords.x, hit.tex_coords.y
+                            ));
+                        }
+                        None => ui.text("Right click to pick"),
+                    }
                 });
+            self.gpu_picking = gpu_picking;
+            self.cluster_heatmap = cluster_heatmap;
+            self.background = background;
+            self.clear_color = clear_color;
+            self.time_of_day = time_of_day;
+            self.tonemapper = tonemapper;
+            self.exposure = exposure;
+            self.sample_count = sample_count;
+            self.ssao = ssao;
+            self.post_effects = post_effects;
         }
 
         // Render shadow debug window
         {
             let window = imgui::Window::new(imgui::im_str!("Shadow Debug"));
             let mut shadows_enabled = self.shadows_enabled;
+            let mut bake_static_shadows = self.bake_static_shadows;
+            let mut shadow_filter = self.shadow_filter;
             window
                 .position([64.0, 256.0], imgui::Condition::FirstUseEver)
                 .size([128.0 * 3.0, 512.0], imgui::Condition::FirstUseEver)
@@ -193,29 +398,84 @@ impl DebugUi {
                 .always_vertical_scrollbar(true)
                 .build(&ui, || {
                     ui.checkbox(imgui::im_str!("Shadows enabled"), &mut shadows_enabled);
-                    ui.separator();
-
-                    for (i, imgs) in images.chunks(6).enumerate() {
-                        let title = imgui::ImString::new(format!("Light #{}", i));
+                    ui.checkbox(
+                        imgui::im_str!("Bake static shadows"),
+                        &mut bake_static_shadows,
+                    );
 
-                        let header = imgui::CollapsingHeader::new(&title);
-                        let is_expanded = header.build(&ui);
+                    let filter_names: Vec<imgui::ImString> = light::ShadowFilter::ALL
+                        .iter()
+                        .map(|filter| imgui::ImString::new(filter.name()))
+                        .collect();
+                    let filter_items: Vec<&imgui::ImStr> =
+                        filter_names.iter().map(|name| name.as_ref()).collect();
+                    let mut filter_index = light::ShadowFilter::ALL
+                        .iter()
+                        .position(|filter| filter.name() == shadow_filter.name())
+                        .unwrap_or(0);
+                    if imgui::ComboBox::new(imgui::im_str!("Shadow filter")).build_simple_string(
+                        &ui,
+                        &mut filter_index,
+                        &filter_items,
+                    ) {
+                        shadow_filter = light::ShadowFilter::ALL[filter_index];
+                    }
+                    match &mut shadow_filter {
+                        light::ShadowFilter::Hardware => {}
+                        light::ShadowFilter::Pcf { taps, radius } => {
+                            imgui::Slider::new(
+                                imgui::im_str!("Filter taps"),
+                                1..=light::MAX_FILTER_TAPS,
+                            )
+                            .build(&ui, taps);
+                            imgui::Slider::new(imgui::im_str!("Filter radius"), 0.5..=8.0)
+                                .build(&ui, radius);
+                        }
+                        light::ShadowFilter::Pcss {
+                            taps,
+                            light_size,
+                            sun_size,
+                        } => {
+                            imgui::Slider::new(
+                                imgui::im_str!("Filter taps"),
+                                1..=light::MAX_FILTER_TAPS,
+                            )
+                            .build(&ui, taps);
+                            imgui::Slider::new(imgui::im_str!("Light size"), 0.05..=2.0)
+                                .build(&ui, light_size);
+                            imgui::Slider::new(imgui::im_str!("Sun size"), 0.001..=0.05)
+                                .build(&ui, sun_size);
+                        }
+                        light::ShadowFilter::Vsm {
+                            blur_radius,
+                            light_bleed_reduction,
+                        }
+                        | light::ShadowFilter::Evsm {
+                            blur_radius,
+                            light_bleed_reduction,
+                        } => {
+                            imgui::Slider::new(imgui::im_str!("Blur radius"), 0..=8)
+                                .build(&ui, blur_radius);
+                            imgui::Slider::new(imgui::im_str!("Light bleed reduction"), 0.0..=0.9)
+                                .build(&ui, light_bleed_reduction);
+                        }
+                    }
+                    ui.separator();
 
-                        let style_token =
-                            ui.push_style_vars(&[imgui::StyleVar::ItemSpacing([4.0, 4.0])]);
-                        if is_expanded {
-                            ui.columns(3, imgui::im_str!("Columnz"), false);
-                            for img in imgs {
-                                img.build(&ui);
-                                ui.next_column();
-                            }
-                            ui.columns(1, imgui::im_str!("Columnz"), false);
+                    ui.text(format!("Atlas: {0}x{0}", atlas_size));
+                    for &(light, count, resolution) in &shadow_maps {
+                        if count == 0 {
+                            ui.text(format!("- Light {}: no room", light));
+                        } else {
+                            ui.text(format!("- Light {}: {} x {}px", light, count, resolution));
                         }
-                        style_token.pop(&ui);
                     }
+                    atlas_image.build(&ui);
                 });
 
             self.shadows_enabled = shadows_enabled;
+            self.bake_static_shadows = bake_static_shadows;
+            self.shadow_filter = shadow_filter;
         }
 
         if self.last_cursor != ui.mouse_cursor() {
@@ -241,14 +501,43 @@ impl DebugUi {
     }
 }
 
-fn create_texture(device: &wgpu::Device, renderer: &mut imgui_wgpu::Renderer) -> imgui::TextureId {
-    let imgui_texture = imgui_wgpu::Texture::new(
+/// Creates a bind group for drawing the shadow atlas into the imgui texture
+fn create_shadow_bind_group(
+    context: &Context,
+    lights: &light::Lights,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    context
+        .device
+        .create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &context.texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &lights.shadow_atlas.texture_view,
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: None,
+        })
+}
+
+fn create_texture(
+    device: &wgpu::Device,
+    renderer: &imgui_wgpu::Renderer,
+    resolution: u32,
+) -> imgui_wgpu::Texture {
+    imgui_wgpu::Texture::new(
         device,
-        &renderer,
-        1024,
-        1024,
+        renderer,
+        resolution,
+        resolution,
         wgpu::TextureFormat::Rgba8Unorm,
         None,
-    );
-    renderer.textures.insert(imgui_texture)
+    )
 }
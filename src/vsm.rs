@@ -0,0 +1,359 @@
+use crate::light;
+use crate::shadow::{self, AtlasRect, ShadowAtlas, ShadowTarget};
+use crate::{compile_frag, compile_vertex};
+use std::mem;
+
+/// Computes the depth moments sampled by the variance shadow filters (see
+/// `light::ShadowFilter::Vsm`). The moments of each shadow map in the atlas are blurred
+/// horizontally into a scratch texture, which is then blurred vertically into the same region of
+/// `Lights::shadow_moments`.
+pub struct VsmPass {
+    moments_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    source_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    uniforms_bind_group_layout: wgpu::BindGroupLayout,
+    uniforms_buffer: wgpu::Buffer,
+    uniforms_bind_group: wgpu::BindGroup,
+    /// Number of shadow maps `uniforms_buffer` has room for
+    draw_capacity: usize,
+    /// Horizontally blurred moments of the shadow map being processed, in its top left corner.
+    /// It's as large as the largest shadow map.
+    scratch: ShadowAtlas,
+    /// Size of the moments atlas, and the filter, the moments were last computed for. All shadow
+    /// maps are computed again when these change.
+    computed: Option<(u32, light::ShadowFilter)>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VsmUniforms {
+    /// Region of the shadow map in the atlas, in texels
+    origin: [u32; 2],
+    size: u32,
+    /// In texels
+    blur_radius: u32,
+    /// Whether to store exponentially warped moments (EVSM)
+    exponential: u32,
+    _padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for VsmUniforms {}
+unsafe impl bytemuck::Zeroable for VsmUniforms {}
+
+impl VsmPass {
+    pub fn new(device: &wgpu::Device, compiler: &mut shaderc::Compiler) -> Self {
+        let source_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                        count: None,
+                    },
+                ],
+                label: Some("VSM source bind group layout"),
+            });
+
+        let uniforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: true,
+                        min_binding_size: uniforms_binding_size(),
+                    },
+                    count: None,
+                }],
+                label: Some("VSM uniforms bind group layout"),
+            });
+
+        let draw_capacity = 1;
+        let (uniforms_buffer, uniforms_bind_group) =
+            create_uniforms(device, &uniforms_bind_group_layout, draw_capacity);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("VSM source"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: None,
+            ..Default::default()
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("VSM pipeline"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&uniforms_bind_group_layout, &source_bind_group_layout],
+        });
+        let vs_module = compile_vertex!(device, compiler, "fullscreen.vert").unwrap();
+        let moments_module = compile_frag!(device, compiler, "vsm_moments.frag").unwrap();
+        let blur_module = compile_frag!(device, compiler, "vsm_blur.frag").unwrap();
+        let moments_pipeline = create_pipeline(device, &layout, &vs_module, &moments_module);
+        let blur_pipeline = create_pipeline(device, &layout, &vs_module, &blur_module);
+
+        VsmPass {
+            moments_pipeline,
+            blur_pipeline,
+            source_bind_group_layout,
+            sampler,
+            uniforms_bind_group_layout,
+            uniforms_buffer,
+            uniforms_bind_group,
+            draw_capacity,
+            scratch: ShadowAtlas::empty(device),
+            computed: None,
+        }
+    }
+
+    /// Updates the moments of the shadow maps that were rendered (as returned by
+    /// `ShadowPass::render`), or of every shadow map in use if the moments atlas or the filter
+    /// changed. Does nothing unless a variance filter is in use.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        lights: &light::Lights,
+        rendered: &[ShadowTarget],
+    ) {
+        let filter = lights.config.shadow_filter;
+        let blur_radius = match filter.moments_blur_radius() {
+            Some(radius) => radius,
+            None => {
+                self.computed = None;
+                return;
+            }
+        };
+
+        let moments = &lights.shadow_moments;
+        let textures = (moments.size, filter);
+        let targets: Vec<ShadowTarget> = if self.computed == Some(textures) {
+            rendered.to_vec()
+        } else {
+            lights
+                .iter()
+                .flat_map(|light| lights.shadow_targets(light.id))
+                .collect()
+        };
+        self.computed = Some(textures);
+        if targets.is_empty() {
+            return;
+        }
+
+        let largest = targets
+            .iter()
+            .map(|target| target.rect.size)
+            .max()
+            .unwrap_or(1);
+        if self.scratch.size < largest {
+            self.scratch = ShadowAtlas::new(device, largest, shadow::MOMENTS_FORMAT);
+        }
+        self.reserve(device, targets.len());
+
+        // Both draws of a shadow map share its uniforms: its moments into the scratch texture,
+        // and the blur back into its region of the moments atlas
+        let exponential = match filter {
+            light::ShadowFilter::Evsm { .. } => 1,
+            _ => 0,
+        };
+        for (i, target) in targets.iter().enumerate() {
+            let uniforms = VsmUniforms {
+                origin: [target.rect.x, target.rect.y],
+                size: target.rect.size,
+                blur_radius,
+                exponential,
+                _padding: [0; 3],
+            };
+            queue.write_buffer(
+                &self.uniforms_buffer,
+                draw_offset(i) as wgpu::BufferAddress,
+                bytemuck::bytes_of(&uniforms),
+            );
+        }
+
+        let atlas_source = self.create_source_bind_group(device, &lights.shadow_atlas.texture_view);
+        let scratch_source = self.create_source_bind_group(device, &self.scratch.texture_view);
+
+        for (i, target) in targets.iter().enumerate() {
+            let scratch_rect = AtlasRect {
+                x: 0,
+                y: 0,
+                size: target.rect.size,
+            };
+            self.draw(
+                encoder,
+                &self.moments_pipeline,
+                (&self.scratch.texture_view, scratch_rect),
+                &atlas_source,
+                i,
+            );
+            self.draw(
+                encoder,
+                &self.blur_pipeline,
+                (&moments.texture_view, target.rect),
+                &scratch_source,
+                i,
+            );
+        }
+    }
+
+    fn reserve(&mut self, device: &wgpu::Device, draw_count: usize) {
+        if draw_count > self.draw_capacity {
+            self.draw_capacity = draw_count.next_power_of_two();
+            let (buffer, bind_group) = create_uniforms(
+                device,
+                &self.uniforms_bind_group_layout,
+                self.draw_capacity,
+            );
+            self.uniforms_buffer = buffer;
+            self.uniforms_bind_group = bind_group;
+        }
+    }
+
+    fn create_source_bind_group(
+        &self,
+        device: &wgpu::Device,
+        view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.source_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("VSM source"),
+        })
+    }
+
+    /// Draws into a region of the target, leaving the rest of it as it was
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        (target, rect): (&wgpu::TextureView, AtlasRect),
+        source: &wgpu::BindGroup,
+        draw_index: usize,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        let size = rect.size as f32;
+        render_pass.set_viewport(rect.x as f32, rect.y as f32, size, size, 0.0, 1.0);
+        render_pass.set_scissor_rect(rect.x, rect.y, rect.size, rect.size);
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(
+            0,
+            &self.uniforms_bind_group,
+            &[draw_offset(draw_index) as wgpu::DynamicOffset],
+        );
+        render_pass.set_bind_group(1, source, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("VSM"),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::None,
+            ..Default::default()
+        }),
+        // The moments go in all four channels, so they can't be blended
+        color_states: &[wgpu::ColorStateDescriptor {
+            format: shadow::MOMENTS_FORMAT,
+            color_blend: wgpu::BlendDescriptor::REPLACE,
+            alpha_blend: wgpu::BlendDescriptor::REPLACE,
+            write_mask: wgpu::ColorWrite::ALL,
+        }],
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        depth_stencil_state: None,
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: wgpu::IndexFormat::Uint32,
+            vertex_buffers: &[],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    })
+}
+
+fn create_uniforms(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    draw_capacity: usize,
+) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("VSM uniforms"),
+        size: (draw_capacity as u64 * wgpu::BIND_BUFFER_ALIGNMENT) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer {
+                buffer: &buffer,
+                offset: 0,
+                size: uniforms_binding_size(),
+            },
+        }],
+        label: Some("VSM uniforms bind group"),
+    });
+
+    (buffer, bind_group)
+}
+
+fn uniforms_binding_size() -> Option<wgpu::BufferSize> {
+    wgpu::BufferSize::new(mem::size_of::<VsmUniforms>() as _)
+}
+
+fn draw_offset(draw_index: usize) -> usize {
+    draw_index * wgpu::BIND_BUFFER_ALIGNMENT as usize
+}